@@ -48,7 +48,7 @@ where
 
     // Construct all the opmodes we'll use in setup_sender and setup_receiver
     let opmodes = ["base", "auth", "psk", "authpsk"];
-    let opmodes_s = vec![
+    let opmodes_s = [
         OpModeS::Base,
         OpModeS::Auth((sk_sender.clone(), pk_sender.clone())),
         OpModeS::Psk(psk_bundle),
@@ -79,14 +79,9 @@ where
     // Collect the encapsulated keys from each setup_sender under each opmode. We will pass these
     // to setup_receiver in a moment
     let encapped_keys = opmodes_s.iter().map(|opmode_s| {
-        setup_sender::<Aead, Kdf, Kem, _>(
-            &opmode_s,
-            &pk_recip,
-            b"bench setup receiver",
-            &mut csprng,
-        )
-        .unwrap()
-        .0
+        setup_sender::<Aead, Kdf, Kem, _>(opmode_s, &pk_recip, b"bench setup receiver", &mut csprng)
+            .unwrap()
+            .0
     });
 
     // Bench setup_receiver for each opmode
@@ -147,11 +142,8 @@ where
             let start = Instant::now();
             for (mut ciphertext, aad, tag) in ciphertext_aad_tags.into_iter() {
                 // black_box makes sure the compiler doesn't optimize away this computation
-                black_box(
-                    decryption_ctx
-                        .open_in_place_detached(&mut ciphertext, &aad, &tag)
-                        .unwrap(),
-                );
+                black_box(decryption_ctx.open_in_place_detached(&mut ciphertext, &aad, &tag))
+                    .unwrap();
             }
             start.elapsed()
         });
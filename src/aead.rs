@@ -127,7 +127,7 @@ fn mix_nonce<A: Aead>(base_nonce: &AeadNonce<A>, seq: &Seq) -> AeadNonce<A> {
 
 /// An authenticated encryption tag
 #[derive(Clone)]
-pub struct AeadTag<A: Aead>(GenericArray<u8, <A::AeadImpl as BaseAeadCore>::TagSize>);
+pub struct AeadTag<A: Aead>(pub(crate) GenericArray<u8, <A::AeadImpl as BaseAeadCore>::TagSize>);
 
 impl<A: Aead> Default for AeadTag<A> {
     fn default() -> AeadTag<A> {
@@ -170,6 +170,8 @@ pub(crate) struct AeadCtx<A: Aead, Kdf: KdfTrait, Kem: KemTrait> {
     exporter_secret: ExporterSecret<Kdf>,
     /// The running sequence number
     seq: Seq,
+    /// The number of times this context has been rekeyed
+    generation: u64,
     /// This binds the `AeadCtx` to the KEM that made it. Used to generate `suite_id`.
     src_kem: PhantomData<Kem>,
     /// The full ID of the ciphersuite that created this `AeadCtx`. Used for context binding.
@@ -186,6 +188,7 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> Clone for AeadCtx<A, Kdf, Kem> {
             base_nonce: self.base_nonce.clone(),
             exporter_secret: self.exporter_secret.clone(),
             seq: self.seq.clone(),
+            generation: self.generation,
             src_kem: PhantomData,
             suite_id: self.suite_id,
         }
@@ -206,6 +209,7 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
             base_nonce,
             exporter_secret,
             seq: <Seq as Default>::default(),
+            generation: 0,
             src_kem: PhantomData,
             suite_id,
         }
@@ -217,7 +221,7 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
     //                        exporter_context, L)
 
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
-    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    /// does not depend on sequence number, so it is constant until the context is rekeyed.
     ///
     /// Return Value
     /// ============
@@ -236,6 +240,131 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
             .labeled_expand(&self.suite_id, b"sec", exporter_ctx, out_buf)
             .map_err(|_| HpkeError::KdfOutputTooLong)
     }
+
+    // Not part of RFC 9180. Modeled on the TLS 1.3 KeyUpdate.
+    // def Context.Rekey():
+    //   self.generation += 1
+    //   gen_bytes = I2OSP(self.generation, 8)
+    //   key = LabeledExpand(self.exporter_secret, "rekey_key", gen_bytes, Nk)
+    //   base_nonce = LabeledExpand(self.exporter_secret, "rekey_nonce", gen_bytes, Nn)
+    //   exporter_secret = LabeledExpand(self.exporter_secret, "rekey_exp", gen_bytes, Nh)
+    //   self.seq = 0
+
+    /// Replaces the key, base nonce, and exporter secret with fresh ones derived from the current
+    /// exporter secret, and resets the sequence number. The old values are zeroized, so they
+    /// can't be recovered from the new state.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the generation counter would overflow, returns
+    /// `Err(HpkeError::MessageLimitReached)` and leaves the context unchanged.
+    pub fn rekey(&mut self) -> Result<(), HpkeError> {
+        let generation = self
+            .generation
+            .checked_add(1)
+            .ok_or(HpkeError::MessageLimitReached)?;
+        let mut gen_bytes = [0u8; 8];
+        write_u64_be(&mut gen_bytes, generation);
+
+        // Same as in export(), the PRK is the right length by construction
+        let hkdf_ctx = SimpleHkdf::<Kdf>::from_prk(self.exporter_secret.0.as_slice()).unwrap();
+
+        // These only fail if the output is 255x the digest size, which these never are
+        let mut key = AeadKey::<A>::default();
+        let mut base_nonce = AeadNonce::<A>::default();
+        let mut exporter_secret = ExporterSecret::<Kdf>::default();
+        hkdf_ctx
+            .labeled_expand(
+                &self.suite_id,
+                b"rekey_key",
+                &gen_bytes,
+                key.0.as_mut_slice(),
+            )
+            .expect("aead key len is way too big");
+        hkdf_ctx
+            .labeled_expand(
+                &self.suite_id,
+                b"rekey_nonce",
+                &gen_bytes,
+                base_nonce.0.as_mut_slice(),
+            )
+            .expect("nonce len is way too big");
+        hkdf_ctx
+            .labeled_expand(
+                &self.suite_id,
+                b"rekey_exp",
+                &gen_bytes,
+                exporter_secret.0.as_mut_slice(),
+            )
+            .expect("exporter secret len is way too big");
+
+        // Overwriting drops, and thereby zeroizes, the old key material
+        self.encryptor = <A::AeadImpl as aead::KeyInit>::new(&key.0);
+        self.base_nonce = base_nonce;
+        self.exporter_secret = exporter_secret;
+        self.seq = <Seq as Default>::default();
+        self.overflowed = false;
+        self.generation = generation;
+
+        Ok(())
+    }
+
+    // RFC 9458 §4.4
+    // secret = context.Export("message/bhttp response", max(Nn, Nk))
+    // response_nonce = random(max(Nn, Nk))
+    // salt = concat(enc, response_nonce)
+    // prk = Extract(salt, secret)
+    // aead_key = Expand(prk, "key", Nk)
+    // aead_nonce = Expand(prk, "nonce", Nn)
+    //
+    // The response context also gets exporter_secret = Expand(prk, "exp", Nh). This isn't part
+    // of RFC 9458.
+
+    /// Derives the context for a response to this session, given the export label, the session's
+    /// encapsulated key, and the response nonce
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    fn response_ctx(
+        &self,
+        label: &[u8],
+        encapped_key: &[u8],
+        response_nonce: &[u8],
+    ) -> Result<AeadCtx<A, Kdf, Kem>, HpkeError> {
+        enforce_equal_len(response_nonce_len::<A>(), response_nonce.len())?;
+
+        let mut secret = vec![0u8; response_nonce_len::<A>()];
+        self.export(label, &mut secret)?;
+        let mut salt = crate::Vec::with_capacity(encapped_key.len() + response_nonce.len());
+        salt.extend_from_slice(encapped_key);
+        salt.extend_from_slice(response_nonce);
+        let hkdf_ctx = SimpleHkdf::<Kdf>::new(Some(&salt), &secret);
+        secret.zeroize();
+
+        // These only fail if the output is 255x the digest size, which these never are
+        let mut key = AeadKey::<A>::default();
+        let mut base_nonce = AeadNonce::<A>::default();
+        let mut exporter_secret = ExporterSecret::<Kdf>::default();
+        hkdf_ctx
+            .expand(b"key", key.0.as_mut_slice())
+            .expect("aead key len is way too big");
+        hkdf_ctx
+            .expand(b"nonce", base_nonce.0.as_mut_slice())
+            .expect("nonce len is way too big");
+        hkdf_ctx
+            .expand(b"exp", exporter_secret.0.as_mut_slice())
+            .expect("exporter secret len is way too big");
+
+        Ok(AeadCtx::new(&key, base_nonce, exporter_secret))
+    }
+}
+
+/// Returns the length of a response nonce for the AEAD `A`, i.e., `max(Nn, Nk)`. See
+/// `AeadCtxR::reply_context`.
+pub fn response_nonce_len<A: Aead>() -> usize {
+    use generic_array::typenum::Unsigned;
+    core::cmp::max(
+        <A::AeadImpl as BaseAeadCore>::NonceSize::to_usize(),
+        <A::AeadImpl as aead::KeySizeUser>::KeySize::to_usize(),
+    )
 }
 
 /// The HPKE receiver's context. This is what you use to `open` ciphertexts and `export` secrets.
@@ -339,8 +468,38 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
         Ok(buf)
     }
 
+    /// Opens a ciphertext made by `AeadCtxS::seal_padded`, and strips the padding from the
+    /// resulting plaintext
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If this context has been used for so many encryptions
+    /// that the sequence number overflowed, returns `Err(HpkeError::MessageLimitReached)`. If the
+    /// tag fails to validate, or the plaintext is not correctly padded, returns
+    /// `Err(HpkeError::OpenError)`.
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    pub fn open_padded(
+        &mut self,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<crate::Vec<u8>, HpkeError> {
+        let mut buf = self.open(ciphertext, aad)?;
+        match crate::padding::unpadded_len(&buf) {
+            Ok(plaintext_len) => {
+                buf.truncate(plaintext_len);
+                Ok(buf)
+            }
+            Err(_) => {
+                // Don't leave the authenticated but malformed plaintext lying around
+                buf.zeroize();
+                Err(HpkeError::OpenError)
+            }
+        }
+    }
+
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
-    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    /// does not depend on sequence number, so it is constant until the context is rekeyed.
     ///
     /// Return Value
     /// ============
@@ -352,6 +511,50 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
         // Pass to AeadCtx
         self.0.export(info, out_buf)
     }
+
+    /// Derives a fresh key, base nonce, and exporter secret from the current exporter secret, and
+    /// zeroizes the old ones. The sender MUST call `rekey` at the same point in the message stream,
+    /// or decryption will fail. Exported values change after a rekey.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If this context has been rekeyed 2^64 - 1 times, returns
+    /// `Err(HpkeError::MessageLimitReached)`.
+    pub fn rekey(&mut self) -> Result<(), HpkeError> {
+        self.0.rekey()
+    }
+
+    /// Returns the number of times this context has been rekeyed
+    pub fn generation(&self) -> u64 {
+        self.0.generation
+    }
+
+    /// Makes a context for replying to the sender of this session, in the style of Oblivious HTTP
+    /// responses. `label` is the exporter context the response key is derived from, e.g.,
+    /// `b"message/bhttp response"`, and `encapped_key` is this session's encapsulated key. A fresh
+    /// response nonce is drawn from `csprng`. It MUST be sent along with the response, so the
+    /// sender can call `AeadCtxS::response_context`.
+    ///
+    /// The reply context has its own exporter secret, which is not part of RFC 9458.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns the response nonce and a context for sealing the response
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    pub fn reply_context<R: rand_core::CryptoRng + rand_core::RngCore>(
+        &self,
+        label: &[u8],
+        encapped_key: &Kem::EncappedKey,
+        csprng: &mut R,
+    ) -> Result<(crate::Vec<u8>, AeadCtxS<A, Kdf, Kem>), HpkeError> {
+        let mut response_nonce = vec![0u8; response_nonce_len::<A>()];
+        csprng.fill_bytes(&mut response_nonce);
+        let ctx = self
+            .0
+            .response_ctx(label, &encapped_key.to_bytes(), &response_nonce)?;
+        Ok((response_nonce, ctx.into()))
+    }
 }
 
 /// The HPKE senders's context. This is what you use to `seal` plaintexts and `export` secrets.
@@ -441,8 +644,43 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
         Ok(buf)
     }
 
+    /// Pads the given plaintext according to `policy`, then seals it and returns the ciphertext.
+    /// The ciphertext length reveals only the padded length. Open it with
+    /// `AeadCtxR::open_padded`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext)` on success. If `policy` can't pad a plaintext of this length,
+    /// returns `Err(HpkeError::ValidationError)`. If this context has been used for so many
+    /// encryptions that the sequence number overflowed, returns
+    /// `Err(HpkeError::MessageLimitReached)`. If an error happened during encryption, returns
+    /// `Err(HpkeError::SealError)`.
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    pub fn seal_padded(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+        policy: &crate::padding::PaddingPolicy,
+    ) -> Result<crate::Vec<u8>, HpkeError> {
+        let msg_len = plaintext.len();
+        let padded_len = policy.padded_len(msg_len)?;
+        let tag_len = AeadTag::<A>::size();
+
+        // Make a buffer that can hold a padded ciphertext + tag. Copy in and pad the plaintext
+        let mut buf = vec![0u8; padded_len + tag_len];
+        buf[..msg_len].copy_from_slice(plaintext);
+        crate::padding::write_padding(&mut buf[..padded_len], msg_len);
+
+        // Seal the whole padded plaintext, and append the tag
+        let tag = self.seal_in_place_detached(&mut buf[..padded_len], aad)?;
+        buf[padded_len..].copy_from_slice(&tag.0);
+
+        Ok(buf)
+    }
+
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
-    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    /// does not depend on sequence number, so it is constant until the context is rekeyed.
     ///
     /// Return Value
     /// ============
@@ -453,6 +691,45 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
         // Pass to AeadCtx
         self.0.export(info, out_buf)
     }
+
+    /// Derives a fresh key, base nonce, and exporter secret from the current exporter secret, and
+    /// zeroizes the old ones. The receiver MUST call `rekey` at the same point in the message stream,
+    /// or decryption will fail. Exported values change after a rekey.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If this context has been rekeyed 2^64 - 1 times, returns
+    /// `Err(HpkeError::MessageLimitReached)`.
+    pub fn rekey(&mut self) -> Result<(), HpkeError> {
+        self.0.rekey()
+    }
+
+    /// Returns the number of times this context has been rekeyed
+    pub fn generation(&self) -> u64 {
+        self.0.generation
+    }
+
+    /// Makes a context for opening the recipient's reply to this session. `label` and
+    /// `encapped_key` MUST be the same as the recipient used in `AeadCtxR::reply_context`, and
+    /// `response_nonce` is the nonce the recipient sent.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ctx)` on success. If `response_nonce` isn't `response_nonce_len::<A>()` bytes
+    /// long, returns `Err(HpkeError::IncorrectInputLength)`.
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    pub fn response_context(
+        &self,
+        label: &[u8],
+        encapped_key: &Kem::EncappedKey,
+        response_nonce: &[u8],
+    ) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError> {
+        let ctx = self
+            .0
+            .response_ctx(label, &encapped_key.to_bytes(), response_nonce)?;
+        Ok(ctx.into())
+    }
 }
 
 // Export all the AEAD implementations
@@ -672,6 +949,125 @@ mod test {
         };
     }
 
+    /// Tests that rekeying both sides keeps them in sync, resets the sequence counter, and
+    /// changes the exporter secret, and that rekeying only one side breaks decryption
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    macro_rules! test_rekey {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let msg = b"the old key is gone";
+                let aad = b"";
+
+                let mut old_export = [0u8; 32];
+                sender_ctx.export(b"", &mut old_export).unwrap();
+
+                // Rekeying lifts an overflowed context back up
+                sender_ctx.0.seq.0 = u64::MAX;
+                receiver_ctx.0.seq.0 = u64::MAX;
+                let ciphertext = sender_ctx.seal(msg, aad).unwrap();
+                receiver_ctx.open(&ciphertext, aad).unwrap();
+                assert!(sender_ctx.seal(msg, aad).is_err());
+
+                sender_ctx.rekey().unwrap();
+                receiver_ctx.rekey().unwrap();
+                assert_eq!(sender_ctx.generation(), 1);
+                assert_eq!(receiver_ctx.0.seq.0, 0);
+                let ciphertext = sender_ctx.seal(msg, aad).unwrap();
+                assert_eq!(receiver_ctx.open(&ciphertext, aad).unwrap(), msg);
+
+                // Exports still agree, but differ from before the rekey
+                let mut sender_export = [0u8; 32];
+                let mut receiver_export = [0u8; 32];
+                sender_ctx.export(b"", &mut sender_export).unwrap();
+                receiver_ctx.export(b"", &mut receiver_export).unwrap();
+                assert_eq!(sender_export, receiver_export);
+                assert_ne!(sender_export, old_export);
+
+                // A sender that's one generation ahead can't be read
+                sender_ctx.rekey().unwrap();
+                let ciphertext = sender_ctx.seal(msg, aad).unwrap();
+                assert!(receiver_ctx.open(&ciphertext, aad).is_err());
+
+                // The generation counter doesn't wrap
+                sender_ctx.0.generation = u64::MAX;
+                assert!(matches!(
+                    sender_ctx.rekey(),
+                    Err(HpkeError::MessageLimitReached)
+                ));
+            }
+        };
+    }
+
+    /// Tests that the recipient can answer the sender with a reply context, and that the
+    /// response nonce and label matter
+    #[cfg(any(feature = "alloc", feature = "std"))]
+    macro_rules! test_reply_context {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{
+                    kem::Kem as KemTrait,
+                    op_mode::{OpModeR, OpModeS},
+                    setup::{setup_receiver, setup_sender},
+                };
+                use rand::{rngs::StdRng, SeedableRng};
+
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, b"", &mut csprng)
+                        .unwrap();
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, b"")
+                        .unwrap();
+
+                let label = b"message/bhttp response";
+                let request = sender_ctx.seal(b"GET /balance", b"").unwrap();
+                receiver_ctx.open(&request, b"").unwrap();
+
+                // The server answers under the same session
+                let (response_nonce, mut reply_ctx) = receiver_ctx
+                    .reply_context(label, &encapped_key, &mut csprng)
+                    .unwrap();
+                assert_eq!(response_nonce.len(), crate::aead::response_nonce_len::<A>());
+                let response = reply_ctx.seal(b"21 BTC", b"").unwrap();
+
+                let mut response_ctx = sender_ctx
+                    .response_context(label, &encapped_key, &response_nonce)
+                    .unwrap();
+                assert_eq!(response_ctx.open(&response, b"").unwrap(), b"21 BTC");
+
+                // A different nonce or label gives a different key
+                let mut bad_nonce = response_nonce.clone();
+                bad_nonce[0] ^= 1;
+                let mut bad_ctx = sender_ctx
+                    .response_context(label, &encapped_key, &bad_nonce)
+                    .unwrap();
+                assert!(bad_ctx.open(&response, b"").is_err());
+                let mut bad_ctx = sender_ctx
+                    .response_context(b"other", &encapped_key, &response_nonce)
+                    .unwrap();
+                assert!(bad_ctx.open(&response, b"").is_err());
+
+                // Truncated nonces are rejected
+                assert!(matches!(
+                    sender_ctx.response_context(label, &encapped_key, &response_nonce[1..]),
+                    Err(HpkeError::IncorrectInputLength(_, _))
+                ));
+            }
+        };
+    }
+
     test_invalid_nonce!(test_invalid_nonce_chacha, ChaCha20Poly1305);
 
     #[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
@@ -685,6 +1081,8 @@ mod test {
             crate::kem::SecpK256HkdfSha256
         );
         test_overflow!(test_overflow_k256, crate::kem::SecpK256HkdfSha256);
+        test_rekey!(test_rekey_k256, crate::kem::SecpK256HkdfSha256);
+        test_reply_context!(test_reply_context_k256, crate::kem::SecpK256HkdfSha256);
 
         test_ctx_correctness!(
             test_ctx_correctness_chacha_k256,
@@ -0,0 +1,219 @@
+//! Receiving `Auth`-mode messages when the sender could be any of several known keys
+//!
+//! `OpModeR::Auth` needs the receiver to know which identity key the sender used. When a message
+//! could come from any of a set of known contacts, the receiver can instead try each candidate,
+//! using the first ciphertext of the session to tell which one is right. [`KnownSenders`]
+//! precomputes the static-static DH with every candidate, so each attempt costs one key schedule
+//! and one AEAD decryption, and the expensive DH with the encapsulated key happens only once per
+//! message.
+
+use crate::{
+    aead::{Aead, AeadCtxR},
+    dhkex::{
+        secp256k1::{KexResult, PrivateKey, PublicKey, Secp256k1},
+        DhKeyExchange,
+    },
+    kdf::Kdf as KdfTrait,
+    kem::{secpk256_hkdfsha256::derive_shared_secret, Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, PskBundle},
+    setup::derive_enc_ctx,
+    HpkeError, Vec,
+};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// A recipient private key along with a list of sender identity keys the recipient accepts
+/// messages from. The static-static DH with each sender is computed once, up front.
+pub struct KnownSenders {
+    sk_recip: PrivateKey,
+    pk_recip: PublicKey,
+    // Each sender's identity pubkey and DH(sk_recip, pk_sender_id)
+    senders: Vec<(PublicKey, KexResult)>,
+}
+
+impl KnownSenders {
+    /// Precomputes the DH of `sk_recip` with every candidate sender identity key
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(known_senders)` on success. If a key exchange fails, returns
+    /// `Err(HpkeError::DecapError)`.
+    pub fn new(sk_recip: &PrivateKey, candidates: &[PublicKey]) -> Result<KnownSenders, HpkeError> {
+        let senders = candidates
+            .iter()
+            .map(|pk| {
+                Secp256k1::dh(sk_recip, pk)
+                    .map(|kex_res| (pk.clone(), kex_res))
+                    .map_err(|_| HpkeError::DecapError)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(KnownSenders {
+            sk_recip: sk_recip.clone(),
+            pk_recip: Kem::sk_to_pk(sk_recip),
+            senders,
+        })
+    }
+
+    /// Returns the candidate sender identity keys, in the order they were given to `new`
+    pub fn candidates(&self) -> impl Iterator<Item = &PublicKey> {
+        self.senders.iter().map(|(pk, _)| pk)
+    }
+
+    /// Initiates a decryption context for a message from one of the known senders, in `Auth`
+    /// mode if `psk` is `None` and `AuthPsk` mode otherwise. `ciphertext` and `aad` are the first
+    /// message of the session, which is used to tell which sender it was. It is opened as part of
+    /// this call, so the returned context is ready for the second message.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((sender_idx, ctx, plaintext))` on success, where `sender_idx` is the index of
+    /// the authenticated sender in the candidate list. If the key exchange fails, returns
+    /// `Err(HpkeError::DecapError)`. If no candidate opens the ciphertext, returns
+    /// `Err(HpkeError::OpenError)`.
+    pub fn setup_receiver<A, Kdf>(
+        &self,
+        psk: Option<PskBundle>,
+        encapped_key: &EncappedKey,
+        info: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<(usize, AeadCtxR<A, Kdf, Kem>, Vec<u8>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        // The ephemeral DH is the same for every candidate
+        let kex_res_eph =
+            Secp256k1::dh(&self.sk_recip, &encapped_key.0).map_err(|_| HpkeError::DecapError)?;
+
+        for (idx, (pk_sender_id, kex_res_identity)) in self.senders.iter().enumerate() {
+            let shared_secret = derive_shared_secret(
+                &kex_res_eph,
+                Some((kex_res_identity, pk_sender_id)),
+                encapped_key,
+                &self.pk_recip,
+            );
+            let mode = match psk {
+                Some(bundle) => OpModeR::AuthPsk(pk_sender_id.clone(), bundle),
+                None => OpModeR::Auth(pk_sender_id.clone()),
+            };
+            let mut ctx: AeadCtxR<A, Kdf, Kem> =
+                derive_enc_ctx::<_, _, Kem, _>(&mode, shared_secret, info).into();
+
+            if let Ok(plaintext) = ctx.open(ciphertext, aad) {
+                return Ok((idx, ctx, plaintext));
+            }
+        }
+
+        Err(HpkeError::OpenError)
+    }
+}
+
+/// Initiates a decryption context for a message from any one of `candidates`. This is a one-off
+/// version of `KnownSenders::setup_receiver`. If you receive many messages from the same set of
+/// senders, make a `KnownSenders` once and reuse it.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((sender_idx, ctx, plaintext))` on success, where `sender_idx` is the index of the
+/// authenticated sender in `candidates`. If a key exchange fails, returns
+/// `Err(HpkeError::DecapError)`. If no candidate opens the ciphertext, returns
+/// `Err(HpkeError::OpenError)`.
+pub fn setup_receiver_any_sender<A, Kdf>(
+    psk: Option<PskBundle>,
+    sk_recip: &PrivateKey,
+    candidates: &[PublicKey],
+    encapped_key: &EncappedKey,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<(usize, AeadCtxR<A, Kdf, Kem>, Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    KnownSenders::new(sk_recip, candidates)?.setup_receiver(
+        psk,
+        encapped_key,
+        info,
+        ciphertext,
+        aad,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, op_mode::OpModeS, setup::setup_sender,
+        test_util::gen_rand_buf,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that the receiver identifies the right sender among several candidates, with and
+    /// without a PSK, and rejects senders that aren't in the list
+    #[test]
+    fn test_any_sender() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"who goes there";
+        let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+        let psk_bundle = PskBundle {
+            psk: &psk,
+            psk_id: &psk_id,
+        };
+
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let senders = [
+            Kem::gen_keypair(&mut csprng),
+            Kem::gen_keypair(&mut csprng),
+            Kem::gen_keypair(&mut csprng),
+        ];
+        let candidates = senders.clone().map(|(_, pk)| pk);
+        let known = KnownSenders::new(&sk_recip, &candidates).unwrap();
+
+        for psk in [None, Some(psk_bundle)] {
+            for (expected_idx, sender_id) in senders.iter().enumerate() {
+                let sender_mode = match psk {
+                    Some(bundle) => OpModeS::AuthPsk(sender_id.clone(), bundle),
+                    None => OpModeS::Auth(sender_id.clone()),
+                };
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, info, &mut csprng)
+                        .unwrap();
+
+                let first = sender_ctx.seal(b"first", b"").unwrap();
+                let second = sender_ctx.seal(b"second", b"").unwrap();
+                let (idx, mut receiver_ctx, plaintext) = known
+                    .setup_receiver::<A, Kdf>(psk, &encapped_key, info, &first, b"")
+                    .unwrap();
+                assert_eq!(idx, expected_idx);
+                assert_eq!(plaintext, b"first");
+                // The context picks up where the first message left off
+                assert_eq!(receiver_ctx.open(&second, b"").unwrap(), b"second");
+            }
+        }
+
+        // A sender who isn't a candidate is rejected
+        let stranger = Kem::gen_keypair(&mut csprng);
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Auth(stranger), &pk_recip, info, &mut csprng)
+                .unwrap();
+        let first = sender_ctx.seal(b"first", b"").unwrap();
+        let res = setup_receiver_any_sender::<A, Kdf>(
+            None,
+            &sk_recip,
+            &candidates,
+            &encapped_key,
+            info,
+            &first,
+            b"",
+        );
+        assert!(matches!(res, Err(HpkeError::OpenError)));
+    }
+}
@@ -0,0 +1,235 @@
+//! Sender authentication by BIP-340 signature
+//!
+//! `Auth` mode authenticates the sender with a static DH key. That's deniable, since the receiver
+//! could have computed the same shared secret themselves, and it requires the receiver to know
+//! the sender's HPKE key ahead of time. In the signed mode here, the sender instead signs the
+//! encapsulated key, the recipient public key, the ciphersuite, and `info` with a BIP-340 key.
+//! The signature travels with the ciphertext, and anyone who sees it can check who set up the
+//! session.
+//!
+//! Only the session setup is attributable. The signature doesn't cover any ciphertext, and
+//! anyone holding the session's context, including the receiver, can seal further messages under
+//! it. So a valid signature shows who started the session, not who wrote a given message in it.
+//! To sign the messages themselves, see the `envelope` module.
+//!
+//! The signature is made in addition to the usual HPKE mode, so it can be combined with `Base` or
+//! `Psk` mode, or even with `Auth` mode.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    schnorr::{Signature, SigningKey, VerifyingKey},
+    setup::{setup_receiver, setup_sender},
+    util::{full_suite_id, tagged_hash},
+    HpkeError, Serializable,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+// msg = hash_HPKE/AuthSig(suite_id || enc || pkRm || info)
+// suite_id, enc, and pkRm are fixed-length, so this encoding is unambiguous
+
+/// Computes the message that the sender signs
+fn auth_sig_msg<A, Kdf>(encapped_key: &EncappedKey, pk_recip: &PublicKey, info: &[u8]) -> [u8; 32]
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    tagged_hash(
+        b"HPKE/AuthSig",
+        &[
+            &full_suite_id::<A, Kdf, Kem>(),
+            &encapped_key.to_bytes(),
+            &pk_recip.to_bytes(),
+            info,
+        ],
+    )
+}
+
+/// Initiates an encryption context to the given recipient public key, and signs the setup with
+/// `signing_key`. The signature MUST be sent to the recipient along with the encapsulated key. It
+/// covers only the setup, not the messages sealed with the returned context.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated public key, a signature, and an encryption context. If an
+/// error happened during key encapsulation, returns `Err(HpkeError::EncapError)`. This is the
+/// only possible error.
+pub fn setup_sender_signed<A, Kdf, R>(
+    mode: &OpModeS<Kem>,
+    signing_key: &SigningKey,
+    pk_recip: &PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey, Signature, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    let (encapped_key, enc_ctx) = setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+
+    let mut aux_rand = [0u8; 32];
+    csprng.fill_bytes(&mut aux_rand);
+    let msg = auth_sig_msg::<A, Kdf>(&encapped_key, pk_recip, info);
+    let signature = signing_key.sign(&msg, &aux_rand);
+
+    Ok((encapped_key, signature, enc_ctx))
+}
+
+/// Verifies that `signature` is `verifying_key`'s signature on this session setup, and if so,
+/// initiates a decryption context. See `setup_receiver`. This attributes the setup to
+/// `verifying_key`, but not the ciphertexts opened with the context.
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If the signature is invalid, returns
+/// `Err(HpkeError::ValidationError)`. If an error happened during key decapsulation, returns
+/// `Err(HpkeError::DecapError)`.
+pub fn setup_receiver_signed<A, Kdf>(
+    mode: &OpModeR<Kem>,
+    verifying_key: &VerifyingKey,
+    sk_recip: &PrivateKey,
+    encapped_key: &EncappedKey,
+    signature: &Signature,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    // Check the signature before doing any work with the private key
+    let pk_recip = Kem::sk_to_pk(sk_recip);
+    let msg = auth_sig_msg::<A, Kdf>(encapped_key, &pk_recip, info);
+    verifying_key.verify(&msg, signature)?;
+
+    setup_receiver(mode, sk_recip, encapped_key, info)
+}
+
+/// Verifies the sender's signature on a session setup without decrypting anything. This is for
+/// third parties who want to check who set up a session. They need the recipient's public key,
+/// but not the private key. It says nothing about who produced any ciphertext in the session.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(())` if the signature is valid. Otherwise returns `Err(HpkeError::ValidationError)`.
+pub fn verify_sender_signature<A, Kdf>(
+    verifying_key: &VerifyingKey,
+    pk_recip: &PublicKey,
+    encapped_key: &EncappedKey,
+    signature: &Signature,
+    info: &[u8],
+) -> Result<(), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    let msg = auth_sig_msg::<A, Kdf>(encapped_key, pk_recip, info);
+    verifying_key.verify(&msg, signature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::{ChaCha20Poly1305, ExportOnlyAead},
+        kdf::HkdfSha256,
+        test_util::{aead_ctx_eq, gen_rand_buf, new_op_mode_pair, OpModeKind},
+        Deserializable,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that signed setup works in every mode, that the signature survives serialization,
+    /// and that signatures from the wrong key, or over a different session, are rejected
+    #[test]
+    fn test_auth_sig() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"signed, sealed, delivered";
+
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let signing_key = SigningKey::gen(&mut csprng);
+        let verifying_key = signing_key.verifying_key();
+        let other_key = SigningKey::gen(&mut csprng).verifying_key();
+
+        for op_mode_kind in &[
+            OpModeKind::Base,
+            OpModeKind::Auth,
+            OpModeKind::Psk,
+            OpModeKind::AuthPsk,
+        ] {
+            let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+            let (sender_mode, receiver_mode) =
+                new_op_mode_pair::<Kem>(*op_mode_kind, &psk, &psk_id);
+
+            let (encapped_key, signature, mut sender_ctx) = setup_sender_signed::<A, Kdf, _>(
+                &sender_mode,
+                &signing_key,
+                &pk_recip,
+                info,
+                &mut csprng,
+            )
+            .unwrap();
+            let signature = Signature::from_bytes(&signature.to_bytes()).unwrap();
+
+            // A third party can check the signature with just public values
+            verify_sender_signature::<A, Kdf>(
+                &verifying_key,
+                &pk_recip,
+                &encapped_key,
+                &signature,
+                info,
+            )
+            .unwrap();
+
+            let mut receiver_ctx = setup_receiver_signed::<A, Kdf>(
+                &receiver_mode,
+                &verifying_key,
+                &sk_recip,
+                &encapped_key,
+                &signature,
+                info,
+            )
+            .unwrap();
+            assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+            // Wrong signer
+            let res = setup_receiver_signed::<A, Kdf>(
+                &receiver_mode,
+                &other_key,
+                &sk_recip,
+                &encapped_key,
+                &signature,
+                info,
+            );
+            assert!(matches!(res, Err(HpkeError::ValidationError)));
+
+            // Different info, and a different ciphersuite
+            assert!(verify_sender_signature::<A, Kdf>(
+                &verifying_key,
+                &pk_recip,
+                &encapped_key,
+                &signature,
+                b"something else",
+            )
+            .is_err());
+            assert!(verify_sender_signature::<ExportOnlyAead, Kdf>(
+                &verifying_key,
+                &pk_recip,
+                &encapped_key,
+                &signature,
+                info,
+            )
+            .is_err());
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//! Binary HTTP (RFC 9292) message encoding
+//!
+//! This is the plaintext format that Oblivious HTTP encapsulates. [`Request`] and [`Response`]
+//! encode to and decode from both the known-length and indeterminate-length framings, optionally
+//! followed by zero padding to hide the message length.
+//!
+//! Decoding accepts truncated messages, where trailing empty sections are left off, as RFC 9292
+//! §3.8 allows. Field names and values are taken as-is. Checking that they are valid HTTP is up to
+//! the application.
+
+use crate::{HpkeError, Vec};
+
+/// Which framing to encode a message with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Every section is prefixed with its length
+    KnownLength,
+    /// Sections are terminated with a zero, so the message can be produced incrementally
+    IndeterminateLength,
+}
+
+// RFC 9292 §3.3: Framing Indicator values
+const KNOWN_LENGTH_REQUEST: u64 = 0;
+const KNOWN_LENGTH_RESPONSE: u64 = 1;
+const INDETERMINATE_LENGTH_REQUEST: u64 = 2;
+const INDETERMINATE_LENGTH_RESPONSE: u64 = 3;
+
+/// A header or trailer field
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    /// The field name. This SHOULD be lowercase.
+    pub name: Vec<u8>,
+    /// The field value
+    pub value: Vec<u8>,
+}
+
+impl Field {
+    /// Makes a field from a name and value
+    pub fn new(name: &[u8], value: &[u8]) -> Field {
+        Field {
+            name: name.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+}
+
+/// An HTTP request
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    /// The method, e.g., `GET`
+    pub method: Vec<u8>,
+    /// The scheme, e.g., `https`
+    pub scheme: Vec<u8>,
+    /// The authority, e.g., `example.com`. This can be empty.
+    pub authority: Vec<u8>,
+    /// The path and query, e.g., `/index.html`
+    pub path: Vec<u8>,
+    /// The header fields
+    pub headers: Vec<Field>,
+    /// The content
+    pub content: Vec<u8>,
+    /// The trailer fields
+    pub trailers: Vec<Field>,
+}
+
+/// An interim response, with a status code from 100 to 199
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InformationalResponse {
+    /// The status code
+    pub status: u16,
+    /// The header fields
+    pub headers: Vec<Field>,
+}
+
+/// An HTTP response
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    /// Any interim responses that came before the final one
+    pub informational: Vec<InformationalResponse>,
+    /// The final status code, from 200 to 599
+    pub status: u16,
+    /// The header fields
+    pub headers: Vec<Field>,
+    /// The content
+    pub content: Vec<u8>,
+    /// The trailer fields
+    pub trailers: Vec<Field>,
+}
+
+// RFC 9000 §16: variable-length integers. The two most significant bits of the first byte give
+// the length of the encoding, which is 1, 2, 4, or 8 bytes.
+
+/// Appends `v` as a variable-length integer
+///
+/// Panics
+/// ======
+/// Panics if `v >= 2^62`
+fn write_varint(out: &mut Vec<u8>, v: u64) {
+    if v < 1 << 6 {
+        out.push(v as u8);
+    } else if v < 1 << 14 {
+        out.extend_from_slice(&(v as u16 | 0x4000).to_be_bytes());
+    } else if v < 1 << 30 {
+        out.extend_from_slice(&(v as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        assert!(v < 1 << 62, "varint is too big");
+        out.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+/// Appends a length-prefixed byte string
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Appends field lines, with no length prefix or terminator
+fn write_field_lines(out: &mut Vec<u8>, fields: &[Field]) {
+    for field in fields {
+        write_bytes(out, &field.name);
+        write_bytes(out, &field.value);
+    }
+}
+
+/// Appends a field section in the given framing
+fn write_field_section(out: &mut Vec<u8>, framing: Framing, fields: &[Field]) {
+    match framing {
+        Framing::KnownLength => {
+            let mut lines = Vec::new();
+            write_field_lines(&mut lines, fields);
+            write_bytes(out, &lines);
+        }
+        Framing::IndeterminateLength => {
+            write_field_lines(out, fields);
+            // Content Terminator
+            write_varint(out, 0);
+        }
+    }
+}
+
+/// Appends content in the given framing. Indeterminate-length content is sent as one chunk.
+fn write_content(out: &mut Vec<u8>, framing: Framing, content: &[u8]) {
+    match framing {
+        Framing::KnownLength => write_bytes(out, content),
+        Framing::IndeterminateLength => {
+            if !content.is_empty() {
+                write_bytes(out, content);
+            }
+            write_varint(out, 0);
+        }
+    }
+}
+
+/// A cursor over an encoded message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HpkeError> {
+        if self.0.len() < len {
+            return Err(HpkeError::ValidationError);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, HpkeError> {
+        let first = *self.0.first().ok_or(HpkeError::ValidationError)?;
+        let len = 1usize << (first >> 6);
+        let bytes = self.take(len)?;
+
+        let mut v = u64::from(first & 0x3f);
+        for b in &bytes[1..] {
+            v = (v << 8) | u64::from(*b);
+        }
+        Ok(v)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], HpkeError> {
+        let len = self.varint()?;
+        let len = usize::try_from(len).map_err(|_| HpkeError::ValidationError)?;
+        self.take(len)
+    }
+
+    fn status(&mut self) -> Result<u16, HpkeError> {
+        let status = self.varint()?;
+        u16::try_from(status).map_err(|_| HpkeError::ValidationError)
+    }
+
+    fn field_section(&mut self, framing: Framing) -> Result<Vec<Field>, HpkeError> {
+        let mut fields = Vec::new();
+        match framing {
+            Framing::KnownLength => {
+                let mut lines = Reader(self.bytes()?);
+                while !lines.is_empty() {
+                    let name = lines.bytes()?.to_vec();
+                    let value = lines.bytes()?.to_vec();
+                    fields.push(Field { name, value });
+                }
+            }
+            Framing::IndeterminateLength => loop {
+                let name = self.bytes()?;
+                // A zero-length name is the Content Terminator
+                if name.is_empty() {
+                    break;
+                }
+                let value = self.bytes()?;
+                fields.push(Field::new(name, value));
+            },
+        }
+        Ok(fields)
+    }
+
+    fn content(&mut self, framing: Framing) -> Result<Vec<u8>, HpkeError> {
+        match framing {
+            Framing::KnownLength => Ok(self.bytes()?.to_vec()),
+            Framing::IndeterminateLength => {
+                let mut content = Vec::new();
+                loop {
+                    let chunk = self.bytes()?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    content.extend_from_slice(chunk);
+                }
+                Ok(content)
+            }
+        }
+    }
+
+    /// Checks that all that's left is padding
+    fn padding(&self) -> Result<(), HpkeError> {
+        if self.0.iter().all(|&b| b == 0) {
+            Ok(())
+        } else {
+            Err(HpkeError::ValidationError)
+        }
+    }
+
+    /// Reads the headers, content, and trailers that end both requests and responses, and the
+    /// padding after them. Missing sections are left empty.
+    fn message_tail(
+        &mut self,
+        framing: Framing,
+    ) -> Result<(Vec<Field>, Vec<u8>, Vec<Field>), HpkeError> {
+        let mut headers = Vec::new();
+        let mut content = Vec::new();
+        let mut trailers = Vec::new();
+        if !self.is_empty() {
+            headers = self.field_section(framing)?;
+        }
+        if !self.is_empty() {
+            content = self.content(framing)?;
+        }
+        if !self.is_empty() {
+            trailers = self.field_section(framing)?;
+        }
+        self.padding()?;
+        Ok((headers, content, trailers))
+    }
+}
+
+/// Reads a framing indicator and checks that it's one of the two given ones
+fn read_framing(r: &mut Reader, known: u64, indeterminate: u64) -> Result<Framing, HpkeError> {
+    match r.varint()? {
+        v if v == known => Ok(Framing::KnownLength),
+        v if v == indeterminate => Ok(Framing::IndeterminateLength),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+// RFC 9292 §3.1
+// Request {
+//   Framing Indicator (i) = 0 or 2,
+//   Request Control Data (..),
+//   Field Section (..),
+//   Content (..),
+//   Field Section (..),
+//   Padding (..),
+// }
+//
+// Request Control Data {
+//   Method Length (i), Method (..),
+//   Scheme Length (i), Scheme (..),
+//   Authority Length (i), Authority (..),
+//   Path Length (i), Path (..),
+// }
+
+impl Request {
+    /// Encodes this request, followed by `padding` zero bytes
+    pub fn encode(&self, framing: Framing, padding: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(
+            &mut out,
+            match framing {
+                Framing::KnownLength => KNOWN_LENGTH_REQUEST,
+                Framing::IndeterminateLength => INDETERMINATE_LENGTH_REQUEST,
+            },
+        );
+        write_bytes(&mut out, &self.method);
+        write_bytes(&mut out, &self.scheme);
+        write_bytes(&mut out, &self.authority);
+        write_bytes(&mut out, &self.path);
+        write_field_section(&mut out, framing, &self.headers);
+        write_content(&mut out, framing, &self.content);
+        write_field_section(&mut out, framing, &self.trailers);
+        out.resize(out.len() + padding, 0);
+        out
+    }
+
+    /// Decodes a request in either framing
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(request)` on success. If `encoded` isn't a valid request, or has nonzero bytes
+    /// after the end of the message, returns `Err(HpkeError::ValidationError)`.
+    pub fn decode(encoded: &[u8]) -> Result<Request, HpkeError> {
+        let mut r = Reader(encoded);
+        let framing = read_framing(&mut r, KNOWN_LENGTH_REQUEST, INDETERMINATE_LENGTH_REQUEST)?;
+        let method = r.bytes()?.to_vec();
+        let scheme = r.bytes()?.to_vec();
+        let authority = r.bytes()?.to_vec();
+        let path = r.bytes()?.to_vec();
+        let (headers, content, trailers) = r.message_tail(framing)?;
+
+        Ok(Request {
+            method,
+            scheme,
+            authority,
+            path,
+            headers,
+            content,
+            trailers,
+        })
+    }
+}
+
+// RFC 9292 §3.1
+// Response {
+//   Framing Indicator (i) = 1 or 3,
+//   Informational Response (..) ...,
+//   Final Response Control Data (..),   // Status Code (i) = 200..599
+//   Field Section (..),
+//   Content (..),
+//   Field Section (..),
+//   Padding (..),
+// }
+//
+// Informational Response {
+//   Informational Response Control Data (..),   // Status Code (i) = 100..199
+//   Field Section (..),
+// }
+
+impl Response {
+    /// Encodes this response, followed by `padding` zero bytes
+    ///
+    /// Panics
+    /// ======
+    /// Panics if an informational status is outside 100 to 199, or the final status is outside
+    /// 200 to 599
+    pub fn encode(&self, framing: Framing, padding: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(
+            &mut out,
+            match framing {
+                Framing::KnownLength => KNOWN_LENGTH_RESPONSE,
+                Framing::IndeterminateLength => INDETERMINATE_LENGTH_RESPONSE,
+            },
+        );
+        for info in &self.informational {
+            assert!(
+                (100..200).contains(&info.status),
+                "informational status must be 1xx"
+            );
+            write_varint(&mut out, info.status.into());
+            write_field_section(&mut out, framing, &info.headers);
+        }
+        assert!(
+            (200..600).contains(&self.status),
+            "final status must be 2xx to 5xx"
+        );
+        write_varint(&mut out, self.status.into());
+        write_field_section(&mut out, framing, &self.headers);
+        write_content(&mut out, framing, &self.content);
+        write_field_section(&mut out, framing, &self.trailers);
+        out.resize(out.len() + padding, 0);
+        out
+    }
+
+    /// Decodes a response in either framing
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(response)` on success. If `encoded` isn't a valid response, or has nonzero
+    /// bytes after the end of the message, returns `Err(HpkeError::ValidationError)`.
+    pub fn decode(encoded: &[u8]) -> Result<Response, HpkeError> {
+        let mut r = Reader(encoded);
+        let framing = read_framing(&mut r, KNOWN_LENGTH_RESPONSE, INDETERMINATE_LENGTH_RESPONSE)?;
+
+        let mut informational = Vec::new();
+        let status = loop {
+            let status = r.status()?;
+            match status {
+                100..=199 => informational.push(InformationalResponse {
+                    status,
+                    headers: r.field_section(framing)?,
+                }),
+                200..=599 => break status,
+                _ => return Err(HpkeError::ValidationError),
+            }
+        };
+        let (headers, content, trailers) = r.message_tail(framing)?;
+
+        Ok(Response {
+            informational,
+            status,
+            headers,
+            content,
+            trailers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Tests both framings of requests and responses, padding, truncation, and the RFC 9292
+    /// examples, then sends a request through OHTTP to a stand-in gateway
+    #[test]
+    fn test_bhttp() {
+        // RFC 9292 §5.1, known-length request for GET https://www.example.com/hello.txt
+        let example: &[u8] = &[
+            0x00, 0x03, 0x47, 0x45, 0x54, 0x05, 0x68, 0x74, 0x74, 0x70, 0x73, 0x00, 0x0a, 0x2f,
+            0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x40, 0x6c, 0x0a, 0x75, 0x73,
+            0x65, 0x72, 0x2d, 0x61, 0x67, 0x65, 0x6e, 0x74, 0x34, 0x63, 0x75, 0x72, 0x6c, 0x2f,
+            0x37, 0x2e, 0x31, 0x36, 0x2e, 0x33, 0x20, 0x6c, 0x69, 0x62, 0x63, 0x75, 0x72, 0x6c,
+            0x2f, 0x37, 0x2e, 0x31, 0x36, 0x2e, 0x33, 0x20, 0x4f, 0x70, 0x65, 0x6e, 0x53, 0x53,
+            0x4c, 0x2f, 0x30, 0x2e, 0x39, 0x2e, 0x37, 0x6c, 0x20, 0x7a, 0x6c, 0x69, 0x62, 0x2f,
+            0x31, 0x2e, 0x32, 0x2e, 0x33, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x0f, 0x77, 0x77, 0x77,
+            0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x0f, 0x61,
+            0x63, 0x63, 0x65, 0x70, 0x74, 0x2d, 0x6c, 0x61, 0x6e, 0x67, 0x75, 0x61, 0x67, 0x65,
+            0x06, 0x65, 0x6e, 0x2c, 0x20, 0x6d, 0x69, 0x00, 0x00,
+        ];
+        let request = Request {
+            method: b"GET".to_vec(),
+            scheme: b"https".to_vec(),
+            authority: Vec::new(),
+            path: b"/hello.txt".to_vec(),
+            headers: vec![
+                Field::new(
+                    b"user-agent",
+                    b"curl/7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3",
+                ),
+                Field::new(b"host", b"www.example.com"),
+                Field::new(b"accept-language", b"en, mi"),
+            ],
+            content: Vec::new(),
+            trailers: Vec::new(),
+        };
+        assert_eq!(Request::decode(example).unwrap(), request);
+        assert_eq!(request.encode(Framing::KnownLength, 0), example);
+
+        // Both framings round-trip, with and without padding
+        let response = Response {
+            informational: vec![InformationalResponse {
+                status: 103,
+                headers: vec![Field::new(b"link", b"</style.css>; rel=preload")],
+            }],
+            status: 200,
+            headers: vec![Field::new(b"content-type", b"text/plain")],
+            content: b"This content contains CRLF.\r\n".to_vec(),
+            trailers: vec![Field::new(b"trailer", b"text")],
+        };
+        for &framing in &[Framing::KnownLength, Framing::IndeterminateLength] {
+            for &padding in &[0, 17] {
+                let encoded = request.encode(framing, padding);
+                assert_eq!(Request::decode(&encoded).unwrap(), request);
+                let encoded = response.encode(framing, padding);
+                assert_eq!(Response::decode(&encoded).unwrap(), response);
+            }
+        }
+
+        // Truncated trailing sections decode as empty, but nonzero padding doesn't decode
+        assert_eq!(
+            Request::decode(&example[..example.len() - 2]).unwrap(),
+            request
+        );
+        let mut bad = request.encode(Framing::KnownLength, 4);
+        *bad.last_mut().unwrap() = 1;
+        assert!(Request::decode(&bad).is_err());
+        assert!(Response::decode(example).is_err());
+
+        #[cfg(feature = "secp")]
+        {
+            use crate::{
+                aead::ChaCha20Poly1305,
+                kdf::HkdfSha256,
+                kem::{Kem, SecpK256HkdfSha256},
+                ohttp::{encapsulate_request, Gateway, KeyConfig, SymmetricSuite},
+            };
+            use rand::{rngs::StdRng, SeedableRng};
+
+            type A = ChaCha20Poly1305;
+            type Kdf = HkdfSha256;
+
+            let mut csprng = StdRng::from_entropy();
+            let (sk, pk) = SecpK256HkdfSha256::gen_keypair(&mut csprng);
+            let gateway = Gateway::new(
+                KeyConfig {
+                    key_id: 1,
+                    public_key: pk,
+                    symmetric: vec![SymmetricSuite::new::<A, Kdf>()],
+                },
+                sk,
+            );
+
+            let (enc_request, client) = encapsulate_request::<A, Kdf, _>(
+                gateway.config(),
+                &request.encode(Framing::KnownLength, 32),
+                &mut csprng,
+            )
+            .unwrap();
+            let (plaintext, server) = gateway.decapsulate_request::<A, Kdf>(&enc_request).unwrap();
+            assert_eq!(Request::decode(&plaintext).unwrap(), request);
+
+            let enc_response = server
+                .encapsulate_response(
+                    &response.encode(Framing::IndeterminateLength, 0),
+                    &mut csprng,
+                )
+                .unwrap();
+            let plaintext = client.decapsulate_response(&enc_response).unwrap();
+            assert_eq!(Response::decode(&plaintext).unwrap(), response);
+        }
+    }
+}
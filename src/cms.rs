@@ -0,0 +1,859 @@
+//! CMS `KEMRecipientInfo` (RFC 9629)
+//!
+//! This lets a KEM deliver the content-encryption key (CEK) of a CMS `EnvelopedData` or
+//! `AuthEnvelopedData`, so S/MIME-style documents can be encrypted to the same keys as everything
+//! else. The sender encapsulates to the recipient's public key, derives a key-encryption key (KEK)
+//! from the shared secret with HKDF (RFC 8619), and wraps the CEK under it. The result is a
+//! [`KemRecipientInfo`], which is DER-encoded inside an `OtherRecipientInfo` with type
+//! `id-ori-kem`.
+//!
+//! The wrap algorithm is supplied through the [`KeyWrap`] trait. Deployed implementations use AES
+//! Key Wrap (RFC 3394), which the `aes-kw` feature provides as `Aes128Wrap` and `Aes256Wrap`.
+
+use crate::{
+    kdf::{Kdf as KdfTrait, SimpleHkdf},
+    kem::Kem as KemTrait,
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+/// The OID `id-ori-kem` (1.2.840.113549.1.9.16.13.3), in DER content octets
+pub const ID_ORI_KEM: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x0d, 0x03,
+];
+/// The OID `id-aes128-wrap` (2.16.840.1.101.3.4.1.5), in DER content octets
+pub const ID_AES128_WRAP: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x05];
+/// The OID `id-aes256-wrap` (2.16.840.1.101.3.4.1.45), in DER content octets
+pub const ID_AES256_WRAP: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2d];
+/// The OID `id-alg-hkdf-with-sha256` (1.2.840.113549.1.9.16.3.28), in DER content octets
+pub const ID_ALG_HKDF_WITH_SHA256: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x03, 0x1c,
+];
+/// The OID `id-alg-hkdf-with-sha384` (1.2.840.113549.1.9.16.3.29), in DER content octets
+pub const ID_ALG_HKDF_WITH_SHA384: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x03, 0x1d,
+];
+/// The OID `id-alg-hkdf-with-sha512` (1.2.840.113549.1.9.16.3.30), in DER content octets
+pub const ID_ALG_HKDF_WITH_SHA512: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x03, 0x1e,
+];
+
+// DER tags
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+// rid's subjectKeyIdentifier is [0] IMPLICIT OCTET STRING
+const TAG_RID_SKI: u8 = 0x80;
+// ukm is [0] EXPLICIT UserKeyingMaterial
+const TAG_UKM: u8 = 0xa0;
+// RecipientInfo's ori is [4] IMPLICIT OtherRecipientInfo
+const TAG_ORI: u8 = 0xa4;
+
+/// An X.509 `AlgorithmIdentifier`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlgorithmIdentifier {
+    /// The algorithm's OID, as DER content octets (i.e., without the tag and length)
+    pub algorithm: Vec<u8>,
+    /// The DER encoding of the parameters, if any
+    pub parameters: Option<Vec<u8>>,
+}
+
+impl AlgorithmIdentifier {
+    fn write_der(&self, out: &mut Vec<u8>) {
+        let mut content = Vec::new();
+        write_tlv(&mut content, TAG_OID, &self.algorithm);
+        if let Some(parameters) = &self.parameters {
+            content.extend_from_slice(parameters);
+        }
+        write_tlv(out, TAG_SEQUENCE, &content);
+    }
+
+    fn read_der(reader: &mut DerReader<'_>) -> Result<AlgorithmIdentifier, HpkeError> {
+        let mut fields = DerReader {
+            buf: reader.expect(TAG_SEQUENCE)?,
+        };
+        let algorithm = fields.expect(TAG_OID)?.to_vec();
+        let parameters = if fields.buf.is_empty() {
+            None
+        } else {
+            let (_, _, whole) = fields.tlv()?;
+            Some(whole.to_vec())
+        };
+        fields.finish()?;
+
+        Ok(AlgorithmIdentifier {
+            algorithm,
+            parameters,
+        })
+    }
+}
+
+/// Returns the HKDF algorithm identifier for `Kdf`. RFC 8619 §3 says the parameters are absent.
+fn hkdf_algorithm<Kdf: KdfTrait>() -> Result<AlgorithmIdentifier, HpkeError> {
+    let algorithm = match Kdf::KDF_ID {
+        0x0001 => ID_ALG_HKDF_WITH_SHA256,
+        0x0002 => ID_ALG_HKDF_WITH_SHA384,
+        0x0003 => ID_ALG_HKDF_WITH_SHA512,
+        _ => return Err(HpkeError::ValidationError),
+    };
+    Ok(AlgorithmIdentifier {
+        algorithm: algorithm.to_vec(),
+        parameters: None,
+    })
+}
+
+/// Identifies the recipient's certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecipientIdentifier {
+    /// The DER encoding of an `IssuerAndSerialNumber` SEQUENCE
+    IssuerAndSerialNumber(Vec<u8>),
+    /// The subject key identifier of the certificate
+    SubjectKeyIdentifier(Vec<u8>),
+}
+
+/// A key-wrap algorithm that a [`KemRecipientInfo`] uses to encrypt the CEK under the KEK, such
+/// as AES Key Wrap (RFC 3394)
+pub trait KeyWrap {
+    /// The length of the KEK, in bytes
+    const KEK_LEN: u16;
+
+    /// The algorithm identifier of this algorithm
+    fn algorithm() -> AlgorithmIdentifier;
+
+    /// Wraps `cek` under `kek`, which is `KEK_LEN` bytes long
+    fn wrap(kek: &[u8], cek: &[u8]) -> Result<Vec<u8>, HpkeError>;
+
+    /// Unwraps `wrapped` under `kek`, which is `KEK_LEN` bytes long. Fails if the wrapped key
+    /// doesn't authenticate.
+    fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, HpkeError>;
+}
+
+// RFC 3565 §2.3.2
+// The AlgorithmIdentifier parameters field MUST be absent.
+
+/// AES Key Wrap (RFC 3394) with a 128-bit KEK, `id-aes128-wrap`
+#[cfg(feature = "aes-kw")]
+#[cfg_attr(docsrs, doc(cfg(feature = "aes-kw")))]
+pub struct Aes128Wrap;
+
+#[cfg(feature = "aes-kw")]
+impl KeyWrap for Aes128Wrap {
+    const KEK_LEN: u16 = 16;
+
+    fn algorithm() -> AlgorithmIdentifier {
+        AlgorithmIdentifier {
+            algorithm: ID_AES128_WRAP.to_vec(),
+            parameters: None,
+        }
+    }
+
+    fn wrap(kek: &[u8], cek: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let kek = aes_kw::KekAes128::try_from(kek).map_err(|_| HpkeError::ValidationError)?;
+        aes_wrap(cek, |cek, out| kek.wrap(cek, out))
+    }
+
+    fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let kek = aes_kw::KekAes128::try_from(kek).map_err(|_| HpkeError::ValidationError)?;
+        aes_unwrap(wrapped, |wrapped, out| kek.unwrap(wrapped, out))
+    }
+}
+
+/// AES Key Wrap (RFC 3394) with a 256-bit KEK, `id-aes256-wrap`
+#[cfg(feature = "aes-kw")]
+#[cfg_attr(docsrs, doc(cfg(feature = "aes-kw")))]
+pub struct Aes256Wrap;
+
+#[cfg(feature = "aes-kw")]
+impl KeyWrap for Aes256Wrap {
+    const KEK_LEN: u16 = 32;
+
+    fn algorithm() -> AlgorithmIdentifier {
+        AlgorithmIdentifier {
+            algorithm: ID_AES256_WRAP.to_vec(),
+            parameters: None,
+        }
+    }
+
+    fn wrap(kek: &[u8], cek: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let kek = aes_kw::KekAes256::try_from(kek).map_err(|_| HpkeError::ValidationError)?;
+        aes_wrap(cek, |cek, out| kek.wrap(cek, out))
+    }
+
+    fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let kek = aes_kw::KekAes256::try_from(kek).map_err(|_| HpkeError::ValidationError)?;
+        aes_unwrap(wrapped, |wrapped, out| kek.unwrap(wrapped, out))
+    }
+}
+
+/// Runs AES Key Wrap on `cek`. Fails if the CEK isn't a multiple of 8 bytes, or is shorter than
+/// 16.
+#[cfg(feature = "aes-kw")]
+fn aes_wrap<F>(cek: &[u8], wrap: F) -> Result<Vec<u8>, HpkeError>
+where
+    F: FnOnce(&[u8], &mut [u8]) -> aes_kw::Result<()>,
+{
+    let mut wrapped = vec![0u8; cek.len() + aes_kw::IV_LEN];
+    wrap(cek, &mut wrapped).map_err(|_| HpkeError::ValidationError)?;
+    Ok(wrapped)
+}
+
+/// Runs AES Key Unwrap on `wrapped`, which includes checking its integrity
+#[cfg(feature = "aes-kw")]
+fn aes_unwrap<F>(wrapped: &[u8], unwrap: F) -> Result<Vec<u8>, HpkeError>
+where
+    F: FnOnce(&[u8], &mut [u8]) -> aes_kw::Result<()>,
+{
+    let cek_len = wrapped
+        .len()
+        .checked_sub(aes_kw::IV_LEN)
+        .ok_or(HpkeError::OpenError)?;
+    let mut cek = vec![0u8; cek_len];
+    if unwrap(wrapped, &mut cek).is_err() {
+        cek.zeroize();
+        return Err(HpkeError::OpenError);
+    }
+    Ok(cek)
+}
+
+// RFC 9629 §3
+// KEMRecipientInfo ::= SEQUENCE {
+//   version CMSVersion,  -- always set to 0
+//   rid RecipientIdentifier,
+//   kem KEMAlgorithmIdentifier,
+//   kemct OCTET STRING,
+//   kdf KeyDerivationAlgorithmIdentifier,
+//   kekLength INTEGER (1..65535),
+//   ukm [0] EXPLICIT UserKeyingMaterial OPTIONAL,
+//   wrap KeyEncryptionAlgorithmIdentifier,
+//   encryptedKey EncryptedKey }
+
+/// A CEK encrypted to one recipient with a KEM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KemRecipientInfo {
+    /// Identifies the recipient's certificate
+    pub rid: RecipientIdentifier,
+    /// The KEM algorithm
+    pub kem: AlgorithmIdentifier,
+    /// The KEM ciphertext, i.e., the serialized encapsulated key
+    pub kemct: Vec<u8>,
+    /// The KDF that derives the KEK
+    pub kdf: AlgorithmIdentifier,
+    /// The length of the KEK, in bytes
+    pub kek_length: u16,
+    /// Optional user keying material, mixed into the KEK derivation
+    pub ukm: Option<Vec<u8>>,
+    /// The key-wrap algorithm
+    pub wrap: AlgorithmIdentifier,
+    /// The wrapped CEK
+    pub encrypted_key: Vec<u8>,
+}
+
+// RFC 9629 §5
+// CMSORIforKEMOtherInfo ::= SEQUENCE {
+//   wrap KeyEncryptionAlgorithmIdentifier,
+//   kekLength INTEGER (1..65535),
+//   ukm [0] EXPLICIT UserKeyingMaterial OPTIONAL }
+//
+// KEK = KDF(IKM=ss, L=kekLength, info=DER(CMSORIforKEMOtherInfo))
+
+/// Returns the DER encoding of `CMSORIforKEMOtherInfo`, the HKDF info string for the KEK
+fn kem_other_info(wrap: &AlgorithmIdentifier, kek_length: u16, ukm: Option<&[u8]>) -> Vec<u8> {
+    let mut content = Vec::new();
+    wrap.write_der(&mut content);
+    write_tlv(&mut content, TAG_INTEGER, &der_uint(kek_length));
+    write_ukm(&mut content, ukm);
+    let mut info = Vec::new();
+    write_tlv(&mut info, TAG_SEQUENCE, &content);
+    info
+}
+
+/// Derives the KEK from the KEM shared secret, with HKDF and an absent salt
+fn derive_kek<Kdf: KdfTrait>(
+    shared_secret: &[u8],
+    wrap: &AlgorithmIdentifier,
+    kek_length: u16,
+    ukm: Option<&[u8]>,
+) -> Result<Vec<u8>, HpkeError> {
+    let info = kem_other_info(wrap, kek_length, ukm);
+    let mut kek = vec![0u8; kek_length as usize];
+    SimpleHkdf::<Kdf>::new(None, shared_secret)
+        .expand(&info, &mut kek)
+        .map_err(|_| HpkeError::KdfOutputTooLong)?;
+    Ok(kek)
+}
+
+fn write_ukm(out: &mut Vec<u8>, ukm: Option<&[u8]>) {
+    if let Some(ukm) = ukm {
+        let mut octet_string = Vec::new();
+        write_tlv(&mut octet_string, TAG_OCTET_STRING, ukm);
+        write_tlv(out, TAG_UKM, &octet_string);
+    }
+}
+
+impl KemRecipientInfo {
+    /// Encapsulates to `pk_recip`, and wraps `cek` under a KEK derived from the shared secret with
+    /// HKDF over `Kdf`. `kem` is the algorithm identifier of `Kem`, which is recorded as-is. If
+    /// `ukm` is given, it is mixed into the KEK and sent alongside it.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(recipient_info)` on success. If `Kdf` has no HKDF algorithm identifier or
+    /// `W::KEK_LEN` is zero, returns `Err(HpkeError::ValidationError)`. If `W::KEK_LEN` is longer
+    /// than HKDF can output, returns `Err(HpkeError::KdfOutputTooLong)`. If an error happened
+    /// during key encapsulation, returns `Err(HpkeError::EncapError)`. If wrapping fails, returns
+    /// the error from `W::wrap`.
+    pub fn seal<Kdf, Kem, W, R>(
+        rid: RecipientIdentifier,
+        kem: AlgorithmIdentifier,
+        pk_recip: &Kem::PublicKey,
+        cek: &[u8],
+        ukm: Option<&[u8]>,
+        csprng: &mut R,
+    ) -> Result<KemRecipientInfo, HpkeError>
+    where
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+        W: KeyWrap,
+        R: CryptoRng + RngCore,
+    {
+        let kdf = hkdf_algorithm::<Kdf>()?;
+        if W::KEK_LEN == 0 {
+            return Err(HpkeError::ValidationError);
+        }
+        let wrap = W::algorithm();
+
+        let (shared_secret, encapped_key) = Kem::encap(pk_recip, None, csprng)?;
+        let mut kek = derive_kek::<Kdf>(&shared_secret.0, &wrap, W::KEK_LEN, ukm)?;
+        let encrypted_key = W::wrap(&kek, cek);
+        kek.zeroize();
+
+        Ok(KemRecipientInfo {
+            rid,
+            kem,
+            kemct: encapped_key.to_bytes().to_vec(),
+            kdf,
+            kek_length: W::KEK_LEN,
+            ukm: ukm.map(|ukm| ukm.to_vec()),
+            wrap,
+            encrypted_key: encrypted_key?,
+        })
+    }
+
+    /// Decapsulates `kemct` with `sk_recip`, and unwraps the CEK. The `kdf`, `wrap`, and
+    /// `kek_length` fields must match `Kdf` and `W`. Checking that `kem` names the algorithm of
+    /// `sk_recip` is up to the caller.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(cek)` on success. If the fields don't match `Kdf` and `W`, or `kemct` is
+    /// malformed, returns `Err(HpkeError::ValidationError)`. If an error happened during key
+    /// decapsulation, returns `Err(HpkeError::DecapError)`. If unwrapping fails, returns the error
+    /// from `W::unwrap`.
+    pub fn open<Kdf, Kem, W>(&self, sk_recip: &Kem::PrivateKey) -> Result<Vec<u8>, HpkeError>
+    where
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+        W: KeyWrap,
+    {
+        if self.kdf != hkdf_algorithm::<Kdf>()?
+            || self.wrap != W::algorithm()
+            || self.kek_length != W::KEK_LEN
+        {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let encapped_key =
+            Kem::EncappedKey::from_bytes(&self.kemct).map_err(|_| HpkeError::ValidationError)?;
+        let shared_secret = Kem::decap(sk_recip, None, &encapped_key)?;
+        let mut kek = derive_kek::<Kdf>(
+            &shared_secret.0,
+            &self.wrap,
+            self.kek_length,
+            self.ukm.as_deref(),
+        )?;
+        let cek = W::unwrap(&kek, &self.encrypted_key);
+        kek.zeroize();
+
+        cek
+    }
+
+    /// Returns the DER encoding of this `KEMRecipientInfo`
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_tlv(&mut content, TAG_INTEGER, &[0]);
+        match &self.rid {
+            RecipientIdentifier::IssuerAndSerialNumber(der) => content.extend_from_slice(der),
+            RecipientIdentifier::SubjectKeyIdentifier(ski) => {
+                write_tlv(&mut content, TAG_RID_SKI, ski)
+            }
+        }
+        self.kem.write_der(&mut content);
+        write_tlv(&mut content, TAG_OCTET_STRING, &self.kemct);
+        self.kdf.write_der(&mut content);
+        write_tlv(&mut content, TAG_INTEGER, &der_uint(self.kek_length));
+        write_ukm(&mut content, self.ukm.as_deref());
+        self.wrap.write_der(&mut content);
+        write_tlv(&mut content, TAG_OCTET_STRING, &self.encrypted_key);
+
+        let mut out = Vec::new();
+        write_tlv(&mut out, TAG_SEQUENCE, &content);
+        out
+    }
+
+    /// Decodes a DER-encoded `KEMRecipientInfo`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(recipient_info)` on success. If the encoding is malformed, not DER, or has a
+    /// version other than 0 or a `kekLength` outside 1..65535, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn from_der(encoded: &[u8]) -> Result<KemRecipientInfo, HpkeError> {
+        let mut reader = DerReader { buf: encoded };
+        let mut fields = DerReader {
+            buf: reader.expect(TAG_SEQUENCE)?,
+        };
+        reader.finish()?;
+
+        if fields.expect(TAG_INTEGER)? != [0] {
+            return Err(HpkeError::ValidationError);
+        }
+        let rid = match fields.tlv()? {
+            (TAG_SEQUENCE, _, whole) => RecipientIdentifier::IssuerAndSerialNumber(whole.to_vec()),
+            (TAG_RID_SKI, ski, _) => RecipientIdentifier::SubjectKeyIdentifier(ski.to_vec()),
+            _ => return Err(HpkeError::ValidationError),
+        };
+        let kem = AlgorithmIdentifier::read_der(&mut fields)?;
+        let kemct = fields.expect(TAG_OCTET_STRING)?.to_vec();
+        let kdf = AlgorithmIdentifier::read_der(&mut fields)?;
+        let kek_length = read_der_uint(fields.expect(TAG_INTEGER)?)?;
+        let ukm = if fields.buf.first() == Some(&TAG_UKM) {
+            let mut explicit = DerReader {
+                buf: fields.expect(TAG_UKM)?,
+            };
+            let ukm = explicit.expect(TAG_OCTET_STRING)?.to_vec();
+            explicit.finish()?;
+            Some(ukm)
+        } else {
+            None
+        };
+        let wrap = AlgorithmIdentifier::read_der(&mut fields)?;
+        let encrypted_key = fields.expect(TAG_OCTET_STRING)?.to_vec();
+        fields.finish()?;
+
+        Ok(KemRecipientInfo {
+            rid,
+            kem,
+            kemct,
+            kdf,
+            kek_length,
+            ukm,
+            wrap,
+            encrypted_key,
+        })
+    }
+
+    // RFC 5652 §6.2.5
+    // OtherRecipientInfo ::= SEQUENCE {
+    //   oriType OBJECT IDENTIFIER,
+    //   oriValue ANY DEFINED BY oriType }
+
+    /// Returns the DER encoding of this as a `RecipientInfo`, i.e., an `[4] IMPLICIT
+    /// OtherRecipientInfo` with type `id-ori-kem`. This is what goes in the `recipientInfos` of an
+    /// `EnvelopedData`.
+    pub fn to_recipient_info_der(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_tlv(&mut content, TAG_OID, ID_ORI_KEM);
+        content.extend_from_slice(&self.to_der());
+
+        let mut out = Vec::new();
+        write_tlv(&mut out, TAG_ORI, &content);
+        out
+    }
+
+    /// Decodes a DER-encoded `RecipientInfo` made by [`KemRecipientInfo::to_recipient_info_der`]
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(recipient_info)` on success. If the encoding is malformed, or isn't an
+    /// `OtherRecipientInfo` with type `id-ori-kem`, returns `Err(HpkeError::ValidationError)`.
+    pub fn from_recipient_info_der(encoded: &[u8]) -> Result<KemRecipientInfo, HpkeError> {
+        let mut reader = DerReader { buf: encoded };
+        let mut fields = DerReader {
+            buf: reader.expect(TAG_ORI)?,
+        };
+        reader.finish()?;
+
+        if fields.expect(TAG_OID)? != ID_ORI_KEM {
+            return Err(HpkeError::ValidationError);
+        }
+        KemRecipientInfo::from_der(fields.buf)
+    }
+}
+
+//-------- DER --------//
+
+/// Writes a tag, its DER length, and `content`
+fn write_tlv(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        // Long form: the number of length bytes, then the length with no leading zeros
+        let len_bytes = (len as u64).to_be_bytes();
+        let skip = len_bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (8 - skip) as u8);
+        out.extend_from_slice(&len_bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+}
+
+/// Returns the DER INTEGER content octets of `n`
+fn der_uint(n: u16) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    // Drop a redundant leading zero, but keep one where the high bit would make it negative
+    let content = match bytes {
+        [0, low] if low < 0x80 => &bytes[1..],
+        _ => &bytes[..],
+    };
+    let mut out = Vec::with_capacity(3);
+    if content[0] >= 0x80 {
+        out.push(0);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Reads the content octets of a DER INTEGER in 1..65535
+fn read_der_uint(content: &[u8]) -> Result<u16, HpkeError> {
+    let minimal = match content {
+        [] => false,
+        [0, next, ..] => *next >= 0x80,
+        [first, ..] => *first < 0x80,
+    };
+    if !minimal || content.len() > 3 {
+        return Err(HpkeError::ValidationError);
+    }
+
+    let value = content.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32);
+    match u16::try_from(value) {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+/// A cursor over a DER encoding
+struct DerReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    /// Reads one element, returning `(tag, content, whole_element)`. Only single-byte tags and
+    /// minimal definite lengths are accepted.
+    fn tlv(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), HpkeError> {
+        let whole = self.buf;
+        let (&tag, rest) = whole.split_first().ok_or(HpkeError::ValidationError)?;
+        let (&first_len, mut rest) = rest.split_first().ok_or(HpkeError::ValidationError)?;
+        if tag & 0x1f == 0x1f {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let len = if first_len < 0x80 {
+            first_len as usize
+        } else {
+            // The long form must be needed, and must not have leading zeros
+            let num_bytes = (first_len & 0x7f) as usize;
+            if num_bytes == 0 || num_bytes > 4 || num_bytes > rest.len() || rest[0] == 0 {
+                return Err(HpkeError::ValidationError);
+            }
+            let (len_bytes, after) = rest.split_at(num_bytes);
+            rest = after;
+            let len = len_bytes
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            if len < 0x80 {
+                return Err(HpkeError::ValidationError);
+            }
+            len
+        };
+
+        if len > rest.len() {
+            return Err(HpkeError::ValidationError);
+        }
+        let (content, rest) = rest.split_at(len);
+        self.buf = rest;
+        let whole_len = whole.len() - rest.len();
+        Ok((tag, content, &whole[..whole_len]))
+    }
+
+    /// Reads one element with the given tag, and returns its content
+    fn expect(&mut self, expected_tag: u8) -> Result<&'a [u8], HpkeError> {
+        match self.tlv()? {
+            (tag, content, _) if tag == expected_tag => Ok(content),
+            _ => Err(HpkeError::ValidationError),
+        }
+    }
+
+    /// Checks that everything has been read
+    fn finish(&self) -> Result<(), HpkeError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(HpkeError::ValidationError)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "secp"))]
+mod test {
+    use super::*;
+    use crate::{
+        aead::{Aead, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    };
+
+    use aead::{AeadInPlace, KeyInit};
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    /// A wrap under a private OID, to test that any `KeyWrap` works. Each KEK is only ever used
+    /// once, so a fixed nonce is safe.
+    struct TestWrap;
+
+    impl KeyWrap for TestWrap {
+        const KEK_LEN: u16 = 32;
+
+        fn algorithm() -> AlgorithmIdentifier {
+            AlgorithmIdentifier {
+                // 1.3.6.1.4.1.55555.1, under an arbitrary private enterprise number
+                algorithm: vec![0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb2, 0x03, 0x01],
+                parameters: None,
+            }
+        }
+
+        fn wrap(kek: &[u8], cek: &[u8]) -> Result<Vec<u8>, HpkeError> {
+            let cipher = <ChaCha20Poly1305 as Aead>::AeadImpl::new_from_slice(kek).unwrap();
+            let mut wrapped = cek.to_vec();
+            let tag = cipher
+                .encrypt_in_place_detached(&Default::default(), b"", &mut wrapped)
+                .map_err(|_| HpkeError::SealError)?;
+            wrapped.extend_from_slice(&tag);
+            Ok(wrapped)
+        }
+
+        fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, HpkeError> {
+            let cipher = <ChaCha20Poly1305 as Aead>::AeadImpl::new_from_slice(kek).unwrap();
+            let split = wrapped.len().checked_sub(16).ok_or(HpkeError::OpenError)?;
+            let (body, tag) = wrapped.split_at(split);
+            let mut cek = body.to_vec();
+            cipher
+                .decrypt_in_place_detached(&Default::default(), b"", &mut cek, tag.into())
+                .map_err(|_| HpkeError::OpenError)?;
+            Ok(cek)
+        }
+    }
+
+    /// Tests that a sealed `KEMRecipientInfo` survives DER encoding and opens to the CEK, and that
+    /// changing the UKM, KDF, or KEK length stops it opening
+    #[test]
+    fn test_kem_recipient_info() {
+        type Kdf = HkdfSha256;
+        type Kem = SecpK256HkdfSha256;
+
+        // INTEGER encodings are minimal, with a leading zero only to keep the sign bit clear
+        assert_eq!(der_uint(16), [0x10]);
+        assert_eq!(der_uint(128), [0x00, 0x80]);
+        assert_eq!(der_uint(256), [0x01, 0x00]);
+        assert_eq!(der_uint(65535), [0x00, 0xff, 0xff]);
+        for n in [1, 16, 127, 128, 256, 65535] {
+            assert_eq!(read_der_uint(&der_uint(n)), Ok(n));
+        }
+        for bad in [
+            &[][..],
+            &[0x00],
+            &[0x00, 0x10],
+            &[0x80],
+            &[0x01, 0x00, 0x00],
+        ] {
+            assert_eq!(read_der_uint(bad), Err(HpkeError::ValidationError));
+        }
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let mut cek = [0u8; 32];
+        csprng.fill_bytes(&mut cek);
+
+        let kem_alg = AlgorithmIdentifier {
+            // 1.3.6.1.4.1.55555.2
+            algorithm: vec![0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb2, 0x03, 0x02],
+            parameters: None,
+        };
+        let rid = RecipientIdentifier::SubjectKeyIdentifier(vec![0xab; 20]);
+
+        let ri = KemRecipientInfo::seal::<Kdf, Kem, TestWrap, _>(
+            rid,
+            kem_alg.clone(),
+            &pk_recip,
+            &cek,
+            Some(b"archive 2024"),
+            &mut csprng,
+        )
+        .unwrap();
+        assert_eq!(ri.kem, kem_alg);
+        assert_eq!(ri.kdf.algorithm, ID_ALG_HKDF_WITH_SHA256);
+        assert_eq!(ri.kemct.len(), 65);
+
+        // The DER starts with the version, then the subject key identifier
+        let der = ri.to_der();
+        assert_eq!(der[0], TAG_SEQUENCE);
+        assert_eq!(der[3..8], [0x02, 0x01, 0x00, 0x80, 20]);
+        assert_eq!(KemRecipientInfo::from_der(&der).unwrap(), ri);
+
+        let recipient_info = ri.to_recipient_info_der();
+        assert_eq!(recipient_info[0], TAG_ORI);
+        let decoded = KemRecipientInfo::from_recipient_info_der(&recipient_info).unwrap();
+        assert_eq!(decoded, ri);
+        assert_eq!(decoded.open::<Kdf, Kem, TestWrap>(&sk_recip).unwrap(), cek);
+
+        // The UKM feeds into the KEK, so dropping it changes the KEK and unwrapping fails
+        let mut no_ukm = ri.clone();
+        no_ukm.ukm = None;
+        assert_eq!(
+            no_ukm.open::<Kdf, Kem, TestWrap>(&sk_recip),
+            Err(HpkeError::OpenError)
+        );
+        // Fields that don't match the opener's algorithms are rejected up front
+        let mut wrong_len = ri.clone();
+        wrong_len.kek_length = 16;
+        assert_eq!(
+            wrong_len.open::<Kdf, Kem, TestWrap>(&sk_recip),
+            Err(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            ri.open::<crate::kdf::HkdfSha384, Kem, TestWrap>(&sk_recip),
+            Err(HpkeError::ValidationError)
+        );
+
+        // Truncated, trailing, and non-minimal encodings are rejected
+        assert!(KemRecipientInfo::from_der(&der[..der.len() - 1]).is_err());
+        let mut trailing = der.clone();
+        trailing.push(0);
+        assert!(KemRecipientInfo::from_der(&trailing).is_err());
+        let mut long_form = vec![TAG_SEQUENCE, 0x82];
+        long_form.extend_from_slice(&((der.len() - 3) as u16).to_be_bytes());
+        long_form.extend_from_slice(&der[3..]);
+        assert!(der[1] == 0x81 && KemRecipientInfo::from_der(&long_form).is_err());
+    }
+
+    /// Tests the DER `CMSORIforKEMOtherInfo` and the KEK derived from it. The DER is written out
+    /// by hand from the ASN.1 in RFC 9629 §5, and the KEKs were computed with Python's
+    /// `cryptography` HKDF-SHA256.
+    #[test]
+    fn test_derive_kek_kat() {
+        let shared_secret: Vec<u8> = (0..32).collect();
+        let aes128_wrap = AlgorithmIdentifier {
+            algorithm: ID_AES128_WRAP.to_vec(),
+            parameters: None,
+        };
+        let aes256_wrap = AlgorithmIdentifier {
+            algorithm: ID_AES256_WRAP.to_vec(),
+            parameters: None,
+        };
+
+        // SEQUENCE { SEQUENCE { id-aes128-wrap }, INTEGER 16 }
+        assert_eq!(
+            kem_other_info(&aes128_wrap, 16, None),
+            hex::decode("3010300b0609608648016503040105020110").unwrap()
+        );
+        assert_eq!(
+            derive_kek::<HkdfSha256>(&shared_secret, &aes128_wrap, 16, None).unwrap(),
+            hex::decode("fcad36b947c339cd4f4ebd4b8322d573").unwrap()
+        );
+
+        // SEQUENCE { SEQUENCE { id-aes256-wrap }, INTEGER 32, [0] { OCTET STRING ukm } }
+        let ukm = b"archive 2024";
+        assert_eq!(
+            kem_other_info(&aes256_wrap, 32, Some(ukm)),
+            hex::decode("3020300b060960864801650304012d020120a00e040c617263686976652032303234")
+                .unwrap()
+        );
+        assert_eq!(
+            derive_kek::<HkdfSha256>(&shared_secret, &aes256_wrap, 32, Some(ukm)).unwrap(),
+            hex::decode("f517f2f5bc8e7170e94211aa19c34580318aa062b763b73a952e42c9493ac28d")
+                .unwrap()
+        );
+    }
+
+    /// Tests AES Key Wrap against RFC 3394 §4.1 and §4.6, and a `KEMRecipientInfo` round trip
+    /// with it
+    #[cfg(feature = "aes-kw")]
+    #[test]
+    fn test_aes_key_wrap() {
+        type Kdf = HkdfSha256;
+        type Kem = SecpK256HkdfSha256;
+
+        let kek = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let key_data = hex::decode("00112233445566778899aabbccddeeff").unwrap();
+        let wrapped = hex::decode("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5").unwrap();
+        assert_eq!(Aes128Wrap::wrap(&kek, &key_data).unwrap(), wrapped);
+        assert_eq!(Aes128Wrap::unwrap(&kek, &wrapped).unwrap(), key_data);
+
+        let kek = hex::decode("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+            .unwrap();
+        let key_data =
+            hex::decode("00112233445566778899aabbccddeeff000102030405060708090a0b0c0d0e0f")
+                .unwrap();
+        let wrapped = hex::decode(
+            "28c9f404c4b810f4cbccb35cfb87f8263f5786e2d80ed326cbc7f0e71a99f43bfb988b9b7a02dd21",
+        )
+        .unwrap();
+        assert_eq!(Aes256Wrap::wrap(&kek, &key_data).unwrap(), wrapped);
+        assert_eq!(Aes256Wrap::unwrap(&kek, &wrapped).unwrap(), key_data);
+
+        // Tampered or truncated keys fail the integrity check, and CEKs AES-KW can't wrap are
+        // refused
+        let mut tampered = wrapped.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            Aes256Wrap::unwrap(&kek, &tampered),
+            Err(HpkeError::OpenError)
+        );
+        assert_eq!(Aes256Wrap::unwrap(&kek, &[0; 4]), Err(HpkeError::OpenError));
+        assert_eq!(
+            Aes256Wrap::wrap(&kek, &[0; 15]),
+            Err(HpkeError::ValidationError)
+        );
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let mut cek = [0u8; 32];
+        csprng.fill_bytes(&mut cek);
+        let kem_alg = AlgorithmIdentifier {
+            // 1.3.6.1.4.1.55555.2
+            algorithm: vec![0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb2, 0x03, 0x02],
+            parameters: None,
+        };
+        let ri = KemRecipientInfo::seal::<Kdf, Kem, Aes128Wrap, _>(
+            RecipientIdentifier::SubjectKeyIdentifier(vec![0xab; 20]),
+            kem_alg,
+            &pk_recip,
+            &cek,
+            None,
+            &mut csprng,
+        )
+        .unwrap();
+        assert_eq!(ri.wrap.algorithm, ID_AES128_WRAP);
+        assert_eq!(ri.wrap.parameters, None);
+        assert_eq!(ri.kek_length, 16);
+        assert_eq!(ri.encrypted_key.len(), 40);
+        let ri = KemRecipientInfo::from_der(&ri.to_der()).unwrap();
+        assert_eq!(ri.open::<Kdf, Kem, Aes128Wrap>(&sk_recip).unwrap(), cek);
+        assert_eq!(
+            ri.open::<Kdf, Kem, Aes256Wrap>(&sk_recip),
+            Err(HpkeError::ValidationError)
+        );
+    }
+}
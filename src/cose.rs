@@ -0,0 +1,745 @@
+//! COSE-HPKE (draft-ietf-cose-hpke) message encoding
+//!
+//! This encrypts messages as CBOR Object Signing and Encryption structures, so constrained
+//! devices that already speak COSE can use HPKE identities. Two layouts are supported:
+//!
+//! * Integrated encryption, a tagged `COSE_Encrypt0` whose ciphertext is sealed directly to one
+//!   recipient with HPKE. See [`seal_encrypt0`] and [`open_encrypt0`].
+//! * Key encryption, a tagged `COSE_Encrypt` whose content is sealed under a random content key,
+//!   which is in turn HPKE-sealed to each recipient. See [`seal_encrypt`] and [`open_encrypt`].
+//!
+//! In both, the encapsulated key travels in the `ek` header parameter of the HPKE layer, and the
+//! HPKE algorithm identifier in its protected `alg` parameter. The IANA registry only assigns
+//! identifiers to NIST and X25519/X448 suites, so secp256k1 suites must use an identifier agreed
+//! on out of band, e.g., one from the private-use range below -65536.
+//!
+//! Only the subset of CBOR that these structures need is implemented. Indefinite-length items and
+//! nested tags are rejected.
+
+use crate::{
+    aead::{Aead, AeadNonce, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    multi_recipient::{random_cek, unwrap_cek, wrap_cek},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use aead::{AeadInPlace as BaseAeadInPlace, KeyInit as BaseKeyInit};
+use rand_core::{CryptoRng, RngCore};
+
+/// The CBOR tag of a `COSE_Encrypt0` message
+pub const COSE_ENCRYPT0_TAG: u64 = 16;
+/// The CBOR tag of a `COSE_Encrypt` message
+pub const COSE_ENCRYPT_TAG: u64 = 96;
+
+/// The `alg` header parameter label
+pub const HEADER_ALG: i64 = 1;
+/// The `kid` header parameter label
+pub const HEADER_KID: i64 = 4;
+/// The `IV` header parameter label
+pub const HEADER_IV: i64 = 5;
+/// The `ek` header parameter label, which carries the HPKE encapsulated key
+pub const HEADER_EK: i64 = -4;
+
+// COSE messages nest CBOR at most four levels deep (message, recipients, recipient, header map).
+// The decoder refuses to recurse much past that.
+const MAX_DEPTH: usize = 8;
+
+/// Returns the COSE algorithm identifier of the content encryption algorithm `A`
+fn content_alg<A: Aead>() -> Result<i64, HpkeError> {
+    match A::AEAD_ID {
+        // RFC 9053 §4.1: A128GCM and A256GCM
+        0x0001 => Ok(1),
+        0x0002 => Ok(3),
+        // RFC 9053 §4.3: ChaCha20/Poly1305
+        0x0003 => Ok(24),
+        // The export-only AEAD can't encrypt content
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+//-------- CBOR --------//
+
+/// A decoded CBOR data item. Text strings are kept as raw bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Null,
+}
+
+// RFC 8949 §3: the initial byte is a 3-bit major type and a 5-bit argument, where arguments above
+// 23 say how many big-endian bytes follow
+fn write_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+    let major = major << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    } else if arg <= u8::MAX as u64 {
+        out.extend_from_slice(&[major | 24, arg as u8]);
+    } else if arg <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(arg as u16).to_be_bytes());
+    } else if arg <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(arg as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+fn encode_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(n) if *n >= 0 => write_head(out, 0, *n as u64),
+        Value::Int(n) => write_head(out, 1, !(*n) as u64),
+        Value::Bytes(b) => {
+            write_head(out, 2, b.len() as u64);
+            out.extend_from_slice(b);
+        }
+        Value::Text(t) => {
+            write_head(out, 3, t.len() as u64);
+            out.extend_from_slice(t);
+        }
+        Value::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            items.iter().for_each(|item| encode_value(out, item));
+        }
+        Value::Map(entries) => {
+            write_head(out, 5, entries.len() as u64);
+            for (k, v) in entries {
+                encode_value(out, k);
+                encode_value(out, v);
+            }
+        }
+        Value::Null => out.push(0xf6),
+    }
+}
+
+fn to_cbor(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_value(&mut out, value);
+    out
+}
+
+/// A cursor over a CBOR encoding
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HpkeError> {
+        if len > self.buf.len() {
+            return Err(HpkeError::ValidationError);
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    /// Reads an initial byte and its argument, returning `(major_type, additional_info, arg)`
+    fn head(&mut self) -> Result<(u8, u8, u64), HpkeError> {
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let arg = match info {
+            0..=23 => info as u64,
+            24..=27 => {
+                let mut arg_bytes = [0u8; 8];
+                let len = 1 << (info - 24);
+                arg_bytes[8 - len..].copy_from_slice(self.take(len)?);
+                u64::from_be_bytes(arg_bytes)
+            }
+            // Reserved values and indefinite lengths
+            _ => return Err(HpkeError::ValidationError),
+        };
+        Ok((major, info, arg))
+    }
+
+    fn take_len(&mut self, len: u64) -> Result<&'a [u8], HpkeError> {
+        let len = usize::try_from(len).map_err(|_| HpkeError::ValidationError)?;
+        self.take(len)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, HpkeError> {
+        if depth > MAX_DEPTH {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (major, info, arg) = self.head()?;
+        let value = match major {
+            0 => Value::Int(i64::try_from(arg).map_err(|_| HpkeError::ValidationError)?),
+            1 => Value::Int(!i64::try_from(arg).map_err(|_| HpkeError::ValidationError)?),
+            2 => Value::Bytes(self.take_len(arg)?.to_vec()),
+            3 => Value::Text(self.take_len(arg)?.to_vec()),
+            4 => {
+                // Don't preallocate from an untrusted count
+                let mut items = Vec::new();
+                for _ in 0..arg {
+                    items.push(self.value(depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            5 => {
+                let mut entries: Vec<(Value, Value)> = Vec::new();
+                for _ in 0..arg {
+                    let k = self.value(depth + 1)?;
+                    // Duplicate map keys make a header ambiguous
+                    if entries.iter().any(|(existing, _)| *existing == k) {
+                        return Err(HpkeError::ValidationError);
+                    }
+                    let v = self.value(depth + 1)?;
+                    entries.push((k, v));
+                }
+                Value::Map(entries)
+            }
+            7 if info == 22 => Value::Null,
+            // Tags are only allowed at the top level, and other simple values and floats don't
+            // appear in these structures
+            _ => return Err(HpkeError::ValidationError),
+        };
+
+        Ok(value)
+    }
+}
+
+/// Decodes a single CBOR item that takes up all of `buf`
+fn from_cbor(buf: &[u8]) -> Result<Value, HpkeError> {
+    let mut reader = Reader { buf };
+    let value = reader.value(0)?;
+    if !reader.buf.is_empty() {
+        return Err(HpkeError::ValidationError);
+    }
+    Ok(value)
+}
+
+/// Decodes a COSE message, which must be an array of `len` items. The message may optionally be
+/// wrapped in `tag`.
+fn decode_message(buf: &[u8], tag: u64, len: usize) -> Result<Vec<Value>, HpkeError> {
+    let mut reader = Reader { buf };
+    if buf.first().map(|b| b >> 5) == Some(6) {
+        let (_, _, found_tag) = reader.head()?;
+        if found_tag != tag {
+            return Err(HpkeError::ValidationError);
+        }
+    }
+    let value = reader.value(0)?;
+    if !reader.buf.is_empty() {
+        return Err(HpkeError::ValidationError);
+    }
+
+    match value {
+        Value::Array(items) if items.len() == len => Ok(items),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+fn write_tag(out: &mut Vec<u8>, tag: u64) {
+    write_head(out, 6, tag);
+}
+
+//-------- Headers --------//
+
+/// Returns the entries of a header map
+fn header_map(value: &Value) -> Result<&[(Value, Value)], HpkeError> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+/// Looks up an integer header label
+fn header_get(entries: &[(Value, Value)], label: i64) -> Option<&Value> {
+    entries
+        .iter()
+        .find(|(k, _)| *k == Value::Int(label))
+        .map(|(_, v)| v)
+}
+
+fn header_bytes(entries: &[(Value, Value)], label: i64) -> Result<&[u8], HpkeError> {
+    match header_get(entries, label) {
+        Some(Value::Bytes(b)) => Ok(b),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+/// Decodes a protected header bucket, which is a serialized map, or empty for no parameters
+fn protected_header(value: &Value) -> Result<Vec<(Value, Value)>, HpkeError> {
+    match value {
+        Value::Bytes(b) if b.is_empty() => Ok(Vec::new()),
+        Value::Bytes(b) => match from_cbor(b)? {
+            Value::Map(entries) => Ok(entries),
+            _ => Err(HpkeError::ValidationError),
+        },
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+/// Makes the protected header `{alg: alg}`
+fn alg_header(alg: i64) -> Vec<u8> {
+    to_cbor(&Value::Map(vec![(Value::Int(HEADER_ALG), Value::Int(alg))]))
+}
+
+/// Makes the unprotected header of an HPKE layer, `{kid: kid, ek: enc}`. Keys are in CBOR
+/// deterministic order, which puts `kid` (4) before `ek` (-4).
+fn hpke_unprotected_header<Kem: KemTrait>(
+    kid: Option<&[u8]>,
+    encapped_key: &Kem::EncappedKey,
+) -> Value {
+    let mut entries = Vec::new();
+    if let Some(kid) = kid {
+        entries.push((Value::Int(HEADER_KID), Value::Bytes(kid.to_vec())));
+    }
+    entries.push((
+        Value::Int(HEADER_EK),
+        Value::Bytes(encapped_key.to_bytes().to_vec()),
+    ));
+    Value::Map(entries)
+}
+
+/// Checks that an HPKE layer's protected header names `alg`, and returns the encapsulated key in
+/// its unprotected header
+fn hpke_layer_params<Kem: KemTrait>(
+    protected: &[(Value, Value)],
+    unprotected: &[(Value, Value)],
+    alg: i64,
+) -> Result<Kem::EncappedKey, HpkeError> {
+    if header_get(protected, HEADER_ALG) != Some(&Value::Int(alg)) {
+        return Err(HpkeError::ValidationError);
+    }
+    Kem::EncappedKey::from_bytes(header_bytes(unprotected, HEADER_EK)?)
+}
+
+// RFC 9052 §5.3
+// Enc_structure = [
+//     context : "Encrypt" / "Encrypt0" / "Enc_Recipient" /
+//         "Mac_Recipient" / "Rec_Recipient",
+//     protected : empty_or_serialized_map,
+//     external_aad : bstr
+// ]
+
+/// Encodes the `Enc_structure` used as the AAD of a content layer
+fn enc_structure(context: &[u8], protected: &[u8], external_aad: &[u8]) -> Vec<u8> {
+    to_cbor(&Value::Array(vec![
+        Value::Text(context.to_vec()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(external_aad.to_vec()),
+    ]))
+}
+
+// draft-ietf-cose-hpke, Key Encryption
+// Recipient_structure = [
+//     context: "HPKE Recipient",
+//     next_layer_alg: int / tstr,
+//     recipient_protected_header: empty_or_serialized_map,
+//     recipient_extra_info: bstr
+// ]
+
+/// Encodes the `Recipient_structure` used as the HPKE info string of a recipient layer
+fn recipient_structure(next_layer_alg: i64, protected: &[u8]) -> Vec<u8> {
+    to_cbor(&Value::Array(vec![
+        Value::Text(b"HPKE Recipient".to_vec()),
+        Value::Int(next_layer_alg),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(Vec::new()),
+    ]))
+}
+
+//-------- COSE_Encrypt0 --------//
+
+/// Seals `plaintext` to `pk_recip` as a tagged `COSE_Encrypt0` message using HPKE integrated
+/// encryption. `alg` is the HPKE algorithm identifier placed in the protected header, and `kid`
+/// optionally identifies the recipient key. `external_aad` is authenticated but not sent.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(message)` on success. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`. If an error happened during encryption, returns
+/// `Err(HpkeError::SealError)`.
+pub fn seal_encrypt0<A, Kdf, Kem, R>(
+    alg: i64,
+    pk_recip: &Kem::PublicKey,
+    kid: Option<&[u8]>,
+    plaintext: &[u8],
+    external_aad: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let protected = alg_header(alg);
+    let aad = enc_structure(b"Encrypt0", &protected, external_aad);
+
+    let (encapped_key, mut ctx) =
+        setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, pk_recip, b"", csprng)?;
+    let mut ciphertext = plaintext.to_vec();
+    let tag = ctx.seal_in_place_detached(&mut ciphertext, &aad)?;
+    ciphertext.extend_from_slice(&tag.to_bytes());
+
+    let mut out = Vec::new();
+    write_tag(&mut out, COSE_ENCRYPT0_TAG);
+    encode_value(
+        &mut out,
+        &Value::Array(vec![
+            Value::Bytes(protected),
+            hpke_unprotected_header::<Kem>(kid, &encapped_key),
+            Value::Bytes(ciphertext),
+        ]),
+    );
+
+    Ok(out)
+}
+
+/// Opens a `COSE_Encrypt0` message made by [`seal_encrypt0`]. The message's protected `alg` must
+/// equal `alg`. The CBOR tag is optional.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If the message is malformed, is detached, or names a
+/// different algorithm, returns `Err(HpkeError::ValidationError)`. If an error happened during key
+/// decapsulation, returns `Err(HpkeError::DecapError)`. If an error happened during decryption,
+/// returns `Err(HpkeError::OpenError)`.
+pub fn open_encrypt0<A, Kdf, Kem>(
+    alg: i64,
+    sk_recip: &Kem::PrivateKey,
+    message: &[u8],
+    external_aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let items = decode_message(message, COSE_ENCRYPT0_TAG, 3)?;
+    let (protected_bytes, ciphertext) = match (&items[0], &items[2]) {
+        (Value::Bytes(p), Value::Bytes(ct)) => (p, ct),
+        _ => return Err(HpkeError::ValidationError),
+    };
+    let protected = protected_header(&items[0])?;
+    let encapped_key = hpke_layer_params::<Kem>(&protected, header_map(&items[1])?, alg)?;
+
+    // The AAD covers the protected header exactly as it was sent
+    let aad = enc_structure(b"Encrypt0", protected_bytes, external_aad);
+
+    let mut ctx = setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, sk_recip, &encapped_key, b"")?;
+    ctx.open(ciphertext, &aad)
+}
+
+//-------- COSE_Encrypt --------//
+
+/// A recipient of a `COSE_Encrypt` message
+pub struct CoseRecipient<'a, Kem: KemTrait> {
+    /// The recipient's public key
+    pub public_key: &'a Kem::PublicKey,
+    /// An optional key ID that lets the recipient find its entry
+    pub kid: Option<&'a [u8]>,
+}
+
+/// Seals `plaintext` to every recipient as a tagged `COSE_Encrypt` message using HPKE key
+/// encryption. The content is encrypted with `A` under a random content key, which is HPKE-sealed
+/// to each recipient. `alg` is the HPKE algorithm identifier placed in each recipient's protected
+/// header. `external_aad` is authenticated but not sent.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(message)` on success. If `A` has no COSE content encryption identifier, returns
+/// `Err(HpkeError::ValidationError)`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`. If an error happened during encryption, returns
+/// `Err(HpkeError::SealError)`.
+pub fn seal_encrypt<A, Kdf, Kem, R>(
+    alg: i64,
+    recipients: &[CoseRecipient<'_, Kem>],
+    plaintext: &[u8],
+    external_aad: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let content_alg = content_alg::<A>()?;
+
+    // The content layer gets its own key and IV, which go nowhere but the recipient layer
+    let cek = random_cek::<A, R>(csprng);
+    let mut iv = AeadNonce::<A>::default();
+    csprng.fill_bytes(iv.0.as_mut_slice());
+
+    // Seal the content
+    let protected = alg_header(content_alg);
+    let aad = enc_structure(b"Encrypt", &protected, external_aad);
+    let mut ciphertext = plaintext.to_vec();
+    let tag = <A::AeadImpl as BaseKeyInit>::new(&cek.0)
+        .encrypt_in_place_detached(&iv.0, &aad, &mut ciphertext)
+        .map_err(|_| HpkeError::SealError)?;
+    ciphertext.extend_from_slice(&tag);
+
+    // Every COSE_recipient shares one protected header, so they share the Recipient_structure
+    // that binds the CEK to the content algorithm
+    let recipient_protected = alg_header(alg);
+    let info = recipient_structure(content_alg, &recipient_protected);
+    let mut recipient_values = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let (encapped_key, encrypted_cek) = wrap_cek::<A, Kdf, Kem, R>(
+            &OpModeS::Base,
+            recipient.public_key,
+            &info,
+            b"",
+            &cek,
+            csprng,
+        )?;
+
+        recipient_values.push(Value::Array(vec![
+            Value::Bytes(recipient_protected.clone()),
+            hpke_unprotected_header::<Kem>(recipient.kid, &encapped_key),
+            Value::Bytes(encrypted_cek),
+        ]));
+    }
+
+    let mut out = Vec::new();
+    write_tag(&mut out, COSE_ENCRYPT_TAG);
+    encode_value(
+        &mut out,
+        &Value::Array(vec![
+            Value::Bytes(protected),
+            Value::Map(vec![(Value::Int(HEADER_IV), Value::Bytes(iv.0.to_vec()))]),
+            Value::Bytes(ciphertext),
+            Value::Array(recipient_values),
+        ]),
+    );
+
+    Ok(out)
+}
+
+/// Opens a `COSE_Encrypt` message made by [`seal_encrypt`]. If `kid` is given, only recipients
+/// with that key ID are tried. Otherwise every recipient whose `alg` is `alg` is tried in turn.
+/// The CBOR tag is optional.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If the message is malformed, or its content algorithm isn't
+/// `A`, returns `Err(HpkeError::ValidationError)`. If no recipient entry opens with `sk_recip`, or
+/// the content fails to decrypt, returns `Err(HpkeError::OpenError)`.
+pub fn open_encrypt<A, Kdf, Kem>(
+    alg: i64,
+    sk_recip: &Kem::PrivateKey,
+    kid: Option<&[u8]>,
+    message: &[u8],
+    external_aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let content_alg = content_alg::<A>()?;
+    let items = decode_message(message, COSE_ENCRYPT_TAG, 4)?;
+
+    // Check the content layer's parameters
+    let protected_bytes = match &items[0] {
+        Value::Bytes(b) => b,
+        _ => return Err(HpkeError::ValidationError),
+    };
+    let protected = protected_header(&items[0])?;
+    if header_get(&protected, HEADER_ALG) != Some(&Value::Int(content_alg)) {
+        return Err(HpkeError::ValidationError);
+    }
+    let iv = header_bytes(header_map(&items[1])?, HEADER_IV)?;
+    let mut nonce = AeadNonce::<A>::default();
+    if iv.len() != nonce.0.len() {
+        return Err(HpkeError::ValidationError);
+    }
+    nonce.0.copy_from_slice(iv);
+    let ciphertext = match &items[2] {
+        Value::Bytes(ct) => ct,
+        _ => return Err(HpkeError::ValidationError),
+    };
+    let recipients = match &items[3] {
+        Value::Array(recipients) => recipients,
+        _ => return Err(HpkeError::ValidationError),
+    };
+
+    // Try each COSE_recipient until one gives us the CEK
+    let mut cek = None;
+    for recipient in recipients {
+        let fields = match recipient {
+            Value::Array(fields) if fields.len() == 3 => fields,
+            _ => return Err(HpkeError::ValidationError),
+        };
+        let recipient_protected = protected_header(&fields[0])?;
+        let unprotected = header_map(&fields[1])?;
+
+        // Recipients of other HPKE suites, or with a kid that isn't ours, aren't tried
+        if header_get(&recipient_protected, HEADER_ALG) != Some(&Value::Int(alg)) {
+            continue;
+        }
+        if let Some(kid) = kid {
+            if header_get(unprotected, HEADER_KID) != Some(&Value::Bytes(kid.to_vec())) {
+                continue;
+            }
+        }
+
+        let (recipient_protected_bytes, encrypted_cek) = match (&fields[0], &fields[2]) {
+            (Value::Bytes(p), Value::Bytes(c)) => (p, c),
+            _ => return Err(HpkeError::ValidationError),
+        };
+        let encapped_key = hpke_layer_params::<Kem>(&recipient_protected, unprotected, alg)?;
+        let info = recipient_structure(content_alg, recipient_protected_bytes);
+        if let Ok(key) = unwrap_cek::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            sk_recip,
+            &encapped_key,
+            &info,
+            b"",
+            encrypted_cek,
+        ) {
+            cek = Some(key);
+            break;
+        }
+    }
+    let cek = cek.ok_or(HpkeError::OpenError)?;
+    let cipher = <A::AeadImpl as BaseKeyInit>::new(&cek.0);
+
+    // Open the content
+    let tag_len = AeadTag::<A>::size();
+    let msg_len = ciphertext
+        .len()
+        .checked_sub(tag_len)
+        .ok_or(HpkeError::OpenError)?;
+    let (ciphertext, tag_bytes) = ciphertext.split_at(msg_len);
+    let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+    let aad = enc_structure(b"Encrypt", protected_bytes, external_aad);
+    let mut plaintext = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(&nonce.0, &aad, &mut plaintext, &tag.0)
+        .map_err(|_| HpkeError::OpenError)?;
+
+    Ok(plaintext)
+}
+
+#[cfg(all(test, feature = "secp"))]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // An identifier from the COSE private-use range
+    const ALG: i64 = -65537;
+
+    /// Tests the CBOR layout of both message types, that each recipient can open them, and that
+    /// changing the external AAD, algorithm, or key ID stops them opening
+    #[test]
+    fn test_cose_hpke() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = SecpK256HkdfSha256;
+
+        // Deterministic encodings of the structures that get authenticated
+        assert_eq!(alg_header(ALG), [0xa1, 0x01, 0x3a, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            enc_structure(b"Encrypt0", &[0xa1, 0x01, 0x18, 0x18], b"\x01"),
+            [
+                &[0x83, 0x68][..],
+                b"Encrypt0",
+                &[0x44, 0xa1, 0x01, 0x18, 0x18, 0x41, 0x01]
+            ]
+            .concat()
+        );
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk1, pk1) = Kem::gen_keypair(&mut csprng);
+        let (sk2, pk2) = Kem::gen_keypair(&mut csprng);
+        let msg = b"sensor reading: 21.5C";
+        let aad = b"device 7";
+
+        // COSE_Encrypt0
+        let encrypt0 =
+            seal_encrypt0::<A, Kdf, Kem, _>(ALG, &pk1, Some(b"k1"), msg, aad, &mut csprng).unwrap();
+        // Tag 16, then a 3-element array starting with the protected header
+        assert_eq!(encrypt0[..3], [0xd0, 0x83, 0x47]);
+        let items = decode_message(&encrypt0, COSE_ENCRYPT0_TAG, 3).unwrap();
+        let unprotected = header_map(&items[1]).unwrap();
+        assert_eq!(header_bytes(unprotected, HEADER_KID).unwrap(), b"k1");
+        assert_eq!(header_bytes(unprotected, HEADER_EK).unwrap().len(), 65);
+
+        assert_eq!(
+            open_encrypt0::<A, Kdf, Kem>(ALG, &sk1, &encrypt0, aad).unwrap(),
+            msg
+        );
+        assert_eq!(
+            open_encrypt0::<A, Kdf, Kem>(ALG, &sk1, &encrypt0, b"device 8"),
+            Err(HpkeError::OpenError)
+        );
+        assert_eq!(
+            open_encrypt0::<A, Kdf, Kem>(ALG - 1, &sk1, &encrypt0, aad),
+            Err(HpkeError::ValidationError)
+        );
+        // A COSE_Encrypt tag isn't accepted in place of COSE_Encrypt0
+        let mut retagged = encrypt0.clone();
+        retagged[0] = 0xd8;
+        retagged.insert(1, 0x60);
+        assert_eq!(
+            open_encrypt0::<A, Kdf, Kem>(ALG, &sk1, &retagged, aad),
+            Err(HpkeError::ValidationError)
+        );
+
+        // COSE_Encrypt to two recipients
+        let recipients = [
+            CoseRecipient {
+                public_key: &pk1,
+                kid: Some(&b"k1"[..]),
+            },
+            CoseRecipient {
+                public_key: &pk2,
+                kid: Some(&b"k2"[..]),
+            },
+        ];
+        let encrypt =
+            seal_encrypt::<A, Kdf, Kem, _>(ALG, &recipients, msg, aad, &mut csprng).unwrap();
+        // Tag 96, then a 4-element array starting with the protected header {alg: ChaCha20/Poly1305}
+        assert_eq!(
+            encrypt[..8],
+            [0xd8, 0x60, 0x84, 0x44, 0xa1, 0x01, 0x18, 0x18]
+        );
+
+        for (sk, kid) in [(&sk1, &b"k1"[..]), (&sk2, &b"k2"[..])].iter() {
+            assert_eq!(
+                open_encrypt::<A, Kdf, Kem>(ALG, sk, Some(kid), &encrypt, aad).unwrap(),
+                msg
+            );
+            assert_eq!(
+                open_encrypt::<A, Kdf, Kem>(ALG, sk, None, &encrypt, aad).unwrap(),
+                msg
+            );
+            assert_eq!(
+                open_encrypt::<A, Kdf, Kem>(ALG, sk, Some(kid), &encrypt, b""),
+                Err(HpkeError::OpenError)
+            );
+        }
+        // Looking under the other recipient's key ID finds nothing to open
+        assert_eq!(
+            open_encrypt::<A, Kdf, Kem>(ALG, &sk1, Some(b"k2"), &encrypt, aad),
+            Err(HpkeError::OpenError)
+        );
+
+        // Malformed CBOR is rejected rather than panicking
+        for bad in [&[][..], &[0xd0], &[0xd0, 0x9f], &[0xd0, 0x83, 0x5b, 0xff]].iter() {
+            assert_eq!(
+                open_encrypt0::<A, Kdf, Kem>(ALG, &sk1, bad, aad),
+                Err(HpkeError::ValidationError)
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Proofs of correct decapsulation
+//!
+//! A recipient can prove to a third party, e.g., an arbitrator, what a ciphertext decrypted to,
+//! without revealing their private key. The recipient reveals the DH result `sk_recip⋅enc`
+//! together with a BIP-374 DLEQ proof that it was computed with the private key behind
+//! `pk_recip`. The verifier checks the proof and then runs the rest of the decapsulation and the
+//! key schedule themselves, ending up with the same decryption context as the recipient.
+//!
+//! The DH result only opens the one session it came from, but it does open all of that session.
+//! Don't reveal it unless the whole session can be disclosed.
+
+use crate::{
+    aead::{Aead, AeadCtxR},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    dleq::DleqProof,
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::OpModeR,
+    threshold::{setup_receiver_with_dh, DhShare},
+    HpkeError,
+};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The DH results of a decapsulation along with proofs that they were computed with the
+/// recipient's private key. In `Auth` and `AuthPsk` modes, this includes the DH with the sender's
+/// identity key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecapProof {
+    /// `sk_recip⋅enc` and a proof of its correctness
+    pub dh_eph: (DhShare, DleqProof),
+    /// `sk_recip⋅pk_sender_id` and a proof of its correctness. This is `Some` iff the mode
+    /// authenticates the sender.
+    pub dh_identity: Option<(DhShare, DleqProof)>,
+}
+
+/// Proves what `encapped_key` decapsulates to under `sk_recip`. `aux_rand` SHOULD be fresh
+/// randomness.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(proof)` on success. Proof generation only fails with negligible probability, in
+/// which case it returns `Err(HpkeError::ValidationError)`.
+pub fn prove_decap(
+    mode: &OpModeR<Kem>,
+    sk_recip: &PrivateKey,
+    encapped_key: &EncappedKey,
+    aux_rand: &[u8; 32],
+) -> Result<DecapProof, HpkeError> {
+    let dh_eph = DhShare::new_with_proof(sk_recip, &encapped_key.0, aux_rand)?;
+    let dh_identity = match mode.get_pk_sender_id() {
+        Some(pk_sender_id) => Some(DhShare::new_with_proof(sk_recip, pk_sender_id, aux_rand)?),
+        None => None,
+    };
+
+    Ok(DecapProof {
+        dh_eph,
+        dh_identity,
+    })
+}
+
+/// Checks a decapsulation proof and, if it's valid, derives the same decryption context the
+/// recipient did. The verifier can then open the session's ciphertexts.
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If a proof is invalid, or `proof.dh_identity` doesn't
+/// match whether `mode` authenticates the sender, returns `Err(HpkeError::ValidationError)`.
+pub fn verify_decap<A, Kdf>(
+    mode: &OpModeR<Kem>,
+    pk_recip: &PublicKey,
+    encapped_key: &EncappedKey,
+    proof: &DecapProof,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    let (dh_eph, proof_eph) = &proof.dh_eph;
+    dh_eph.verify(pk_recip, &encapped_key.0, proof_eph)?;
+
+    let dh_identity = match (mode.get_pk_sender_id(), &proof.dh_identity) {
+        (Some(pk_sender_id), Some((dh_identity, proof_identity))) => {
+            dh_identity.verify(pk_recip, pk_sender_id, proof_identity)?;
+            Some(dh_identity)
+        }
+        (None, None) => None,
+        _ => return Err(HpkeError::ValidationError),
+    };
+
+    setup_receiver_with_dh(mode, pk_recip, encapped_key, dh_eph, dh_identity, info)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        setup::setup_sender,
+        test_util::{gen_rand_buf, new_op_mode_pair, OpModeKind},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that a verifier can open the recipient's messages using only a decapsulation proof,
+    /// in every mode, and that forged DH results are rejected
+    #[test]
+    fn test_decap_proof() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"exhibit A";
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let (sk_other, _) = Kem::gen_keypair(&mut csprng);
+
+        for op_mode_kind in &[
+            OpModeKind::Base,
+            OpModeKind::Auth,
+            OpModeKind::Psk,
+            OpModeKind::AuthPsk,
+        ] {
+            let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+            let (sender_mode, receiver_mode) =
+                new_op_mode_pair::<Kem>(*op_mode_kind, &psk, &psk_id);
+
+            let (encapped_key, mut sender_ctx) =
+                setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, info, &mut csprng).unwrap();
+            let ciphertext = sender_ctx.seal(b"the disputed message", b"").unwrap();
+
+            // The recipient proves the decapsulation, and the verifier reads the message
+            let proof =
+                prove_decap(&receiver_mode, &sk_recip, &encapped_key, &gen_rand_buf()).unwrap();
+            let mut verifier_ctx =
+                verify_decap::<A, Kdf>(&receiver_mode, &pk_recip, &encapped_key, &proof, info)
+                    .unwrap();
+            assert_eq!(
+                verifier_ctx.open(&ciphertext, b"").unwrap(),
+                b"the disputed message"
+            );
+
+            // A DH result computed with a different key doesn't verify
+            let forged =
+                prove_decap(&receiver_mode, &sk_other, &encapped_key, &gen_rand_buf()).unwrap();
+            let res =
+                verify_decap::<A, Kdf>(&receiver_mode, &pk_recip, &encapped_key, &forged, info);
+            assert!(matches!(res, Err(HpkeError::ValidationError)));
+
+            // Dropping the identity DH in an authed mode, or adding one in an unauthed mode, fails
+            let mut mismatched = proof.clone();
+            mismatched.dh_identity = match proof.dh_identity {
+                Some(_) => None,
+                None => Some(proof.dh_eph.clone()),
+            };
+            let res =
+                verify_decap::<A, Kdf>(&receiver_mode, &pk_recip, &encapped_key, &mismatched, info);
+            assert!(matches!(res, Err(HpkeError::ValidationError)));
+        }
+    }
+}
@@ -12,11 +12,11 @@ use subtle::{Choice, ConstantTimeEq};
 
 /// A secp256k1 public key
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct PublicKey(secp256k1::PublicKey);
+pub struct PublicKey(pub(crate) secp256k1::PublicKey);
 
 /// A secp256k1 private key
 #[derive(Clone)]
-pub struct PrivateKey(secp256k1::SecretKey);
+pub struct PrivateKey(pub(crate) secp256k1::SecretKey);
 
 impl ConstantTimeEq for PrivateKey {
     fn ct_eq(&self, other: &Self) -> Choice {
@@ -32,7 +32,7 @@ impl PartialEq for PrivateKey {
 impl Eq for PrivateKey {}
 
 /// A bare DH computation result
-pub struct KexResult([u8; 64]);
+pub struct KexResult(pub(crate) [u8; 64]);
 
 impl Serializable for PublicKey {
     // IANA HPKE KEM Identifiers: Npk of DHKEM(Secp256k1, HKDF-SHA256) is 65
@@ -111,6 +111,26 @@ impl Serializable for KexResult {
     }
 }
 
+/// Interprets `bytes` as a big-endian integer and reduces it modulo the group order. Since the
+/// group order is more than half of 2^256, a single subtraction is always enough.
+pub(crate) fn reduce_scalar(bytes: [u8; 32]) -> secp256k1::Scalar {
+    use secp256k1::{constants::CURVE_ORDER, Scalar};
+
+    if let Ok(s) = Scalar::from_be_bytes(bytes) {
+        return s;
+    }
+
+    // bytes >= n, so compute bytes - n with a schoolbook subtraction
+    let mut out = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let diff = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+        borrow = (diff < 0) as i16;
+        out[i] = diff.rem_euclid(256) as u8;
+    }
+    Scalar::from_be_bytes(out).expect("bytes - n is always less than n")
+}
+
 /// Represents ECDH functionality over the Secp256k1 group
 pub struct Secp256k1 {}
 
@@ -229,8 +249,8 @@ mod tests {
         dh_res_xcoord_bytes: &[u8],
     ) {
         // Deserialize the pubkey and privkey and do a DH operation
-        let sk_recip = Kex::PrivateKey::from_bytes(&sk_recip_bytes).unwrap();
-        let pk_sender = Kex::PublicKey::from_bytes(&pk_sender_bytes).unwrap();
+        let sk_recip = Kex::PrivateKey::from_bytes(sk_recip_bytes).unwrap();
+        let pk_sender = Kex::PublicKey::from_bytes(pk_sender_bytes).unwrap();
         let derived_dh = Kex::dh(&sk_recip, &pk_sender).unwrap();
 
         // Assert that the derived DH result matches the test vector. Recall that the HPKE DH
@@ -296,7 +316,7 @@ mod tests {
     #[cfg(feature = "secp")]
     #[test]
     fn test_vector_ecdh_k256() {
-        test_vector_ecdh::<Secp256k1>(&K256_PRIVKEYS[0], &K256_PUBKEYS[1], &K256_DH_RES_XCOORD);
+        test_vector_ecdh::<Secp256k1>(K256_PRIVKEYS[0], K256_PUBKEYS[1], K256_DH_RES_XCOORD);
     }
 
     #[cfg(feature = "secp")]
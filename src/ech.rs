@@ -0,0 +1,551 @@
+//! TLS Encrypted Client Hello (draft-ietf-tls-esni) configurations and HPKE setup
+//!
+//! A server publishes an `ECHConfigList`, e.g., in a DNS HTTPS record. The client picks an
+//! [`EchConfig`] whose KEM and symmetric suite it supports, and encrypts its inner ClientHello to
+//! the config's public key with [`setup_client`]. The server then decrypts with [`setup_server`]
+//! using the same config. Both bind the HPKE context to the full serialized config.
+//!
+//! Only configs for secp256k1 with version `0xfe0d` are parsed. Others are skipped when decoding
+//! a list, as the draft requires.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    ohttp::SymmetricSuite,
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    util::{split_checked, split_u16_prefixed, split_versioned, write_u16_prefixed},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// A KDF and AEAD pair that a client-facing server accepts. This is the same encoding as an OHTTP
+/// symmetric suite.
+pub type HpkeSymmetricCipherSuite = SymmetricSuite;
+
+/// The `ECHConfig` version this module understands
+pub const ECH_VERSION: u16 = 0xfe0d;
+
+/// The label that starts the HPKE info string
+const INFO_LABEL: &[u8] = b"tls ech";
+
+/// An extension in an `ECHConfig`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchConfigExtension {
+    /// The extension type. If the high bit is set, clients that don't understand it must not use
+    /// the config.
+    pub ext_type: u16,
+    /// The extension's contents
+    pub data: Vec<u8>,
+}
+
+impl EchConfigExtension {
+    /// Returns whether a client must understand this extension to use the config
+    pub fn is_mandatory(&self) -> bool {
+        self.ext_type & 0x8000 != 0
+    }
+}
+
+/// A client-facing server's ECH configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchConfig {
+    /// Identifies the key to the server
+    pub config_id: u8,
+    /// The server's public key
+    pub public_key: PublicKey,
+    /// The KDF and AEAD pairs the server accepts, in order of preference. This MUST NOT be empty.
+    pub cipher_suites: Vec<HpkeSymmetricCipherSuite>,
+    /// The longest server name the client should expect to pad to, or 0 for no hint
+    pub maximum_name_length: u8,
+    /// The name the client puts in the outer ClientHello. This MUST be 1 to 255 bytes.
+    pub public_name: Vec<u8>,
+    /// Extensions to the config
+    pub extensions: Vec<EchConfigExtension>,
+}
+
+// draft-ietf-tls-esni §4
+// struct {
+//     HpkeKdfId kdf_id;
+//     HpkeAeadId aead_id;
+// } HpkeSymmetricCipherSuite;
+//
+// struct {
+//     uint8 config_id;
+//     HpkeKemId kem_id;
+//     HpkePublicKey public_key;
+//     HpkeSymmetricCipherSuite cipher_suites<4..2^16-4>;
+// } HpkeKeyConfig;
+//
+// struct {
+//     HpkeKeyConfig key_config;
+//     uint8 maximum_name_length;
+//     opaque public_name<1..255>;
+//     ECHConfigExtension extensions<0..2^16-1>;
+// } ECHConfigContents;
+//
+// struct {
+//     uint16 version;
+//     uint16 length;
+//     select (ECHConfig.version) {
+//       case 0xfe0d: ECHConfigContents contents;
+//     }
+// } ECHConfig;
+//
+// ECHConfig ECHConfigList<4..2^16-1>;
+
+impl EchConfig {
+    /// Returns whether the server accepts the suite `(Kdf, A)`
+    pub fn supports<A: Aead, Kdf: KdfTrait>(&self) -> bool {
+        self.cipher_suites
+            .contains(&HpkeSymmetricCipherSuite::new::<A, Kdf>())
+    }
+
+    /// Returns whether this config has a mandatory extension. This module understands no
+    /// extensions, so such a config must not be used.
+    pub fn has_mandatory_extension(&self) -> bool {
+        self.extensions.iter().any(|ext| ext.is_mandatory())
+    }
+
+    /// Returns whether `to_bytes` can serialize this config without panicking
+    fn is_encodable(&self) -> bool {
+        let suites_len = self.cipher_suites.len() * 4;
+        let extensions_len = self.extensions.iter().try_fold(0usize, |len, ext| {
+            (ext.data.len() <= u16::MAX as usize).then(|| len + 4 + ext.data.len())
+        });
+        let extensions_len = match extensions_len {
+            Some(len) if len <= u16::MAX as usize => len,
+            _ => return false,
+        };
+        // config_id || kem_id || public_key || cipher_suites || maximum_name_length ||
+        // public_name || extensions
+        let contents_len = 1
+            + 2
+            + 2
+            + PublicKey::size()
+            + 2
+            + suites_len
+            + 1
+            + 1
+            + self.public_name.len()
+            + 2
+            + extensions_len;
+        (4..=65532).contains(&suites_len)
+            && (1..=255).contains(&self.public_name.len())
+            && contents_len <= u16::MAX as usize
+    }
+
+    /// Picks the first of the server's suites, in its order of preference, that is also in
+    /// `supported`. This lets a client with several suites compiled in choose one at runtime.
+    pub fn select_suite(
+        &self,
+        supported: &[HpkeSymmetricCipherSuite],
+    ) -> Option<HpkeSymmetricCipherSuite> {
+        self.cipher_suites
+            .iter()
+            .find(|suite| supported.contains(suite))
+            .copied()
+    }
+
+    /// Serializes this config, including its version and length
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `cipher_suites` is empty or has more than 16383 entries, `public_name` isn't 1 to
+    /// 255 bytes, or the config is longer than 65535 bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let suites_len = self.cipher_suites.len() * 4;
+        assert!(
+            (4..=65532).contains(&suites_len),
+            "ECH config needs 1 to 16383 cipher suites"
+        );
+        assert!(
+            (1..=255).contains(&self.public_name.len()),
+            "ECH public name must be 1 to 255 bytes"
+        );
+
+        let mut contents = Vec::new();
+        contents.push(self.config_id);
+        contents.extend_from_slice(&Kem::KEM_ID.to_be_bytes());
+        write_u16_prefixed(&mut contents, &self.public_key.to_bytes());
+        contents.extend_from_slice(&(suites_len as u16).to_be_bytes());
+        for suite in &self.cipher_suites {
+            contents.extend_from_slice(&suite.kdf_id.to_be_bytes());
+            contents.extend_from_slice(&suite.aead_id.to_be_bytes());
+        }
+        contents.push(self.maximum_name_length);
+        contents.push(self.public_name.len() as u8);
+        contents.extend_from_slice(&self.public_name);
+        let mut extensions = Vec::new();
+        for ext in &self.extensions {
+            extensions.extend_from_slice(&ext.ext_type.to_be_bytes());
+            write_u16_prefixed(&mut extensions, &ext.data);
+        }
+        assert!(
+            extensions.len() <= u16::MAX as usize,
+            "ECH config extensions are too long"
+        );
+        write_u16_prefixed(&mut contents, &extensions);
+        assert!(
+            contents.len() <= u16::MAX as usize,
+            "ECH config is too long"
+        );
+
+        let mut out = Vec::with_capacity(4 + contents.len());
+        out.extend_from_slice(&ECH_VERSION.to_be_bytes());
+        write_u16_prefixed(&mut out, &contents);
+        out
+    }
+
+    /// Deserializes a single config, including its version and length
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(config)` on success. If the config is malformed, has trailing bytes, has a
+    /// version other than `0xfe0d`, or is for a KEM other than secp256k1, returns an error.
+    pub fn from_bytes(encoded: &[u8]) -> Result<EchConfig, HpkeError> {
+        let (version, contents, _, rest) = split_versioned(encoded)?;
+        if version != ECH_VERSION || !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (config_id, rest) = split_checked(contents, 1)?;
+        let (kem_id, rest) = split_checked(rest, 2)?;
+        if u16::from_be_bytes([kem_id[0], kem_id[1]]) != Kem::KEM_ID {
+            return Err(HpkeError::ValidationError);
+        }
+        let (public_key, rest) = split_u16_prefixed(rest)?;
+        let (suites, rest) = split_u16_prefixed(rest)?;
+        let suite_chunks = suites.chunks_exact(4);
+        if suites.is_empty() || !suite_chunks.remainder().is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+        let cipher_suites = suite_chunks
+            .map(|c| HpkeSymmetricCipherSuite {
+                kdf_id: u16::from_be_bytes([c[0], c[1]]),
+                aead_id: u16::from_be_bytes([c[2], c[3]]),
+            })
+            .collect();
+        let (maximum_name_length, rest) = split_checked(rest, 1)?;
+        let (name_len, rest) = split_checked(rest, 1)?;
+        let (public_name, rest) = split_checked(rest, name_len[0] as usize)?;
+        if public_name.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+        let (mut ext_bytes, rest) = split_u16_prefixed(rest)?;
+        if !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let mut extensions = Vec::new();
+        while !ext_bytes.is_empty() {
+            let (ext_type, r) = split_checked(ext_bytes, 2)?;
+            let (data, r) = split_u16_prefixed(r)?;
+            ext_bytes = r;
+            extensions.push(EchConfigExtension {
+                ext_type: u16::from_be_bytes([ext_type[0], ext_type[1]]),
+                data: data.to_vec(),
+            });
+        }
+
+        Ok(EchConfig {
+            config_id: config_id[0],
+            public_key: PublicKey::from_bytes(public_key)?,
+            cipher_suites,
+            maximum_name_length: maximum_name_length[0],
+            public_name: public_name.to_vec(),
+            extensions,
+        })
+    }
+
+    /// Serializes a list of configs as an `ECHConfigList`
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `configs` is empty, any config can't be serialized, or the list is longer than
+    /// 65535 bytes
+    pub fn encode_list(configs: &[EchConfig]) -> Vec<u8> {
+        assert!(!configs.is_empty(), "ECHConfigList can't be empty");
+
+        let mut list = Vec::new();
+        for config in configs {
+            list.extend_from_slice(&config.to_bytes());
+        }
+        assert!(list.len() <= u16::MAX as usize, "ECHConfigList is too long");
+
+        let mut out = Vec::with_capacity(2 + list.len());
+        write_u16_prefixed(&mut out, &list);
+        out
+    }
+
+    /// Deserializes an `ECHConfigList`. Configs with other versions or other KEMs are skipped,
+    /// since servers may offer several.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(configs)` on success, which may be empty if none are usable. If the list is
+    /// malformed, returns an error.
+    pub fn decode_list(encoded: &[u8]) -> Result<Vec<EchConfig>, HpkeError> {
+        let (mut rest, trailing) = split_u16_prefixed(encoded)?;
+        if rest.is_empty() || !trailing.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let mut configs = Vec::new();
+        while !rest.is_empty() {
+            let (version, contents, config, r) = split_versioned(rest)?;
+            rest = r;
+
+            // Clients MUST ignore ECHConfigs with versions they don't support. The KEM ID follows
+            // the 1-byte config_id, and configs for other KEMs are ignored too. If the contents
+            // are too short to have a KEM ID, parsing below rejects them.
+            let other_kem = contents.len() >= 3
+                && u16::from_be_bytes([contents[1], contents[2]]) != Kem::KEM_ID;
+            if version != ECH_VERSION || other_kem {
+                continue;
+            }
+            configs.push(EchConfig::from_bytes(config)?);
+        }
+        Ok(configs)
+    }
+
+    /// Picks the first config in `configs` that accepts the suite `(Kdf, A)` and has no mandatory
+    /// extensions
+    pub fn select<A: Aead, Kdf: KdfTrait>(configs: &[EchConfig]) -> Option<&EchConfig> {
+        configs
+            .iter()
+            .find(|config| config.supports::<A, Kdf>() && !config.has_mandatory_extension())
+    }
+
+    // draft-ietf-tls-esni §6.1
+    // pkR = DeserializePublicKey(ECHConfig.contents.public_key)
+    // enc, context = SetupBaseS(pkR,
+    //                           "tls ech" || 0x00 || ECHConfig)
+
+    /// Returns the HPKE info string, `"tls ech" || 0x00 || ECHConfig`
+    ///
+    /// Panics
+    /// ======
+    /// Panics if the config can't be serialized. See `to_bytes`.
+    pub fn info(&self) -> Vec<u8> {
+        let mut info = INFO_LABEL.to_vec();
+        info.push(0);
+        info.extend_from_slice(&self.to_bytes());
+        info
+    }
+}
+
+/// Sets up the client's encryption context for the inner ClientHello, with the suite
+/// `(Kdf, A)`. The client sends the returned encapsulated key in its `encrypted_client_hello`
+/// extension.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, ctx))` on success. If the config doesn't accept the suite, has a
+/// mandatory extension, or can't be serialized (see `EchConfig::to_bytes`), returns
+/// `Err(HpkeError::ValidationError)`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`.
+pub fn setup_client<A, Kdf, R>(
+    config: &EchConfig,
+    csprng: &mut R,
+) -> Result<(EncappedKey, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    if !config.supports::<A, Kdf>() || config.has_mandatory_extension() || !config.is_encodable() {
+        return Err(HpkeError::ValidationError);
+    }
+    setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, &config.public_key, &config.info(), csprng)
+}
+
+/// Sets up the server's decryption context for an inner ClientHello that was encrypted to
+/// `config` with the suite `(Kdf, A)`. `sk` is the private key of `config`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(ctx)` on success. If the config doesn't accept the suite or can't be serialized
+/// (see `EchConfig::to_bytes`), returns `Err(HpkeError::ValidationError)`. If an error happened
+/// during key decapsulation, returns `Err(HpkeError::DecapError)`.
+pub fn setup_server<A, Kdf>(
+    config: &EchConfig,
+    sk: &PrivateKey,
+    encapped_key: &EncappedKey,
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    if !config.supports::<A, Kdf>() || !config.is_encodable() {
+        return Err(HpkeError::ValidationError);
+    }
+    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, sk, encapped_key, &config.info())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::{HkdfSha256, HkdfSha384},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests `ECHConfigList` round trips, that unknown configs are skipped, suite selection, and
+    /// that a client and server using the same config agree
+    #[test]
+    fn test_ech_config() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+
+        let config = EchConfig {
+            config_id: 7,
+            public_key: pk,
+            cipher_suites: vec![
+                HpkeSymmetricCipherSuite::new::<A, HkdfSha384>(),
+                HpkeSymmetricCipherSuite::new::<A, Kdf>(),
+            ],
+            maximum_name_length: 64,
+            public_name: b"public.example".to_vec(),
+            extensions: vec![EchConfigExtension {
+                ext_type: 0x1234,
+                data: b"ext".to_vec(),
+            }],
+        };
+
+        // version || length || config_id || kem_id || public key length
+        let bytes = config.to_bytes();
+        assert_eq!(bytes[..2], [0xfe, 0x0d]);
+        assert_eq!(
+            u16::from_be_bytes([bytes[2], bytes[3]]) as usize,
+            bytes.len() - 4
+        );
+        assert_eq!(bytes[4..9], [7, 0x00, 0x16, 0x00, 65]);
+        assert_eq!(EchConfig::from_bytes(&bytes).unwrap(), config);
+        assert_eq!(config.info()[..8], *b"tls ech\x00");
+
+        // A list with a config of an unknown version in front of ours
+        let mut list = EchConfig::encode_list(core::slice::from_ref(&config));
+        let unknown = [0xfe, 0x0e, 0x00, 0x02, 0xaa, 0xbb];
+        list.splice(2..2, unknown.iter().copied());
+        let list_len = (list.len() - 2) as u16;
+        list[..2].copy_from_slice(&list_len.to_be_bytes());
+        let configs = EchConfig::decode_list(&list).unwrap();
+        assert_eq!(configs, core::slice::from_ref(&config));
+
+        // Truncated and empty lists are rejected
+        assert!(EchConfig::decode_list(&list[..list.len() - 1]).is_err());
+        assert!(EchConfig::decode_list(&[0, 0]).is_err());
+
+        // Suite selection follows the server's preference
+        assert_eq!(
+            config.select_suite(&[
+                HpkeSymmetricCipherSuite::new::<A, Kdf>(),
+                HpkeSymmetricCipherSuite::new::<A, HkdfSha384>(),
+            ]),
+            Some(HpkeSymmetricCipherSuite::new::<A, HkdfSha384>())
+        );
+        assert_eq!(
+            config.select_suite(&[HpkeSymmetricCipherSuite {
+                kdf_id: 1,
+                aead_id: 1
+            }]),
+            None
+        );
+        assert_eq!(EchConfig::select::<A, Kdf>(&configs), Some(&config));
+
+        // A client and server with the same config share a context
+        let (encapped_key, mut client_ctx) =
+            setup_client::<A, Kdf, _>(&config, &mut csprng).unwrap();
+        let mut server_ctx = setup_server::<A, Kdf>(&config, &sk, &encapped_key).unwrap();
+        let inner_hello = b"inner ClientHello";
+        let aad = b"outer ClientHelloAAD";
+        let ciphertext = client_ctx.seal(inner_hello, aad).unwrap();
+        assert_eq!(server_ctx.open(&ciphertext, aad).unwrap(), inner_hello);
+
+        // The context is bound to the whole config
+        let mut other = config.clone();
+        other.public_name = b"other.example".to_vec();
+        let mut other_ctx = setup_server::<A, Kdf>(&other, &sk, &encapped_key).unwrap();
+        assert_eq!(other_ctx.open(&ciphertext, aad), Err(HpkeError::OpenError));
+
+        // Configs with a mandatory extension this module doesn't understand aren't used
+        let mut mandatory = config.clone();
+        mandatory.extensions[0].ext_type |= 0x8000;
+        assert_eq!(EchConfig::select::<A, Kdf>(&[mandatory.clone()]), None);
+        assert!(setup_client::<A, Kdf, _>(&mandatory, &mut csprng).is_err());
+    }
+
+    /// Tests that the setup functions reject configs that `to_bytes` can't serialize instead of
+    /// panicking
+    #[test]
+    fn test_ech_unencodable_config() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let config = EchConfig {
+            config_id: 1,
+            public_key: pk,
+            cipher_suites: vec![HpkeSymmetricCipherSuite::new::<A, Kdf>()],
+            maximum_name_length: 0,
+            public_name: b"public.example".to_vec(),
+            extensions: Vec::new(),
+        };
+        let (encapped_key, _) = setup_client::<A, Kdf, _>(&config, &mut csprng).unwrap();
+
+        let mut no_name = config.clone();
+        no_name.public_name.clear();
+        let mut long_name = config.clone();
+        long_name.public_name = vec![b'a'; 256];
+        // Each extension fits, but together they're too long
+        let mut long_extensions = config.clone();
+        long_extensions.extensions = vec![
+            EchConfigExtension {
+                ext_type: 1,
+                data: vec![0; 40000],
+            },
+            EchConfigExtension {
+                ext_type: 2,
+                data: vec![0; 40000],
+            },
+        ];
+        let mut long_extension = config.clone();
+        long_extension.extensions = vec![EchConfigExtension {
+            ext_type: 1,
+            data: vec![0; 65536],
+        }];
+
+        for bad in [no_name, long_name, long_extensions, long_extension] {
+            assert_eq!(
+                setup_client::<A, Kdf, _>(&bad, &mut csprng).err(),
+                Some(HpkeError::ValidationError)
+            );
+            assert_eq!(
+                setup_server::<A, Kdf>(&bad, &sk, &encapped_key).err(),
+                Some(HpkeError::ValidationError)
+            );
+        }
+
+        // The largest config that fits is still accepted
+        let mut largest = config;
+        let room = u16::MAX as usize - (largest.to_bytes().len() - 4) - 4;
+        largest.extensions = vec![EchConfigExtension {
+            ext_type: 1,
+            data: vec![0; room],
+        }];
+        assert_eq!(largest.to_bytes().len(), 4 + u16::MAX as usize);
+        assert!(setup_client::<A, Kdf, _>(&largest, &mut csprng).is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+//! Publicly verifiable signed envelopes
+//!
+//! A [`SignedEnvelope`] is a single-shot HPKE ciphertext, its encapsulated key, and its
+//! ciphersuite, all signed by the sender with a BIP-340 key. Unlike sender authentication inside
+//! the key schedule, the signature is on the outside (encrypt-then-sign), so anyone can verify it
+//! without being able to decrypt. A relay can use this to drop unsigned or forged envelopes before
+//! they ever reach the recipient.
+//!
+//! The signature says who produced the envelope. It says nothing about whether the sender knows
+//! the plaintext, since anyone can re-sign a ciphertext they've seen.
+
+use crate::{
+    aead::Aead,
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    schnorr::{Signature, SigningKey, VerifyingKey},
+    single_shot::{single_shot_open, single_shot_seal},
+    util::{split_checked, tagged_hash, write_u16_be},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// A single-shot ciphertext signed by its sender. This is what `seal_signed` produces and
+/// `open_signed` consumes.
+#[derive(Clone)]
+pub struct SignedEnvelope {
+    /// The KEM the ciphertext was sealed with
+    pub kem_id: u16,
+    /// The KDF the ciphertext was sealed with
+    pub kdf_id: u16,
+    /// The AEAD the ciphertext was sealed with
+    pub aead_id: u16,
+    /// The encapsulated key the recipient uses to derive their decryption context
+    pub encapped_key: EncappedKey,
+    /// The sender's signing key
+    pub verifying_key: VerifyingKey,
+    /// The sender's signature over everything else in the envelope
+    pub signature: Signature,
+    /// The ciphertext, followed by its tag
+    pub ciphertext: Vec<u8>,
+}
+
+// The wire format is
+//   struct {
+//     uint16 kem_id;
+//     uint16 kdf_id;
+//     uint16 aead_id;
+//     opaque enc[Nenc];
+//     opaque verifying_key[32];
+//     opaque signature[64];
+//     opaque ciphertext[remaining bytes];
+//   }
+//
+// The signature is over
+//   hash_HPKE/Envelope(kem_id || kdf_id || aead_id || enc || verifying_key || ciphertext)
+// Everything but the ciphertext is fixed-length, so this encoding is unambiguous.
+
+/// Encodes the ciphersuite as `kem_id || kdf_id || aead_id`
+fn suite_id_bytes(kem_id: u16, kdf_id: u16, aead_id: u16) -> [u8; 6] {
+    let mut buf = [0u8; 6];
+    write_u16_be(&mut buf[0..2], kem_id);
+    write_u16_be(&mut buf[2..4], kdf_id);
+    write_u16_be(&mut buf[4..6], aead_id);
+    buf
+}
+
+/// Computes the message that the sender signs
+fn signed_msg(
+    suite_id: &[u8; 6],
+    encapped_key: &EncappedKey,
+    verifying_key: &VerifyingKey,
+    ciphertext: &[u8],
+) -> [u8; 32] {
+    tagged_hash(
+        b"HPKE/Envelope",
+        &[
+            suite_id,
+            &encapped_key.to_bytes(),
+            &verifying_key.to_bytes(),
+            ciphertext,
+        ],
+    )
+}
+
+impl SignedEnvelope {
+    fn suite_id_bytes(&self) -> [u8; 6] {
+        suite_id_bytes(self.kem_id, self.kdf_id, self.aead_id)
+    }
+
+    /// Verifies the sender's signature. This needs no secrets, so relays can call it to filter
+    /// envelopes. To check that the envelope came from a particular sender, compare
+    /// `self.verifying_key` too.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if the signature is valid. Otherwise returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify(&self) -> Result<(), HpkeError> {
+        let msg = signed_msg(
+            &self.suite_id_bytes(),
+            &self.encapped_key,
+            &self.verifying_key,
+            &self.ciphertext,
+        );
+        self.verifying_key.verify(&msg, &self.signature)
+    }
+
+    /// Serializes this envelope to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.suite_id_bytes());
+        out.extend_from_slice(&self.encapped_key.to_bytes());
+        out.extend_from_slice(&self.verifying_key.to_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    /// Deserializes an envelope that was serialized with `to_bytes`. This does not verify the
+    /// signature.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(envelope)` on success. If `encoded` is truncated or a key or signature is
+    /// malformed, returns an error.
+    pub fn from_bytes(encoded: &[u8]) -> Result<SignedEnvelope, HpkeError> {
+        let (ids, rest) = split_checked(encoded, 6)?;
+        let (enc, rest) = split_checked(rest, EncappedKey::size())?;
+        let (verifying_key, rest) = split_checked(rest, VerifyingKey::size())?;
+        let (signature, ciphertext) = split_checked(rest, Signature::size())?;
+
+        Ok(SignedEnvelope {
+            kem_id: u16::from_be_bytes([ids[0], ids[1]]),
+            kdf_id: u16::from_be_bytes([ids[2], ids[3]]),
+            aead_id: u16::from_be_bytes([ids[4], ids[5]]),
+            encapped_key: EncappedKey::from_bytes(enc)?,
+            verifying_key: VerifyingKey::from_bytes(verifying_key)?,
+            signature: Signature::from_bytes(signature)?,
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+/// Does a `single_shot_seal` to `pk_recip` and signs the result with `signing_key`
+///
+/// Return Value
+/// ============
+/// Returns `Ok(envelope)` on success. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`. If an error happened during encryption, returns
+/// `Err(HpkeError::SealError)`.
+pub fn seal_signed<A, Kdf, R>(
+    mode: &OpModeS<Kem>,
+    signing_key: &SigningKey,
+    pk_recip: &PublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<SignedEnvelope, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    let (encapped_key, ciphertext) =
+        single_shot_seal::<A, Kdf, Kem, R>(mode, pk_recip, info, plaintext, aad, csprng)?;
+
+    let verifying_key = signing_key.verifying_key();
+    let suite_id = suite_id_bytes(Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID);
+    let msg = signed_msg(&suite_id, &encapped_key, &verifying_key, &ciphertext);
+    let mut aux_rand = [0u8; 32];
+    csprng.fill_bytes(&mut aux_rand);
+    let signature = signing_key.sign(&msg, &aux_rand);
+
+    Ok(SignedEnvelope {
+        kem_id: Kem::KEM_ID,
+        kdf_id: Kdf::KDF_ID,
+        aead_id: A::AEAD_ID,
+        encapped_key,
+        verifying_key,
+        signature,
+        ciphertext,
+    })
+}
+
+/// Verifies the envelope's signature and ciphersuite, then does a `single_shot_open`. The caller
+/// is responsible for checking that `envelope.verifying_key` is who they expect.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If the signature is invalid or the envelope's ciphersuite
+/// isn't `(Kem, Kdf, A)`, returns `Err(HpkeError::ValidationError)`. If an error happened during
+/// key decapsulation, returns `Err(HpkeError::DecapError)`. If an error happened during
+/// decryption, returns `Err(HpkeError::OpenError)`.
+pub fn open_signed<A, Kdf>(
+    mode: &OpModeR<Kem>,
+    sk_recip: &PrivateKey,
+    envelope: &SignedEnvelope,
+    info: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    envelope.verify()?;
+    if (envelope.kem_id, envelope.kdf_id, envelope.aead_id)
+        != (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID)
+    {
+        return Err(HpkeError::ValidationError);
+    }
+
+    single_shot_open::<A, Kdf, Kem>(
+        mode,
+        sk_recip,
+        &envelope.encapped_key,
+        info,
+        &envelope.ciphertext,
+        aad,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::{HkdfSha256, HkdfSha384},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that envelopes round-trip, that relays can verify them, and that tampering with any
+    /// field is caught
+    #[test]
+    fn test_signed_envelope() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"sealed with a kiss";
+        let aad = b"and a signature";
+        let msg = b"meet me at the directory";
+
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let signing_key = SigningKey::gen(&mut csprng);
+
+        let envelope = seal_signed::<A, Kdf, _>(
+            &OpModeS::Base,
+            &signing_key,
+            &pk_recip,
+            info,
+            msg,
+            aad,
+            &mut csprng,
+        )
+        .unwrap();
+        let envelope = SignedEnvelope::from_bytes(&envelope.to_bytes()).unwrap();
+        envelope.verify().unwrap();
+        assert_eq!(envelope.verifying_key, signing_key.verifying_key());
+
+        let plaintext =
+            open_signed::<A, Kdf>(&OpModeR::Base, &sk_recip, &envelope, info, aad).unwrap();
+        assert_eq!(plaintext, msg);
+
+        // A tampered ciphertext or suite ID fails verification
+        let mut bad = envelope.clone();
+        bad.ciphertext[0] ^= 1;
+        assert_eq!(bad.verify(), Err(HpkeError::ValidationError));
+        let mut bad = envelope.clone();
+        bad.kdf_id = HkdfSha384::KDF_ID;
+        assert_eq!(bad.verify(), Err(HpkeError::ValidationError));
+
+        // Re-signing with another key verifies, but names a different sender
+        let mut resigned = envelope.clone();
+        let other_key = SigningKey::gen(&mut csprng);
+        resigned.verifying_key = other_key.verifying_key();
+        let msg = signed_msg(
+            &resigned.suite_id_bytes(),
+            &resigned.encapped_key,
+            &resigned.verifying_key,
+            &resigned.ciphertext,
+        );
+        resigned.signature = other_key.sign(&msg, &[0u8; 32]);
+        resigned.verify().unwrap();
+        assert_ne!(resigned.verifying_key, envelope.verifying_key);
+
+        // A valid envelope opened with the wrong ciphersuite is rejected
+        let res = open_signed::<A, HkdfSha384>(&OpModeR::Base, &sk_recip, &envelope, info, aad);
+        assert_eq!(res, Err(HpkeError::ValidationError));
+
+        // Truncation is caught
+        let bytes = envelope.to_bytes();
+        assert!(SignedEnvelope::from_bytes(&bytes[..100]).is_err());
+    }
+}
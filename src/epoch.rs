@@ -0,0 +1,394 @@
+//! Forward-secure recipient keys that evolve per epoch
+//!
+//! An [`EpochKeyChain`] gives the recipient a different keypair for every epoch (e.g., every day).
+//! Each epoch's seed is derived one-way from the previous one, and the keypair is derived from the
+//! seed. Once the recipient advances past an epoch, its seed is erased and can't be recomputed
+//! from any later one, so ciphertexts sent to that epoch can no longer be decrypted by anyone.
+//!
+//! Senders learn epoch public keys from [`SignedEpochKey`]s, which the recipient publishes ahead of
+//! time under a BIP-340 identity key. The epoch number is bound into the `info` string of every
+//! session, so a ciphertext for one epoch can't be passed off as belonging to another.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::{labeled_extract, HkdfSha256, Kdf as KdfTrait, LabeledExpand},
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    schnorr::{Signature, SigningKey, VerifyingKey},
+    setup::{setup_receiver, setup_sender},
+    util::{enforce_equal_len, enforce_outbuf_len, kem_suite_id, tagged_hash, write_u64_be},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use generic_array::typenum;
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The furthest past its current epoch that an [`EpochKeyChain`] will derive or advance to.
+/// Epoch numbers can come from untrusted messages, and reaching epoch `e` takes `e` steps of the
+/// chain, so this bounds the work one message can cause.
+pub const MAX_EPOCH_LOOKAHEAD: u64 = 1 << 16;
+
+// prk_e = LabeledExtract("", "epoch_seed", seed_e)
+// ikm_e = LabeledExpand(prk_e, "epoch_ikm", I2OSP(e, 8), Nsk)
+// seed_{e+1} = LabeledExpand(prk_e, "next_seed", I2OSP(e + 1, 8), 32)
+// (sk_e, pk_e) = DeriveKeyPair(ikm_e)
+// All labels use the KEM's suite ID and KDF.
+
+/// Runs one step of the seed chain. Returns the IKM for epoch `epoch` and the seed for the next
+/// epoch, or `Err(HpkeError::ValidationError)` if `epoch` is the last one.
+fn step(seed: &[u8; 32], epoch: u64) -> Result<([u8; 32], [u8; 32]), HpkeError> {
+    let next_epoch = epoch.checked_add(1).ok_or(HpkeError::ValidationError)?;
+    let suite_id = kem_suite_id::<Kem>();
+    let (_, prk) = labeled_extract::<HkdfSha256>(&[], &suite_id, b"epoch_seed", seed);
+
+    let mut epoch_buf = [0u8; 8];
+    let mut ikm = [0u8; 32];
+    let mut next_seed = [0u8; 32];
+
+    // These only fail if the output is 255x the digest size, which it isn't
+    write_u64_be(&mut epoch_buf, epoch);
+    prk.labeled_expand(&suite_id, b"epoch_ikm", &epoch_buf, &mut ikm)
+        .expect("epoch ikm is way too big");
+    write_u64_be(&mut epoch_buf, next_epoch);
+    prk.labeled_expand(&suite_id, b"next_seed", &epoch_buf, &mut next_seed)
+        .expect("epoch seed is way too big");
+
+    Ok((ikm, next_seed))
+}
+
+/// Returns `info` with the epoch number appended, i.e., `info || "epoch" || I2OSP(epoch, 8)`. This
+/// is the info string that `setup_sender_epoch` and `EpochKeyChain::setup_receiver` use.
+pub fn epoch_info(info: &[u8], epoch: u64) -> Vec<u8> {
+    let mut epoch_buf = [0u8; 8];
+    write_u64_be(&mut epoch_buf, epoch);
+
+    let mut out = Vec::with_capacity(info.len() + 13);
+    out.extend_from_slice(info);
+    out.extend_from_slice(b"epoch");
+    out.extend_from_slice(&epoch_buf);
+    out
+}
+
+/// The recipient's evolving key. This holds only the seed of the current epoch, so earlier
+/// epochs are unrecoverable. The seed is zeroized on drop.
+pub struct EpochKeyChain {
+    epoch: u64,
+    seed: [u8; 32],
+}
+
+impl Drop for EpochKeyChain {
+    fn drop(&mut self) {
+        self.seed.zeroize();
+    }
+}
+
+impl EpochKeyChain {
+    /// Starts a key chain at epoch `epoch` from a secret root seed. `root_seed` MUST be uniformly
+    /// random, and SHOULD be erased once this is created.
+    pub fn new(root_seed: &[u8; 32], epoch: u64) -> EpochKeyChain {
+        EpochKeyChain {
+            epoch,
+            seed: *root_seed,
+        }
+    }
+
+    /// Returns the current epoch
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Checks that `epoch` is one this chain can reach from its current epoch
+    fn check_reachable(&self, epoch: u64) -> Result<(), HpkeError> {
+        if epoch < self.epoch || epoch - self.epoch > MAX_EPOCH_LOOKAHEAD {
+            Err(HpkeError::ValidationError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Derives the keypair for `epoch`, without advancing the chain. This is how the recipient
+    /// computes keys for future epochs, e.g., to publish them ahead of time.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(keypair)` on success. If `epoch` is before the current epoch, its seed is gone,
+    /// so this returns `Err(HpkeError::ValidationError)`. If `epoch` is more than
+    /// [`MAX_EPOCH_LOOKAHEAD`] epochs ahead, or is `u64::MAX`, also returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn keypair(&self, epoch: u64) -> Result<(PrivateKey, PublicKey), HpkeError> {
+        self.check_reachable(epoch)?;
+
+        let mut seed = self.seed;
+        let mut ikm = [0u8; 32];
+        for e in self.epoch..=epoch {
+            // Erase the previous epoch's secrets as we go
+            ikm.zeroize();
+            match step(&seed, e) {
+                Ok((this_ikm, next_seed)) => {
+                    ikm = this_ikm;
+                    seed.zeroize();
+                    seed = next_seed;
+                }
+                Err(err) => {
+                    seed.zeroize();
+                    return Err(err);
+                }
+            }
+        }
+        let keypair = Kem::derive_keypair(&ikm);
+
+        seed.zeroize();
+        ikm.zeroize();
+        Ok(keypair)
+    }
+
+    /// Moves the chain forward to `epoch`, erasing the seeds of every earlier epoch
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `epoch` is before the current epoch, or more than
+    /// [`MAX_EPOCH_LOOKAHEAD`] epochs ahead, returns `Err(HpkeError::ValidationError)`.
+    pub fn advance_to(&mut self, epoch: u64) -> Result<(), HpkeError> {
+        self.check_reachable(epoch)?;
+
+        while self.epoch < epoch {
+            let (mut ikm, next_seed) = step(&self.seed, self.epoch)?;
+            ikm.zeroize();
+            self.seed.zeroize();
+            self.seed = next_seed;
+            self.epoch += 1;
+        }
+        Ok(())
+    }
+
+    /// Signs the public key for `epoch` with the recipient's identity key, for publishing
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(signed_key)` on success. If `epoch` can't be reached from the current epoch
+    /// (see `keypair`), returns `Err(HpkeError::ValidationError)`.
+    pub fn sign_epoch_key<R: CryptoRng + RngCore>(
+        &self,
+        identity: &SigningKey,
+        epoch: u64,
+        csprng: &mut R,
+    ) -> Result<SignedEpochKey, HpkeError> {
+        let (_, public_key) = self.keypair(epoch)?;
+
+        let mut aux_rand = [0u8; 32];
+        csprng.fill_bytes(&mut aux_rand);
+        let signature = identity.sign(&signed_epoch_msg(epoch, &public_key), &aux_rand);
+
+        Ok(SignedEpochKey {
+            epoch,
+            public_key,
+            signature,
+        })
+    }
+
+    /// Initiates a decryption context for a message sent to epoch `epoch`. `info` is the
+    /// application's info string. The epoch is appended to it with `epoch_info`.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns a decryption context. If `epoch` can't be reached from the current
+    /// epoch (see `keypair`), returns `Err(HpkeError::ValidationError)`. If an error happened
+    /// during key decapsulation, returns `Err(HpkeError::DecapError)`.
+    pub fn setup_receiver<A, Kdf>(
+        &self,
+        mode: &OpModeR<Kem>,
+        epoch: u64,
+        encapped_key: &EncappedKey,
+        info: &[u8],
+    ) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        let (sk, _) = self.keypair(epoch)?;
+        setup_receiver(mode, &sk, encapped_key, &epoch_info(info, epoch))
+    }
+}
+
+/// Computes the message that the identity key signs: hash_HPKE/EpochKey(I2OSP(epoch, 8) || pk)
+fn signed_epoch_msg(epoch: u64, public_key: &PublicKey) -> [u8; 32] {
+    let mut epoch_buf = [0u8; 8];
+    write_u64_be(&mut epoch_buf, epoch);
+    tagged_hash(b"HPKE/EpochKey", &[&epoch_buf, &public_key.to_bytes()])
+}
+
+/// An epoch public key, signed by the recipient's identity key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedEpochKey {
+    /// The epoch this key is for
+    pub epoch: u64,
+    /// The recipient's public key for the epoch
+    pub public_key: PublicKey,
+    /// The identity key's signature over the epoch and public key
+    pub signature: Signature,
+}
+
+impl SignedEpochKey {
+    /// Checks that this key is signed by `identity_key`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if the signature is valid. Otherwise returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify(&self, identity_key: &VerifyingKey) -> Result<(), HpkeError> {
+        identity_key.verify(
+            &signed_epoch_msg(self.epoch, &self.public_key),
+            &self.signature,
+        )
+    }
+}
+
+impl Serializable for SignedEpochKey {
+    // uint64 epoch || opaque public_key[Npk] || opaque signature[64]
+    type OutputSize = typenum::U137;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        write_u64_be(&mut buf[0..8], self.epoch);
+        self.public_key.write_exact(&mut buf[8..73]);
+        self.signature.write_exact(&mut buf[73..137]);
+    }
+}
+
+impl Deserializable for SignedEpochKey {
+    // This doesn't check the signature. Use SignedEpochKey::verify for that.
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        let mut epoch = [0u8; 8];
+        epoch.copy_from_slice(&encoded[0..8]);
+        Ok(SignedEpochKey {
+            epoch: u64::from_be_bytes(epoch),
+            public_key: PublicKey::from_bytes(&encoded[8..73])?,
+            signature: Signature::from_bytes(&encoded[73..137])?,
+        })
+    }
+}
+
+/// Verifies `epoch_key` and, if it's valid, initiates an encryption context to it. `info` is the
+/// application's info string. The epoch is appended to it with `epoch_info`. The sender MUST send
+/// the epoch number along with the encapsulated key.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated public key and an encryption context. If the signature is
+/// invalid, returns `Err(HpkeError::ValidationError)`. If an error happened during key
+/// encapsulation, returns `Err(HpkeError::EncapError)`.
+pub fn setup_sender_epoch<A, Kdf, R>(
+    mode: &OpModeS<Kem>,
+    epoch_key: &SignedEpochKey,
+    identity_key: &VerifyingKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    epoch_key.verify(identity_key)?;
+    setup_sender::<A, Kdf, Kem, R>(
+        mode,
+        &epoch_key.public_key,
+        &epoch_info(info, epoch_key.epoch),
+        csprng,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aead::ChaCha20Poly1305, test_util::gen_rand_buf};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that published epoch keys work, that advancing the chain erases old epochs, and that
+    /// keys derived ahead of time match the ones derived after advancing
+    #[test]
+    fn test_epoch_keys() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"daily";
+
+        let identity = SigningKey::gen(&mut csprng);
+        let mut chain = EpochKeyChain::new(&gen_rand_buf(), 10);
+
+        // Publish keys for epochs 10 through 12
+        let published: Vec<SignedEpochKey> = (10..13)
+            .map(|e| chain.sign_epoch_key(&identity, e, &mut csprng).unwrap())
+            .collect();
+        let epoch_11 = SignedEpochKey::from_bytes(&published[1].to_bytes()).unwrap();
+
+        // A sender encrypts to epoch 11
+        let (encapped_key, mut sender_ctx) = setup_sender_epoch::<A, Kdf, _>(
+            &OpModeS::Base,
+            &epoch_11,
+            &identity.verifying_key(),
+            info,
+            &mut csprng,
+        )
+        .unwrap();
+        let ciphertext = sender_ctx.seal(b"tuesday's news", b"").unwrap();
+
+        // The recipient advances to epoch 11 and can still decrypt
+        chain.advance_to(11).unwrap();
+        assert_eq!(chain.keypair(12).unwrap().1, published[2].public_key);
+        let mut receiver_ctx = chain
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, 11, &encapped_key, info)
+            .unwrap();
+        assert_eq!(
+            receiver_ctx.open(&ciphertext, b"").unwrap(),
+            b"tuesday's news"
+        );
+
+        // Claiming the wrong epoch fails, since the epoch is bound into info
+        let mut receiver_ctx = chain
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, 12, &encapped_key, info)
+            .unwrap();
+        assert!(receiver_ctx.open(&ciphertext, b"").is_err());
+
+        // After advancing past epoch 11, it's gone for good
+        chain.advance_to(12).unwrap();
+        assert!(chain
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, 11, &encapped_key, info)
+            .is_err());
+        assert!(chain.advance_to(11).is_err());
+
+        // Signatures from the wrong identity are rejected
+        let other = SigningKey::gen(&mut csprng).verifying_key();
+        assert!(epoch_11.verify(&other).is_err());
+
+        // A message claiming a far-future epoch is rejected without walking the chain there
+        let far = 12 + MAX_EPOCH_LOOKAHEAD + 1;
+        assert_eq!(
+            chain
+                .setup_receiver::<A, Kdf>(&OpModeR::Base, far, &encapped_key, info)
+                .err(),
+            Some(HpkeError::ValidationError)
+        );
+        assert!(chain.keypair(u64::MAX).is_err());
+        assert!(chain.advance_to(far).is_err());
+        assert_eq!(chain.epoch(), 12);
+
+        // The last epoch has no successor, so the chain can't step through it
+        let last = EpochKeyChain::new(&gen_rand_buf(), u64::MAX);
+        assert_eq!(
+            last.keypair(u64::MAX).err(),
+            Some(HpkeError::ValidationError)
+        );
+    }
+}
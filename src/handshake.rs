@@ -0,0 +1,398 @@
+//! A mutually authenticated, forward-secret handshake between known peers
+//!
+//! This is a three-message handshake built from two `Auth`-mode HPKE setups:
+//!
+//! ```text
+//! Initiator                                          Responder
+//!   enc1, ctx1 = SetupAuthS(pkR, skI)
+//!   generate ephemeral (skE, pkE)
+//!                  enc1 || pkE || ctx1.Seal(aad = enc1 || pkE, "")  ->
+//!                                                     ctx1 = SetupAuthR(enc1, skR, pkI)
+//!                                                     enc2, ctx2 = SetupAuthS(pkE, skR)
+//!                                <-  enc2 || responder_confirm
+//!   ctx2 = SetupAuthR(enc2, skE, pkR)
+//!                  initiator_confirm  ->
+//! ```
+//!
+//! The transport keys are derived from the exporters of both contexts and a hash of the whole
+//! transcript, and each side proves it derived the same keys before the other starts using them.
+//! The second setup is to the initiator's ephemeral key, so once both sides have erased their
+//! handshake state, compromising the long-term keys doesn't expose the session.
+//!
+//! The API is sans-io. Each step consumes the previous state and returns the next state along with
+//! the bytes to send. Getting the bytes to the other side is up to the caller.
+
+use crate::{
+    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce, AeadTag},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand, SimpleHkdf},
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender, ExporterSecret},
+    util::{full_suite_id, split_checked, tagged_hash, write_u64_be, FullSuiteId},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The length of a key confirmation value
+const CONFIRM_LEN: usize = 32;
+
+// th1 = hash_HPKE/Handshake(suite_id || I2OSP(len(prologue), 8) || prologue || pkI || pkR || msg1)
+// th2 = hash_HPKE/Handshake(th1 || enc2)
+//
+// The info strings of the two setups are the prologue and th1, respectively.
+
+/// Computes the transcript hash after the first message
+fn transcript_1<A: Aead, Kdf: KdfTrait>(
+    prologue: &[u8],
+    pk_initiator: &PublicKey,
+    pk_responder: &PublicKey,
+    msg1: &[u8],
+) -> [u8; 32] {
+    let mut prologue_len = [0u8; 8];
+    write_u64_be(&mut prologue_len, prologue.len() as u64);
+    tagged_hash(
+        b"HPKE/Handshake",
+        &[
+            &full_suite_id::<A, Kdf, Kem>(),
+            &prologue_len,
+            prologue,
+            &pk_initiator.to_bytes(),
+            &pk_responder.to_bytes(),
+            msg1,
+        ],
+    )
+}
+
+/// Computes the transcript hash after the second message's encapsulated key
+fn transcript_2(th1: &[u8; 32], enc2: &EncappedKey) -> [u8; 32] {
+    tagged_hash(b"HPKE/Handshake", &[th1, &enc2.to_bytes()])
+}
+
+// ikm = ctx1.Export("handshake", 32) || ctx2.Export("handshake", 32)
+// prk = LabeledExtract(th2, "hs_secret", ikm)
+// i2r_key = LabeledExpand(prk, "i2r_key", "", Nk)
+// i2r_base_nonce = LabeledExpand(prk, "i2r_base_nonce", "", Nn)
+// r2i_key = LabeledExpand(prk, "r2i_key", "", Nk)
+// r2i_base_nonce = LabeledExpand(prk, "r2i_base_nonce", "", Nn)
+// exporter_secret = LabeledExpand(prk, "exp", "", Nh)
+// initiator_confirm = LabeledExpand(prk, "i_confirm", "", 32)
+// responder_confirm = LabeledExpand(prk, "r_confirm", "", 32)
+
+/// The keys both sides derive at the end of the handshake
+struct HandshakeKeys<A: Aead, Kdf: KdfTrait> {
+    prk: SimpleHkdf<Kdf>,
+    suite_id: FullSuiteId,
+    _marker: core::marker::PhantomData<A>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> HandshakeKeys<A, Kdf> {
+    /// Derives the keys from the transcript hash and the concatenated exports of both contexts
+    fn new(th2: &[u8; 32], ikm: &[u8; 64]) -> HandshakeKeys<A, Kdf> {
+        let suite_id = full_suite_id::<A, Kdf, Kem>();
+        let (_, prk) = labeled_extract::<Kdf>(th2, &suite_id, b"hs_secret", ikm);
+
+        HandshakeKeys {
+            prk,
+            suite_id,
+            _marker: core::marker::PhantomData,
+        }
+    }
+
+    fn expand(&self, label: &[u8], out: &mut [u8]) {
+        // This only fails if the output is 255x the digest size, which these never are
+        self.prk
+            .labeled_expand(&self.suite_id, label, b"", out)
+            .expect("handshake key is way too big");
+    }
+
+    fn confirm(&self, label: &[u8]) -> [u8; CONFIRM_LEN] {
+        let mut out = [0u8; CONFIRM_LEN];
+        self.expand(label, &mut out);
+        out
+    }
+
+    /// Makes the context for one direction of traffic
+    fn ctx(&self, key_label: &[u8], nonce_label: &[u8]) -> AeadCtx<A, Kdf, Kem> {
+        let mut key = AeadKey::<A>::default();
+        let mut base_nonce = AeadNonce::<A>::default();
+        let mut exporter_secret = ExporterSecret::<Kdf>::default();
+        self.expand(key_label, key.0.as_mut_slice());
+        self.expand(nonce_label, base_nonce.0.as_mut_slice());
+        self.expand(b"exp", exporter_secret.0.as_mut_slice());
+        AeadCtx::new(&key, base_nonce, exporter_secret)
+    }
+
+    /// Makes the transport for the given side
+    fn transport(&self, is_initiator: bool) -> Transport<A, Kdf> {
+        let i2r = self.ctx(b"i2r_key", b"i2r_base_nonce");
+        let r2i = self.ctx(b"r2i_key", b"r2i_base_nonce");
+        let (send, recv) = if is_initiator { (i2r, r2i) } else { (r2i, i2r) };
+        Transport {
+            sender: send.into(),
+            receiver: recv.into(),
+        }
+    }
+}
+
+/// The result of a completed handshake: one context for each direction. Both contexts have the
+/// same exporter secret, so either can be used to export secrets bound to the session.
+pub struct Transport<A: Aead, Kdf: KdfTrait> {
+    /// Encrypts messages to the peer
+    pub sender: AeadCtxS<A, Kdf, Kem>,
+    /// Decrypts messages from the peer
+    pub receiver: AeadCtxR<A, Kdf, Kem>,
+}
+
+/// An initiator waiting for the responder's reply
+pub struct InitiatorHandshake<A: Aead, Kdf: KdfTrait> {
+    pk_responder: PublicKey,
+    sk_eph: PrivateKey,
+    th1: [u8; 32],
+    ctx1: AeadCtxS<A, Kdf, Kem>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> InitiatorHandshake<A, Kdf> {
+    /// Starts a handshake with the responder `pk_responder`, authenticating as `initiator_id`.
+    /// `prologue` is any context both sides agree on beforehand, e.g., a protocol name. It isn't
+    /// sent, but the handshake fails if the sides disagree on it.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the new state and the first message to send. If an error happened during
+    /// key encapsulation, returns `Err(HpkeError::EncapError)`.
+    pub fn start<R: CryptoRng + RngCore>(
+        initiator_id: (PrivateKey, PublicKey),
+        pk_responder: &PublicKey,
+        prologue: &[u8],
+        csprng: &mut R,
+    ) -> Result<(InitiatorHandshake<A, Kdf>, Vec<u8>), HpkeError> {
+        let pk_initiator = initiator_id.1.clone();
+        let (enc1, mut ctx1) = setup_sender::<A, Kdf, Kem, R>(
+            &OpModeS::Auth(initiator_id),
+            pk_responder,
+            prologue,
+            csprng,
+        )?;
+        let (sk_eph, pk_eph) = Kem::gen_keypair(csprng);
+
+        // Authenticate the ephemeral key under the first context
+        let mut msg1 = Vec::new();
+        msg1.extend_from_slice(&enc1.to_bytes());
+        msg1.extend_from_slice(&pk_eph.to_bytes());
+        let tag = ctx1.seal_in_place_detached(&mut [], &msg1)?;
+        msg1.extend_from_slice(&tag.to_bytes());
+
+        let th1 = transcript_1::<A, Kdf>(prologue, &pk_initiator, pk_responder, &msg1);
+        let state = InitiatorHandshake {
+            pk_responder: pk_responder.clone(),
+            sk_eph,
+            th1,
+            ctx1,
+        };
+        Ok((state, msg1))
+    }
+
+    /// Processes the responder's reply
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the transport and the final message to send. The transport can be used
+    /// immediately. If the reply is malformed, returns `Err(HpkeError::ValidationError)`. If an
+    /// error happened during key decapsulation, returns `Err(HpkeError::DecapError)`. If the
+    /// responder's key confirmation is wrong, e.g., because it isn't who it was supposed to be,
+    /// returns `Err(HpkeError::ValidationError)`.
+    pub fn read_response(self, msg2: &[u8]) -> Result<(Transport<A, Kdf>, Vec<u8>), HpkeError> {
+        let (enc2, confirm) = split_checked(msg2, EncappedKey::size())?;
+        if confirm.len() != CONFIRM_LEN {
+            return Err(HpkeError::ValidationError);
+        }
+        let enc2 = EncappedKey::from_bytes(enc2)?;
+
+        let ctx2 = setup_receiver::<A, Kdf, Kem>(
+            &OpModeR::Auth(self.pk_responder.clone()),
+            &self.sk_eph,
+            &enc2,
+            &self.th1,
+        )?;
+        let th2 = transcript_2(&self.th1, &enc2);
+        let mut ikm = Zeroizing::new([0u8; 64]);
+        self.ctx1.export(b"handshake", &mut ikm[..32])?;
+        ctx2.export(b"handshake", &mut ikm[32..])?;
+        let keys = HandshakeKeys::<A, Kdf>::new(&th2, &ikm);
+
+        if !bool::from(keys.confirm(b"r_confirm").ct_eq(confirm)) {
+            return Err(HpkeError::ValidationError);
+        }
+
+        Ok((keys.transport(true), keys.confirm(b"i_confirm").to_vec()))
+    }
+}
+
+/// A responder waiting for the initiator's key confirmation
+pub struct ResponderHandshake<A: Aead, Kdf: KdfTrait> {
+    initiator_confirm: Zeroizing<[u8; CONFIRM_LEN]>,
+    transport: Transport<A, Kdf>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> ResponderHandshake<A, Kdf> {
+    /// Processes the initiator's first message. `responder_id` is the responder's keypair,
+    /// `pk_initiator` is who the responder expects to be talking to, and `prologue` MUST match the
+    /// initiator's.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the new state and the reply to send. If the message is malformed or
+    /// wasn't sent by `pk_initiator`, returns an error.
+    pub fn respond<R: CryptoRng + RngCore>(
+        responder_id: (PrivateKey, PublicKey),
+        pk_initiator: &PublicKey,
+        prologue: &[u8],
+        msg1: &[u8],
+        csprng: &mut R,
+    ) -> Result<(ResponderHandshake<A, Kdf>, Vec<u8>), HpkeError> {
+        let (enc1, rest) = split_checked(msg1, EncappedKey::size())?;
+        let (pk_eph, tag) = split_checked(rest, PublicKey::size())?;
+        let tag = AeadTag::<A>::from_bytes(tag)?;
+        let signed_len = msg1.len() - AeadTag::<A>::size();
+
+        // Check that the initiator's ephemeral key came from the initiator
+        let mut ctx1 = setup_receiver::<A, Kdf, Kem>(
+            &OpModeR::Auth(pk_initiator.clone()),
+            &responder_id.0,
+            &EncappedKey::from_bytes(enc1)?,
+            prologue,
+        )?;
+        ctx1.open_in_place_detached(&mut [], &msg1[..signed_len], &tag)?;
+        let pk_eph = PublicKey::from_bytes(pk_eph)?;
+
+        let th1 = transcript_1::<A, Kdf>(prologue, pk_initiator, &responder_id.1, msg1);
+        let (enc2, ctx2) =
+            setup_sender::<A, Kdf, Kem, R>(&OpModeS::Auth(responder_id), &pk_eph, &th1, csprng)?;
+        let th2 = transcript_2(&th1, &enc2);
+        let mut ikm = Zeroizing::new([0u8; 64]);
+        ctx1.export(b"handshake", &mut ikm[..32])?;
+        ctx2.export(b"handshake", &mut ikm[32..])?;
+        let keys = HandshakeKeys::<A, Kdf>::new(&th2, &ikm);
+
+        let mut msg2 = enc2.to_bytes().to_vec();
+        msg2.extend_from_slice(&keys.confirm(b"r_confirm"));
+        let state = ResponderHandshake {
+            initiator_confirm: Zeroizing::new(keys.confirm(b"i_confirm")),
+            transport: keys.transport(false),
+        };
+        Ok((state, msg2))
+    }
+
+    /// Processes the initiator's key confirmation, completing the handshake
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the transport. If the confirmation is wrong, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn read_confirmation(self, msg3: &[u8]) -> Result<Transport<A, Kdf>, HpkeError> {
+        if !bool::from(self.initiator_confirm.ct_eq(msg3)) {
+            return Err(HpkeError::ValidationError);
+        }
+
+        Ok(self.transport)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Runs a handshake and checks that traffic flows both ways, and that the wrong prologue, an
+    /// impostor responder, or a bad confirmation all fail
+    #[test]
+    fn test_handshake() {
+        let mut csprng = StdRng::from_entropy();
+        let prologue = b"wallet sync v1";
+        let (sk_i, pk_i) = Kem::gen_keypair(&mut csprng);
+        let (sk_r, pk_r) = Kem::gen_keypair(&mut csprng);
+        let (sk_x, pk_x) = Kem::gen_keypair(&mut csprng);
+
+        let (initiator, msg1) = InitiatorHandshake::<A, Kdf>::start(
+            (sk_i.clone(), pk_i.clone()),
+            &pk_r,
+            prologue,
+            &mut csprng,
+        )
+        .unwrap();
+
+        // Responders that disagree on the prologue or the initiator's identity reject the message
+        assert!(ResponderHandshake::<A, Kdf>::respond(
+            (sk_r.clone(), pk_r.clone()),
+            &pk_i,
+            b"wallet sync v2",
+            &msg1,
+            &mut csprng
+        )
+        .is_err());
+        assert!(ResponderHandshake::<A, Kdf>::respond(
+            (sk_r.clone(), pk_r.clone()),
+            &pk_x,
+            prologue,
+            &msg1,
+            &mut csprng
+        )
+        .is_err());
+
+        let (responder, msg2) = ResponderHandshake::<A, Kdf>::respond(
+            (sk_r.clone(), pk_r.clone()),
+            &pk_i,
+            prologue,
+            &msg1,
+            &mut csprng,
+        )
+        .unwrap();
+        let (mut initiator_transport, msg3) = initiator.read_response(&msg2).unwrap();
+
+        // A bad confirmation is rejected
+        let mut bad_msg3 = msg3.clone();
+        bad_msg3[0] ^= 1;
+        let (bad_responder, _) = ResponderHandshake::<A, Kdf>::respond(
+            (sk_r.clone(), pk_r.clone()),
+            &pk_i,
+            prologue,
+            &msg1,
+            &mut csprng,
+        )
+        .unwrap();
+        assert!(bad_responder.read_confirmation(&bad_msg3).is_err());
+
+        let mut responder_transport = responder.read_confirmation(&msg3).unwrap();
+
+        // Traffic flows both ways
+        let ct = initiator_transport.sender.seal(b"hi", b"").unwrap();
+        assert_eq!(responder_transport.receiver.open(&ct, b"").unwrap(), b"hi");
+        let ct = responder_transport.sender.seal(b"hey", b"").unwrap();
+        assert_eq!(initiator_transport.receiver.open(&ct, b"").unwrap(), b"hey");
+
+        // An impostor who intercepts msg1 and answers the ephemeral key as itself fails the
+        // initiator's check, since it can't derive the first context's exports
+        let (initiator, msg1) =
+            InitiatorHandshake::<A, Kdf>::start((sk_i, pk_i), &pk_r, prologue, &mut csprng)
+                .unwrap();
+        let pk_eph = PublicKey::from_bytes(&msg1[65..130]).unwrap();
+        let (enc2, _) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Auth((sk_x, pk_x)), &pk_eph, b"", &mut csprng)
+                .unwrap();
+        let mut forged_msg2 = enc2.to_bytes().to_vec();
+        forged_msg2.extend_from_slice(&[0u8; CONFIRM_LEN]);
+        assert!(initiator.read_response(&forged_msg2).is_err());
+    }
+}
@@ -42,7 +42,8 @@ macro_rules! impl_dhkem {
             // EncappedKeys need to be serializable, since they're gonna be sent over the wire.
             // Underlyingly, they're just DH pubkeys, so we just serialize them the same way
             impl Serializable for EncappedKey {
-                type OutputSize = <<$dhkex as DhKeyExchange>::PublicKey as Serializable>::OutputSize;
+                type OutputSize =
+                    <<$dhkex as DhKeyExchange>::PublicKey as Serializable>::OutputSize;
 
                 // Pass to underlying to_bytes() impl
                 fn write_exact(&self, buf: &mut [u8]) {
@@ -57,7 +58,9 @@ macro_rules! impl_dhkem {
                 // Pass to underlying from_bytes() impl
                 fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
                     let pubkey =
-                        <<$dhkex as DhKeyExchange>::PublicKey as Deserializable>::from_bytes(encoded)?;
+                        <<$dhkex as DhKeyExchange>::PublicKey as Deserializable>::from_bytes(
+                            encoded,
+                        )?;
                     Ok(EncappedKey(pubkey))
                 }
             }
@@ -105,9 +108,6 @@ macro_rules! impl_dhkem {
                 sender_id_keypair: Option<(&PrivateKey, &PublicKey)>,
                 sk_eph: PrivateKey,
             ) -> Result<(SharedSecret<$kem_name>, EncappedKey), HpkeError> {
-                // Put together the binding context used for all KDF operations
-                let suite_id = kem_suite_id::<$kem_name>();
-
                 // Compute the shared secret from the ephemeral inputs
                 let kex_res_eph = <$dhkex as DhKeyExchange>::dh(&sk_eph, pk_recip)
                     .map_err(|_| HpkeError::EncapError)?;
@@ -118,9 +118,41 @@ macro_rules! impl_dhkem {
                     EncappedKey(pk_eph)
                 };
 
+                // If we want to do an authed encap, do a DH exchange between the sender identity
+                // secret key and the recipient's pubkey
+                let shared_secret = if let Some((sk_sender_id, pk_sender_id)) = sender_id_keypair {
+                    let kex_res_identity = <$dhkex as DhKeyExchange>::dh(sk_sender_id, pk_recip)
+                        .map_err(|_| HpkeError::EncapError)?;
+                    derive_shared_secret(
+                        &kex_res_eph,
+                        Some((&kex_res_identity, pk_sender_id)),
+                        &encapped_key,
+                        pk_recip,
+                    )
+                } else {
+                    derive_shared_secret(&kex_res_eph, None, &encapped_key, pk_recip)
+                };
+
+                Ok((shared_secret, encapped_key))
+            }
+
+            /// Runs `ExtractAndExpand` on already-computed DH results. `kex_res_eph` is the DH of
+            /// the ephemeral key and the recipient key. If `identity` is given, it is the DH of the
+            /// sender identity key and the recipient key, along with the sender identity pubkey.
+            /// This is shared by encap and decap, and is how callers that compute the DH elsewhere,
+            /// e.g., split across several parties, get to a shared secret.
+            pub(crate) fn derive_shared_secret(
+                kex_res_eph: &<$dhkex as DhKeyExchange>::KexResult,
+                identity: Option<(&<$dhkex as DhKeyExchange>::KexResult, &PublicKey)>,
+                encapped_key: &EncappedKey,
+                pk_recip: &PublicKey,
+            ) -> SharedSecret<$kem_name> {
+                // Put together the binding context used for all KDF operations
+                let suite_id = kem_suite_id::<$kem_name>();
+
                 // The shared secret is either gonna be kex_res_eph, or that along with another
                 // shared secret that's tied to the sender's identity.
-                let shared_secret = if let Some((sk_sender_id, pk_sender_id)) = sender_id_keypair {
+                if let Some((kex_res_identity, pk_sender_id)) = identity {
                     // kem_context = encapped_key || pk_recip || pk_sender_id
                     // We concat without allocation by making a buffer of the maximum possible
                     // size, then taking the appropriately sized slice.
@@ -132,11 +164,6 @@ macro_rules! impl_dhkem {
                     );
                     let kem_context = &kem_context_buf[..kem_context_size];
 
-                    // We want to do an authed encap. Do a DH exchange between the sender identity
-                    // secret key and the recipient's pubkey
-                    let kex_res_identity = <$dhkex as DhKeyExchange>::dh(sk_sender_id, pk_recip)
-                        .map_err(|_| HpkeError::EncapError)?;
-
                     // concatted_secrets = kex_res_eph || kex_res_identity
                     // Same no-alloc concat trick as above
                     let (concatted_secrets_buf, concatted_secret_size) = concat_with_known_maxlen!(
@@ -152,8 +179,13 @@ macro_rules! impl_dhkem {
                     // 255x the digest size of the hash function. Since these values are fixed at
                     // compile time, we don't worry about it.
                     let mut buf = <SharedSecret<$kem_name> as Default>::default();
-                    extract_and_expand::<$kdf>(concatted_secrets, &suite_id, kem_context, &mut buf.0)
-                        .expect("shared secret is way too big");
+                    extract_and_expand::<$kdf>(
+                        concatted_secrets,
+                        &suite_id,
+                        kem_context,
+                        &mut buf.0,
+                    )
+                    .expect("shared secret is way too big");
                     buf
                 } else {
                     // kem_context = encapped_key || pk_recip
@@ -179,9 +211,7 @@ macro_rules! impl_dhkem {
                     )
                     .expect("shared secret is way too big");
                     buf
-                };
-
-                Ok((shared_secret, encapped_key))
+                }
             }
 
             impl KemTrait for $kem_name {
@@ -265,82 +295,32 @@ macro_rules! impl_dhkem {
                     pk_sender_id: Option<&Self::PublicKey>,
                     encapped_key: &Self::EncappedKey,
                 ) -> Result<SharedSecret<Self>, HpkeError> {
-                    // Put together the binding context used for all KDF operations
-                    let suite_id = kem_suite_id::<Self>();
-
                     // Compute the shared secret from the ephemeral inputs
                     let kex_res_eph = <$dhkex as DhKeyExchange>::dh(sk_recip, &encapped_key.0)
                         .map_err(|_| HpkeError::DecapError)?;
 
-                    // Compute the sender's pubkey from their privkey
+                    // Compute the recipient's pubkey from their privkey
                     let pk_recip = <$dhkex as DhKeyExchange>::sk_to_pk(sk_recip);
 
-                    // The shared secret is either gonna be kex_res_eph, or that along with another
-                    // shared secret that's tied to the sender's identity.
+                    // If we want to do an authed decap, do a DH exchange between the recipient's
+                    // secret key and the sender's identity pubkey
                     if let Some(pk_sender_id) = pk_sender_id {
-                        // kem_context = encapped_key || pk_recip || pk_sender_id We concat without
-                        // allocation by making a buffer of the maximum possible size, then taking the
-                        // appropriately sized slice.
-                        let (kem_context_buf, kem_context_size) = concat_with_known_maxlen!(
-                            MAX_PUBKEY_SIZE,
-                            &encapped_key.to_bytes(),
-                            &pk_recip.to_bytes(),
-                            &pk_sender_id.to_bytes()
-                        );
-                        let kem_context = &kem_context_buf[..kem_context_size];
-
-                        // We want to do an authed encap. Do a DH exchange between the sender identity
-                        // secret key and the recipient's pubkey
-                        let kex_res_identity = <$dhkex as DhKeyExchange>::dh(sk_recip, pk_sender_id)
-                            .map_err(|_| HpkeError::DecapError)?;
-
-                        // concatted_secrets = kex_res_eph || kex_res_identity
-                        // Same no-alloc concat trick as above
-                        let (concatted_secrets_buf, concatted_secret_size) = concat_with_known_maxlen!(
-                            MAX_PUBKEY_SIZE,
-                            &kex_res_eph.to_bytes(),
-                            &kex_res_identity.to_bytes()
-                        );
-                        let concatted_secrets = &concatted_secrets_buf[..concatted_secret_size];
-
-                        // The "authed shared secret" is derived from the KEX of the ephemeral input
-                        // with the recipient pubkey, and the kex of the identity input with the
-                        // recipient pubkey. The HKDF-Expand call only errors if the output values are
-                        // 255x the digest size of the hash function. Since these values are fixed at
-                        // compile time, we don't worry about it.
-                        let mut shared_secret = <SharedSecret<Self> as Default>::default();
-                        extract_and_expand::<$kdf>(
-                            concatted_secrets,
-                            &suite_id,
-                            kem_context,
-                            &mut shared_secret.0,
-                        )
-                        .expect("shared secret is way too big");
-                        Ok(shared_secret)
+                        let kex_res_identity =
+                            <$dhkex as DhKeyExchange>::dh(sk_recip, pk_sender_id)
+                                .map_err(|_| HpkeError::DecapError)?;
+                        Ok(derive_shared_secret(
+                            &kex_res_eph,
+                            Some((&kex_res_identity, pk_sender_id)),
+                            encapped_key,
+                            &pk_recip,
+                        ))
                     } else {
-                        // kem_context = encapped_key || pk_recip || pk_sender_id
-                        // We concat without allocation by making a buffer of the maximum possible
-                        // size, then taking the appropriately sized slice.
-                        let (kem_context_buf, kem_context_size) = concat_with_known_maxlen!(
-                            MAX_PUBKEY_SIZE,
-                            &encapped_key.to_bytes(),
-                            &pk_recip.to_bytes()
-                        );
-                        let kem_context = &kem_context_buf[..kem_context_size];
-
-                        // The "unauthed shared secret" is derived from just the KEX of the ephemeral
-                        // input with the recipient pubkey. The HKDF-Expand call only errors if the
-                        // output values are 255x the digest size of the hash function. Since these
-                        // values are fixed at compile time, we don't worry about it.
-                        let mut shared_secret = <SharedSecret<Self> as Default>::default();
-                        extract_and_expand::<$kdf>(
-                            &kex_res_eph.to_bytes(),
-                            &suite_id,
-                            kem_context,
-                            &mut shared_secret.0,
-                        )
-                        .expect("shared secret is way too big");
-                        Ok(shared_secret)
+                        Ok(derive_shared_secret(
+                            &kex_res_eph,
+                            None,
+                            encapped_key,
+                            &pk_recip,
+                        ))
                     }
                 }
             }
@@ -0,0 +1,348 @@
+use crate::{
+    aead::{Aead, AeadCtxR},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    op_mode::OpModeR,
+    setup::setup_receiver,
+    single_shot::single_shot_open,
+    HpkeError, Vec,
+};
+
+/// A recipient keypair in a [`Keyring`], along with its identifier and validity window
+struct KeyringEntry<Kem: KemTrait> {
+    key_id: Vec<u8>,
+    sk: Kem::PrivateKey,
+    pk: Kem::PublicKey,
+    not_before: Option<u64>,
+    not_after: Option<u64>,
+}
+
+impl<Kem: KemTrait> KeyringEntry<Kem> {
+    /// Returns whether this key may be used at time `now`. The window is `[not_before, not_after)`.
+    fn is_active(&self, now: u64) -> bool {
+        let started = match self.not_before {
+            Some(t) => t <= now,
+            None => true,
+        };
+        let expired = match self.not_after {
+            Some(t) => now >= t,
+            None => false,
+        };
+        started && !expired
+    }
+}
+
+/// A set of recipient private keys, each with an identifier and an optional validity window. This
+/// is for recipients that rotate keys and need to hold several at once. Senders are expected to
+/// send the identifier of the key they used alongside the encapsulated key. If they don't, the
+/// keyring falls back to trying every active key.
+///
+/// Timestamps are whatever unit the application uses, e.g., seconds since the UNIX epoch. The
+/// keyring never reads a clock, so every method that checks validity takes the current time.
+pub struct Keyring<Kem: KemTrait> {
+    entries: Vec<KeyringEntry<Kem>>,
+}
+
+impl<Kem: KemTrait> Default for Keyring<Kem> {
+    fn default() -> Keyring<Kem> {
+        Keyring {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<Kem: KemTrait> Keyring<Kem> {
+    /// Makes an empty keyring
+    pub fn new() -> Keyring<Kem> {
+        Keyring::default()
+    }
+
+    /// Adds a private key under the given identifier. The key is active from `not_before`
+    /// (inclusive) until `not_after` (exclusive). `None` means unbounded in that direction.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If a key with the same identifier is already in the keyring,
+    /// or `not_after` is not after `not_before`, returns `Err(HpkeError::ValidationError)`.
+    pub fn insert(
+        &mut self,
+        key_id: &[u8],
+        sk: Kem::PrivateKey,
+        not_before: Option<u64>,
+        not_after: Option<u64>,
+    ) -> Result<(), HpkeError> {
+        if self.find(key_id).is_some() {
+            return Err(HpkeError::ValidationError);
+        }
+        if let (Some(start), Some(end)) = (not_before, not_after) {
+            if end <= start {
+                return Err(HpkeError::ValidationError);
+            }
+        }
+
+        let pk = Kem::sk_to_pk(&sk);
+        self.entries.push(KeyringEntry {
+            key_id: key_id.to_vec(),
+            sk,
+            pk,
+            not_before,
+            not_after,
+        });
+        Ok(())
+    }
+
+    /// Removes the key with the given identifier, returning its keypair if it was present
+    pub fn remove(&mut self, key_id: &[u8]) -> Option<(Kem::PrivateKey, Kem::PublicKey)> {
+        let idx = self.entries.iter().position(|e| e.key_id == key_id)?;
+        let entry = self.entries.remove(idx);
+        Some((entry.sk, entry.pk))
+    }
+
+    /// Removes every key whose validity window ended at or before `now`
+    pub fn remove_expired(&mut self, now: u64) {
+        self.entries
+            .retain(|e| !matches!(e.not_after, Some(t) if now >= t));
+    }
+
+    /// Returns the public key with the given identifier, if it's in the keyring
+    pub fn public_key(&self, key_id: &[u8]) -> Option<&Kem::PublicKey> {
+        self.find(key_id).map(|e| &e.pk)
+    }
+
+    /// Returns the number of keys in the keyring, active or not
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the keyring has no keys
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn find(&self, key_id: &[u8]) -> Option<&KeyringEntry<Kem>> {
+        self.entries.iter().find(|e| e.key_id == key_id)
+    }
+
+    /// Initiates a decryption context with the key identified by `key_id`. See `setup_receiver`.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns a decryption context. If there is no key with that identifier, the key
+    /// is not active at time `now`, or an error happened during key decapsulation, returns
+    /// `Err(HpkeError::DecapError)`.
+    pub fn setup_receiver<A, Kdf>(
+        &self,
+        mode: &OpModeR<Kem>,
+        key_id: &[u8],
+        encapped_key: &Kem::EncappedKey,
+        info: &[u8],
+        now: u64,
+    ) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        let entry = self
+            .find(key_id)
+            .filter(|e| e.is_active(now))
+            .ok_or(HpkeError::DecapError)?;
+        setup_receiver(mode, &entry.sk, encapped_key, info)
+    }
+
+    /// Decapsulates and decrypts a single-shot ciphertext. If `key_id` is given, only that key is
+    /// tried. Otherwise, every key is tried. In that case the keyring does the same amount of work
+    /// no matter which key, if any, succeeds, so the time taken doesn't reveal which key the
+    /// ciphertext was for. See `single_shot_open`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((key_id, plaintext))` on success, where `key_id` is the identifier of the key
+    /// that opened the ciphertext. If `key_id` is given and there is no active key with that
+    /// identifier, returns `Err(HpkeError::DecapError)`. If no active key opens the ciphertext,
+    /// returns `Err(HpkeError::OpenError)`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open<A, Kdf>(
+        &self,
+        mode: &OpModeR<Kem>,
+        key_id: Option<&[u8]>,
+        encapped_key: &Kem::EncappedKey,
+        info: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+        now: u64,
+    ) -> Result<(&[u8], Vec<u8>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        if let Some(key_id) = key_id {
+            let entry = self
+                .find(key_id)
+                .filter(|e| e.is_active(now))
+                .ok_or(HpkeError::DecapError)?;
+            let plaintext = single_shot_open::<A, Kdf, Kem>(
+                mode,
+                &entry.sk,
+                encapped_key,
+                info,
+                ciphertext,
+                aad,
+            )?;
+            return Ok((&entry.key_id, plaintext));
+        }
+
+        // Trial decryption. Don't stop at the first success, and try inactive keys too, discarding
+        // the result. That way the work done is the same for every ciphertext.
+        let mut found = None;
+        for entry in &self.entries {
+            let res = single_shot_open::<A, Kdf, Kem>(
+                mode,
+                &entry.sk,
+                encapped_key,
+                info,
+                ciphertext,
+                aad,
+            );
+            if let Ok(plaintext) = res {
+                if found.is_none() && entry.is_active(now) {
+                    found = Some((&entry.key_id[..], plaintext));
+                }
+            }
+        }
+
+        found.ok_or(HpkeError::OpenError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Keyring;
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::SecpK256HkdfSha256, op_mode::OpModeR,
+        single_shot::single_shot_seal, test_util::aead_ctx_eq, HpkeError, Kem as KemTrait, OpModeS,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+    type Kem = SecpK256HkdfSha256;
+
+    /// Tests key selection by ID, trial decryption, and validity windows
+    #[test]
+    fn test_keyring() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"rotate me";
+        let aad = b"";
+        let msg = b"which key was it";
+
+        // An old key that expired at t=100, a current key, and a future key active from t=200
+        let (sk_old, pk_old) = Kem::gen_keypair(&mut csprng);
+        let (sk_cur, pk_cur) = Kem::gen_keypair(&mut csprng);
+        let (sk_new, pk_new) = Kem::gen_keypair(&mut csprng);
+        let mut keyring = Keyring::<Kem>::new();
+        keyring.insert(b"old", sk_old, None, Some(100)).unwrap();
+        keyring
+            .insert(b"cur", sk_cur.clone(), Some(50), None)
+            .unwrap();
+        keyring.insert(b"new", sk_new, Some(200), None).unwrap();
+        assert_eq!(keyring.len(), 3);
+        assert_eq!(keyring.public_key(b"new"), Some(&pk_new));
+
+        // Duplicate IDs and empty windows are rejected
+        assert_eq!(
+            keyring.insert(b"cur", sk_cur.clone(), None, None),
+            Err(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            keyring.insert(b"bad", sk_cur, Some(10), Some(10)),
+            Err(HpkeError::ValidationError)
+        );
+
+        let now = 150;
+        let (encapped_key, ciphertext) = single_shot_seal::<A, Kdf, Kem, _>(
+            &OpModeS::Base,
+            &pk_cur,
+            info,
+            msg,
+            aad,
+            &mut csprng,
+        )
+        .unwrap();
+
+        // By ID, and by trial decryption
+        for key_id in [Some(&b"cur"[..]), None] {
+            let (used_id, plaintext) = keyring
+                .open::<A, Kdf>(
+                    &OpModeR::Base,
+                    key_id,
+                    &encapped_key,
+                    info,
+                    &ciphertext,
+                    aad,
+                    now,
+                )
+                .unwrap();
+            assert_eq!(used_id, b"cur");
+            assert_eq!(plaintext, msg);
+        }
+
+        // The wrong ID, an unknown ID, and an inactive ID all fail
+        let res = keyring.open::<A, Kdf>(
+            &OpModeR::Base,
+            Some(b"new"),
+            &encapped_key,
+            info,
+            &ciphertext,
+            aad,
+            now,
+        );
+        assert_eq!(res, Err(HpkeError::DecapError));
+        let res = keyring.open::<A, Kdf>(
+            &OpModeR::Base,
+            Some(b"nope"),
+            &encapped_key,
+            info,
+            &ciphertext,
+            aad,
+            now,
+        );
+        assert_eq!(res, Err(HpkeError::DecapError));
+
+        // Expired keys are skipped in trial decryption
+        let (encapped_key, ciphertext) = single_shot_seal::<A, Kdf, Kem, _>(
+            &OpModeS::Base,
+            &pk_old,
+            info,
+            msg,
+            aad,
+            &mut csprng,
+        )
+        .unwrap();
+        let res = keyring.open::<A, Kdf>(
+            &OpModeR::Base,
+            None,
+            &encapped_key,
+            info,
+            &ciphertext,
+            aad,
+            now,
+        );
+        assert_eq!(res, Err(HpkeError::OpenError));
+
+        // setup_receiver picks the right key
+        let (encapped_key, mut sender_ctx) =
+            crate::setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_cur, info, &mut csprng)
+                .unwrap();
+        let mut receiver_ctx = keyring
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, b"cur", &encapped_key, info, now)
+            .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Expired keys get pruned
+        keyring.remove_expired(now);
+        assert!(keyring.public_key(b"old").is_none());
+        assert_eq!(keyring.remove(b"new").map(|(_, pk)| pk), Some(pk_new));
+        assert_eq!(keyring.len(), 1);
+    }
+}
@@ -81,6 +81,9 @@
 #[macro_use]
 extern crate std;
 
+#[cfg(feature = "std")]
+#[allow(unused_imports)]
+pub(crate) use std::string::String;
 #[cfg(feature = "std")]
 pub(crate) use std::vec::Vec;
 
@@ -89,6 +92,9 @@ pub(crate) use std::vec::Vec;
 #[macro_use]
 extern crate alloc;
 
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+#[allow(unused_imports)]
+pub(crate) use alloc::string::String;
 #[cfg(all(feature = "alloc", not(feature = "std")))]
 pub(crate) use alloc::vec::Vec;
 
@@ -113,12 +119,61 @@ pub use rand_core;
 mod util;
 
 pub mod aead;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod any_sender;
+#[cfg(feature = "secp")]
+pub mod auth_sig;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod bhttp;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod cms;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod cose;
+#[cfg(feature = "secp")]
+pub mod decap_proof;
 mod dhkex;
+#[cfg(feature = "secp")]
+pub mod dleq;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod ech;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod envelope;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod epoch;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod handshake;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod jose;
 pub mod kdf;
 pub mod kem;
+#[cfg(any(feature = "alloc", feature = "std"))]
+mod keyring;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod mls;
+#[cfg(any(feature = "alloc", feature = "std"))]
+mod multi_recipient;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod odoh;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod ohttp;
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub mod one_time_keys;
 mod op_mode;
+#[cfg(feature = "secp")]
+pub mod oracle;
+pub mod padding;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod payjoin;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod prekey;
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub mod ratchet;
+#[cfg(feature = "secp")]
+pub mod schnorr;
 mod setup;
 mod single_shot;
+#[cfg(feature = "secp")]
+pub mod threshold;
 
 #[doc(inline)]
 pub use kem::Kem;
@@ -131,7 +186,17 @@ pub use single_shot::{single_shot_open_in_place_detached, single_shot_seal_in_pl
 
 #[doc(inline)]
 #[cfg(any(feature = "alloc", feature = "std"))]
-pub use single_shot::{single_shot_open, single_shot_seal};
+pub use single_shot::{
+    single_shot_open, single_shot_open_padded, single_shot_seal, single_shot_seal_padded,
+};
+
+#[doc(inline)]
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub use multi_recipient::{multi_open, multi_seal, MultiRecipientCiphertext, RecipientEntry};
+
+#[doc(inline)]
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub use keyring::Keyring;
 
 //-------- Top-level types --------//
 
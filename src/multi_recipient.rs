@@ -0,0 +1,482 @@
+use crate::{
+    aead::{Aead, AeadKey, AeadNonce, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    util::{split_checked, write_u16_be},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use aead::{AeadInPlace as BaseAeadInPlace, KeyInit as BaseKeyInit};
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+/// One recipient's slot in a [`MultiRecipientCiphertext`]. This holds the encapsulated key for
+/// the recipient, the content-encryption key sealed under that recipient's HPKE context, and an
+/// optional hint the recipient can use to find their slot without trial decryption.
+pub struct RecipientEntry<Kem: KemTrait> {
+    /// An optional, application-defined identifier for the recipient's public key. This is sent
+    /// in the clear. It MUST NOT be longer than 255 bytes.
+    pub key_hint: Option<Vec<u8>>,
+    /// The encapsulated key the recipient uses to derive their decryption context
+    pub encapped_key: Kem::EncappedKey,
+    /// The content-encryption key, sealed to this recipient. This is the key ciphertext followed
+    /// by its tag.
+    pub wrapped_key: Vec<u8>,
+}
+
+impl<Kem: KemTrait> Clone for RecipientEntry<Kem> {
+    fn clone(&self) -> RecipientEntry<Kem> {
+        RecipientEntry {
+            key_hint: self.key_hint.clone(),
+            encapped_key: self.encapped_key.clone(),
+            wrapped_key: self.wrapped_key.clone(),
+        }
+    }
+}
+
+/// A payload encrypted once under a random content-encryption key, along with a copy of that key
+/// for every recipient. This is what `multi_seal` produces and `multi_open` consumes.
+pub struct MultiRecipientCiphertext<Kem: KemTrait> {
+    /// One entry per recipient, in the order the recipients were given to `multi_seal`
+    pub recipients: Vec<RecipientEntry<Kem>>,
+    /// The payload, encrypted under the content-encryption key. This is the ciphertext followed
+    /// by its tag.
+    pub ciphertext: Vec<u8>,
+}
+
+impl<Kem: KemTrait> Clone for MultiRecipientCiphertext<Kem> {
+    fn clone(&self) -> MultiRecipientCiphertext<Kem> {
+        MultiRecipientCiphertext {
+            recipients: self.recipients.clone(),
+            ciphertext: self.ciphertext.clone(),
+        }
+    }
+}
+
+// The wire format is
+//   struct {
+//     uint16 num_recipients;
+//     struct {
+//       opaque key_hint<0..255>;
+//       opaque enc[Nenc];
+//       opaque wrapped_key[Nk + Nt];
+//     } recipients[num_recipients];
+//     opaque ciphertext[remaining bytes];
+//   }
+// An empty key hint means no hint was given.
+
+impl<Kem: KemTrait> MultiRecipientCiphertext<Kem> {
+    /// Serializes this ciphertext to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        // The number of recipients is checked in multi_seal, so this can't truncate unless the
+        // caller constructed a bad value by hand
+        let mut num_recipients = [0u8; 2];
+        write_u16_be(&mut num_recipients, self.recipients.len() as u16);
+        out.extend_from_slice(&num_recipients);
+
+        for entry in &self.recipients {
+            let hint = entry.key_hint.as_deref().unwrap_or(&[]);
+            out.push(hint.len() as u8);
+            out.extend_from_slice(hint);
+            out.extend_from_slice(&entry.encapped_key.to_bytes());
+            out.extend_from_slice(&entry.wrapped_key);
+        }
+
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    /// Deserializes a ciphertext that was serialized with `to_bytes`. `A` must be the AEAD that
+    /// the ciphertext was sealed with, since it determines the size of each wrapped key.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext)` on success. If `encoded` is truncated, returns
+    /// `Err(HpkeError::ValidationError)`. If an encapsulated key fails to deserialize, returns the
+    /// error from `EncappedKey::from_bytes`.
+    pub fn from_bytes<A: Aead>(encoded: &[u8]) -> Result<Self, HpkeError> {
+        let enc_len = Kem::EncappedKey::size();
+        let wrapped_len = wrapped_key_size::<A>();
+
+        let (num_recipients, mut rest) = split_checked(encoded, 2)?;
+        let num_recipients = u16::from_be_bytes([num_recipients[0], num_recipients[1]]);
+
+        let mut recipients = Vec::with_capacity(num_recipients as usize);
+        for _ in 0..num_recipients {
+            let (hint_len, r) = split_checked(rest, 1)?;
+            let (hint, r) = split_checked(r, hint_len[0] as usize)?;
+            let (enc, r) = split_checked(r, enc_len)?;
+            let (wrapped_key, r) = split_checked(r, wrapped_len)?;
+            rest = r;
+
+            recipients.push(RecipientEntry {
+                key_hint: if hint.is_empty() {
+                    None
+                } else {
+                    Some(hint.to_vec())
+                },
+                encapped_key: Kem::EncappedKey::from_bytes(enc)?,
+                wrapped_key: wrapped_key.to_vec(),
+            });
+        }
+
+        Ok(MultiRecipientCiphertext {
+            recipients,
+            ciphertext: rest.to_vec(),
+        })
+    }
+}
+
+/// The size of a sealed content-encryption key, i.e., `Nk + Nt`
+fn wrapped_key_size<A: Aead>() -> usize {
+    AeadKey::<A>::default().0.len() + AeadTag::<A>::size()
+}
+
+// COSE_Encrypt and JWE's JSON serialization wrap their CEK the same way `multi_seal` does, so
+// these helpers are shared with them.
+
+/// Picks a fresh random content-encryption key (CEK)
+pub(crate) fn random_cek<A: Aead, R: CryptoRng + RngCore>(csprng: &mut R) -> AeadKey<A> {
+    let mut cek = AeadKey::<A>::default();
+    csprng.fill_bytes(cek.0.as_mut_slice());
+    cek
+}
+
+/// Seals `cek` to `pk_recip`. Returns the encapsulated key and the wrapped key, which is the key
+/// ciphertext followed by its tag.
+pub(crate) fn wrap_cek<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem>,
+    pk_recip: &Kem::PublicKey,
+    info: &[u8],
+    aad: &[u8],
+    cek: &AeadKey<A>,
+    csprng: &mut R,
+) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let (encapped_key, mut ctx) = setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+    let wrapped_key = ctx.seal(&cek.0, aad)?;
+    Ok((encapped_key, wrapped_key))
+}
+
+/// Opens a key made by `wrap_cek`. The decrypted bytes are zeroized once they're copied into the
+/// returned key, which zeroizes itself on drop.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(cek)` on success. If decapsulation or decryption fails, returns that error. If the
+/// decrypted key is the wrong length, returns `Err(HpkeError::OpenError)`. Callers searching for
+/// their entry should treat any error as "not mine".
+pub(crate) fn unwrap_cek<A, Kdf, Kem>(
+    mode: &OpModeR<Kem>,
+    sk_recip: &Kem::PrivateKey,
+    encapped_key: &Kem::EncappedKey,
+    info: &[u8],
+    aad: &[u8],
+    wrapped_key: &[u8],
+) -> Result<AeadKey<A>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mut ctx = setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info)?;
+    let mut key_bytes = ctx.open(wrapped_key, aad)?;
+
+    let mut cek = AeadKey::<A>::default();
+    let key_fits = key_bytes.len() == cek.0.len();
+    if key_fits {
+        cek.0.copy_from_slice(&key_bytes);
+    }
+    key_bytes.zeroize();
+
+    if key_fits {
+        Ok(cek)
+    } else {
+        Err(HpkeError::OpenError)
+    }
+}
+
+/// Encrypts `plaintext` once under a fresh random content-encryption key (CEK), then seals the CEK
+/// to every public key in `recipients` using `setup_sender`. Each recipient is given alongside an
+/// optional key hint, which is copied into their entry so that they can find it without trial
+/// decryption. `mode`, `info` and `aad` are shared by all recipients, and all of them are needed
+/// to open the result.
+///
+/// Every recipient learns the CEK, so any one of them can re-encrypt a different payload for the
+/// others. If the other recipients need to know who produced the payload, sign it.
+///
+/// This cannot be used with `ExportOnlyAead`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(ciphertext)` on success. If there are more than 65535 recipients or a key hint is
+/// longer than 255 bytes, returns `Err(HpkeError::ValidationError)`. If an error happened during
+/// key encapsulation, returns `Err(HpkeError::EncapError)`. If an error happened during
+/// encryption, returns `Err(HpkeError::SealError)`.
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub fn multi_seal<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem>,
+    recipients: &[(&Kem::PublicKey, Option<&[u8]>)],
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<MultiRecipientCiphertext<Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    if recipients.len() > u16::MAX as usize {
+        return Err(HpkeError::ValidationError);
+    }
+    if recipients
+        .iter()
+        .any(|(_, hint)| matches!(hint, Some(h) if h.len() > u8::MAX as usize))
+    {
+        return Err(HpkeError::ValidationError);
+    }
+
+    // Pick a random CEK and encrypt the payload under it. The CEK is only ever used for this one
+    // message, so the all-zero nonce is fine.
+    let cek = random_cek::<A, R>(csprng);
+    let ciphertext = {
+        let nonce = AeadNonce::<A>::default();
+        let mut buf = plaintext.to_vec();
+        let tag = <A::AeadImpl as BaseKeyInit>::new(&cek.0)
+            .encrypt_in_place_detached(&nonce.0, aad, &mut buf)
+            .map_err(|_| HpkeError::SealError)?;
+        buf.extend_from_slice(&tag);
+        buf
+    };
+
+    // Now seal the CEK to every recipient
+    let mut entries = Vec::with_capacity(recipients.len());
+    for (pk_recip, key_hint) in recipients {
+        let (encapped_key, wrapped_key) =
+            wrap_cek::<A, Kdf, Kem, R>(mode, pk_recip, info, aad, &cek, csprng)?;
+        entries.push(RecipientEntry {
+            key_hint: key_hint.map(|h| h.to_vec()),
+            encapped_key,
+            wrapped_key,
+        });
+    }
+
+    Ok(MultiRecipientCiphertext {
+        recipients: entries,
+        ciphertext,
+    })
+}
+
+/// Finds the entry in `ciphertext` that was sealed to `sk_recip`, unwraps the content-encryption
+/// key, and decrypts the payload. If `key_hint` is given, only entries with a matching hint, or
+/// with no hint at all, are tried. Otherwise every entry is tried in order.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If no entry could be opened with `sk_recip`, or the payload
+/// failed to decrypt, returns `Err(HpkeError::OpenError)`.
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub fn multi_open<A, Kdf, Kem>(
+    mode: &OpModeR<Kem>,
+    sk_recip: &Kem::PrivateKey,
+    key_hint: Option<&[u8]>,
+    info: &[u8],
+    ciphertext: &MultiRecipientCiphertext<Kem>,
+    aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // Skip the entries whose hint rules them out
+    let candidates =
+        ciphertext
+            .recipients
+            .iter()
+            .filter(|entry| match (key_hint, entry.key_hint.as_deref()) {
+                (Some(ours), Some(theirs)) => ours == theirs,
+                _ => true,
+            });
+
+    // Try to unwrap the CEK from each candidate. The wrapped key is authenticated, so a successful
+    // unwrap means we found our entry. Any failure, including in decapsulation, just means the
+    // entry isn't ours.
+    let cek = candidates
+        .filter_map(|entry| {
+            unwrap_cek::<A, Kdf, Kem>(
+                mode,
+                sk_recip,
+                &entry.encapped_key,
+                info,
+                aad,
+                &entry.wrapped_key,
+            )
+            .ok()
+        })
+        .next()
+        .ok_or(HpkeError::OpenError)?;
+
+    // Now decrypt the payload with the CEK
+    let tag_len = AeadTag::<A>::size();
+    let msg_len = ciphertext
+        .ciphertext
+        .len()
+        .checked_sub(tag_len)
+        .ok_or(HpkeError::OpenError)?;
+    let (ct, tag) = ciphertext.ciphertext.split_at(msg_len);
+    let tag = AeadTag::<A>::from_bytes(tag)?;
+
+    let nonce = AeadNonce::<A>::default();
+    let mut buf = ct.to_vec();
+    <A::AeadImpl as BaseKeyInit>::new(&cek.0)
+        .decrypt_in_place_detached(&nonce.0, aad, &mut buf, &tag.0)
+        .map_err(|_| HpkeError::OpenError)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that every recipient can open a `multi_seal` ciphertext, with and without key hints,
+    /// and that a non-recipient cannot
+    macro_rules! test_multi_recipient_correctness {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let msg = b"a signed PSBT, probably";
+                let aad = b"cosigner round 2";
+                let info = b"multi recipient test";
+
+                // Three recipients, the middle one without a hint
+                let keypairs = [
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                ];
+                let hints: [Option<&[u8]>; 3] = [Some(b"alice"), None, Some(b"carol")];
+                let recipients: Vec<_> = keypairs
+                    .iter()
+                    .zip(hints.iter())
+                    .map(|((_, pk), hint)| (pk, *hint))
+                    .collect();
+
+                let ciphertext = multi_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &recipients,
+                    info,
+                    msg,
+                    aad,
+                    &mut csprng,
+                )
+                .unwrap();
+
+                // Send it over the wire
+                let ciphertext =
+                    MultiRecipientCiphertext::<Kem>::from_bytes::<A>(&ciphertext.to_bytes())
+                        .unwrap();
+
+                // Everyone should be able to open it, with their hint or without
+                for ((sk, _), hint) in keypairs.iter().zip(hints.iter()) {
+                    for h in [*hint, None] {
+                        let plaintext = multi_open::<A, Kdf, Kem>(
+                            &OpModeR::Base,
+                            sk,
+                            h,
+                            info,
+                            &ciphertext,
+                            aad,
+                        )
+                        .unwrap();
+                        assert_eq!(plaintext, msg);
+                    }
+                }
+
+                // Someone who isn't a recipient can't open it
+                let (bad_sk, _) = Kem::gen_keypair(&mut csprng);
+                assert_eq!(
+                    multi_open::<A, Kdf, Kem>(
+                        &OpModeR::Base,
+                        &bad_sk,
+                        None,
+                        info,
+                        &ciphertext,
+                        aad
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+
+                // A recipient with a hint that doesn't match their own entry can't open it
+                assert_eq!(
+                    multi_open::<A, Kdf, Kem>(
+                        &OpModeR::Base,
+                        &keypairs[0].0,
+                        Some(b"carol"),
+                        info,
+                        &ciphertext,
+                        aad
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "secp")]
+    test_multi_recipient_correctness!(
+        test_multi_recipient_correctness_secp,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::SecpK256HkdfSha256
+    );
+
+    /// Tests that truncated encodings are rejected rather than panicking
+    #[cfg(feature = "secp")]
+    #[test]
+    fn test_multi_recipient_truncated() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = crate::kem::SecpK256HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (_, pk) = Kem::gen_keypair(&mut csprng);
+        let ciphertext = multi_seal::<A, Kdf, Kem, _>(
+            &OpModeS::Base,
+            &[(&pk, Some(b"hint"))],
+            b"",
+            b"msg",
+            b"",
+            &mut csprng,
+        )
+        .unwrap();
+
+        // Cutting anywhere inside the recipient list must fail
+        let encoded = ciphertext.to_bytes();
+        let header_len = encoded.len() - ciphertext.ciphertext.len();
+        for len in 0..header_len {
+            assert!(MultiRecipientCiphertext::<Kem>::from_bytes::<A>(&encoded[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,705 @@
+//! Oblivious DNS over HTTPS (RFC 9230) message encryption
+//!
+//! A target resolver publishes [`ObliviousDoHConfig`]s. A client uses one to encrypt a DNS query
+//! with [`encrypt_query`], and gets back a [`ClientResponse`] for decrypting the answer. The
+//! target decrypts the query with [`Target::decrypt_query`], and answers with the returned
+//! [`ServerResponse`]. Queries and responses travel as [`ObliviousDoHMessage`]s through a proxy
+//! that sees neither the DNS messages nor, thanks to the proxy, the client's address at the
+//! target.
+//!
+//! The test vectors in RFC 9230 use X25519 and AES-128-GCM, which this crate doesn't implement, so
+//! only the secp256k1 KEM with the KDFs and AEADs here can be used.
+
+use crate::{
+    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce, AeadTag},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::{Kdf as KdfTrait, SimpleHkdf},
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender, ExporterSecret},
+    util::{split_checked, split_u16_prefixed, split_versioned, write_u16_prefixed},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use digest::OutputSizeUser;
+use generic_array::typenum::Unsigned;
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The media type of an Oblivious DoH message
+pub const MEDIA_TYPE: &str = "application/oblivious-dns-message";
+
+/// The only config version defined by RFC 9230
+pub const ODOH_VERSION: u16 = 0x0001;
+
+/// The info string of queries
+const QUERY_LABEL: &[u8] = b"odoh query";
+/// The label for the exporter secret of responses
+const RESPONSE_LABEL: &[u8] = b"odoh response";
+/// The label for deriving a config's key ID
+const KEY_ID_LABEL: &[u8] = b"odoh key id";
+
+/// A target's key configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObliviousDoHConfig {
+    /// The HPKE KDF ID
+    pub kdf_id: u16,
+    /// The HPKE AEAD ID
+    pub aead_id: u16,
+    /// The target's public key
+    pub public_key: PublicKey,
+}
+
+// RFC 9230 §6.1
+// struct {
+//    uint16 kem_id;
+//    uint16 kdf_id;
+//    uint16 aead_id;
+//    opaque public_key<1..2^16-1>;
+// } ObliviousDoHConfigContents;
+//
+// struct {
+//    uint16 version;
+//    uint16 length;
+//    select (ObliviousDoHConfig.version) {
+//       case 0x0001: ObliviousDoHConfigContents contents;
+//    }
+// } ObliviousDoHConfig;
+//
+// ObliviousDoHConfig ObliviousDoHConfigs<1..2^16-1>;
+//
+// RFC 9230 §6.2
+// key_id = Expand(Extract("", config), "odoh key id", Nh)
+
+impl ObliviousDoHConfig {
+    /// Returns the config for `public_key` with the suite `(Kdf, A)`
+    pub fn new<A: Aead, Kdf: KdfTrait>(public_key: PublicKey) -> ObliviousDoHConfig {
+        ObliviousDoHConfig {
+            kdf_id: Kdf::KDF_ID,
+            aead_id: A::AEAD_ID,
+            public_key,
+        }
+    }
+
+    /// Returns whether this config is for the suite `(Kdf, A)`
+    pub fn supports<A: Aead, Kdf: KdfTrait>(&self) -> bool {
+        self.kdf_id == Kdf::KDF_ID && self.aead_id == A::AEAD_ID
+    }
+
+    /// Serializes the `ObliviousDoHConfigContents`
+    fn contents_to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&Kem::KEM_ID.to_be_bytes());
+        out.extend_from_slice(&self.kdf_id.to_be_bytes());
+        out.extend_from_slice(&self.aead_id.to_be_bytes());
+        write_u16_prefixed(&mut out, &self.public_key.to_bytes());
+        out
+    }
+
+    /// Computes the key ID that queries to this config carry
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(key_id)` on success. If `Kdf` isn't the config's KDF, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn key_id<Kdf: KdfTrait>(&self) -> Result<Vec<u8>, HpkeError> {
+        if self.kdf_id != Kdf::KDF_ID {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let hkdf_ctx = SimpleHkdf::<Kdf>::new(None, &self.contents_to_bytes());
+        let mut key_id = vec![0u8; <Kdf::HashImpl as OutputSizeUser>::OutputSize::USIZE];
+        // This only fails if the output is 255x the digest size, which it never is
+        hkdf_ctx
+            .expand(KEY_ID_LABEL, &mut key_id)
+            .expect("key ID len is way too big");
+        Ok(key_id)
+    }
+
+    /// Serializes this config, with its version and length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let contents = self.contents_to_bytes();
+        let mut out = Vec::with_capacity(2 + 2 + contents.len());
+        out.extend_from_slice(&ODOH_VERSION.to_be_bytes());
+        write_u16_prefixed(&mut out, &contents);
+        out
+    }
+
+    /// Deserializes a single config
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(config)` on success. If the config is malformed, has trailing bytes, is for a
+    /// version other than [`ODOH_VERSION`], or is for a KEM other than secp256k1, returns an
+    /// error.
+    pub fn from_bytes(encoded: &[u8]) -> Result<ObliviousDoHConfig, HpkeError> {
+        let (version, contents, _, rest) = split_versioned(encoded)?;
+        if version != ODOH_VERSION || !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (ids, rest) = split_checked(contents, 6)?;
+        let (public_key, rest) = split_u16_prefixed(rest)?;
+        if u16::from_be_bytes([ids[0], ids[1]]) != Kem::KEM_ID || !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        Ok(ObliviousDoHConfig {
+            kdf_id: u16::from_be_bytes([ids[2], ids[3]]),
+            aead_id: u16::from_be_bytes([ids[4], ids[5]]),
+            public_key: PublicKey::from_bytes(public_key)?,
+        })
+    }
+
+    /// Serializes a list of configs as `ObliviousDoHConfigs`
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `configs` is empty or encodes to more than 65535 bytes
+    pub fn encode_list(configs: &[ObliviousDoHConfig]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for config in configs {
+            list.extend_from_slice(&config.to_bytes());
+        }
+        assert!(
+            (1..=u16::MAX as usize).contains(&list.len()),
+            "config list needs 1 to 65535 bytes"
+        );
+
+        let mut out = Vec::with_capacity(2 + list.len());
+        write_u16_prefixed(&mut out, &list);
+        out
+    }
+
+    /// Deserializes an `ObliviousDoHConfigs` list. Configs for other versions or KEMs are
+    /// skipped, since targets may offer several.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(configs)` on success. If the list is malformed or has trailing bytes, returns
+    /// an error.
+    pub fn decode_list(encoded: &[u8]) -> Result<Vec<ObliviousDoHConfig>, HpkeError> {
+        let (list, trailing) = split_u16_prefixed(encoded)?;
+        if list.is_empty() || !trailing.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let mut configs = Vec::new();
+        let mut rest = list;
+        while !rest.is_empty() {
+            let (version, contents, config, r) = split_versioned(rest)?;
+            rest = r;
+
+            // RFC 9230 §6.1 has clients skip configs whose version they don't support. Contents
+            // start with the KEM ID, and we only have one KEM. Contents too short for a KEM ID
+            // aren't skipped, so they fail to parse.
+            let other_kem = contents.len() >= 2
+                && u16::from_be_bytes([contents[0], contents[1]]) != Kem::KEM_ID;
+            if version != ODOH_VERSION || other_kem {
+                continue;
+            }
+            configs.push(ObliviousDoHConfig::from_bytes(config)?);
+        }
+        Ok(configs)
+    }
+}
+
+// RFC 9230 §6.3
+// struct {
+//    opaque dns_message<1..2^16-1>;
+//    opaque padding<0..2^16-1>;
+// } ObliviousDoHMessagePlaintext;
+
+/// Encodes `dns_message` as an `ObliviousDoHMessagePlaintext` with `padding_len` zero bytes of
+/// padding. `overhead` is how much encryption will add, so that the result still fits in
+/// `encrypted_message<1..2^16-1>` once sealed.
+fn encode_plaintext(
+    dns_message: &[u8],
+    padding_len: u16,
+    overhead: usize,
+) -> Result<Vec<u8>, HpkeError> {
+    let encrypted_len = 2 + dns_message.len() + 2 + padding_len as usize + overhead;
+    if dns_message.is_empty() || encrypted_len > u16::MAX as usize {
+        return Err(HpkeError::ValidationError);
+    }
+
+    let mut out = Vec::with_capacity(2 + dns_message.len() + 2 + padding_len as usize);
+    write_u16_prefixed(&mut out, dns_message);
+    out.extend_from_slice(&padding_len.to_be_bytes());
+    out.resize(out.len() + padding_len as usize, 0);
+    Ok(out)
+}
+
+/// Decodes an `ObliviousDoHMessagePlaintext`, returning its DNS message. Padding must be all
+/// zeros.
+fn decode_plaintext(plaintext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+    let (dns_message, rest) = split_u16_prefixed(plaintext)?;
+    let (padding, rest) = split_u16_prefixed(rest)?;
+    if dns_message.is_empty() || !rest.is_empty() || padding.iter().any(|&b| b != 0) {
+        return Err(HpkeError::ValidationError);
+    }
+    Ok(dns_message.to_vec())
+}
+
+/// The type of an [`ObliviousDoHMessage`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    /// An encrypted DNS query
+    Query = 0x01,
+    /// An encrypted DNS response
+    Response = 0x02,
+}
+
+/// An encrypted query or response, as sent between client, proxy, and target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObliviousDoHMessage {
+    /// Whether this is a query or a response
+    pub message_type: MessageType,
+    /// For queries, the key ID of the target's config. For responses, the response nonce.
+    pub key_id: Vec<u8>,
+    /// The encrypted `ObliviousDoHMessagePlaintext`
+    pub encrypted_message: Vec<u8>,
+}
+
+// RFC 9230 §6.3
+// struct {
+//    uint8  message_type;
+//    opaque key_id<0..2^16-1>;
+//    opaque encrypted_message<1..2^16-1>;
+// } ObliviousDoHMessage;
+
+impl ObliviousDoHMessage {
+    /// Computes the AAD that binds the encrypted message to its type and key ID
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + 2 + self.key_id.len());
+        aad.push(self.message_type as u8);
+        write_u16_prefixed(&mut aad, &self.key_id);
+        aad
+    }
+
+    /// Serializes this message
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `key_id` is longer than 65535 bytes, or `encrypted_message` is empty or longer
+    /// than 65535 bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(
+            self.key_id.len() <= u16::MAX as usize,
+            "key ID can be at most 65535 bytes"
+        );
+        assert!(
+            (1..=u16::MAX as usize).contains(&self.encrypted_message.len()),
+            "encrypted message needs 1 to 65535 bytes"
+        );
+
+        let mut out =
+            Vec::with_capacity(1 + 2 + self.key_id.len() + 2 + self.encrypted_message.len());
+        out.push(self.message_type as u8);
+        write_u16_prefixed(&mut out, &self.key_id);
+        write_u16_prefixed(&mut out, &self.encrypted_message);
+        out
+    }
+
+    /// Deserializes a message
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(message)` on success. If the message is malformed, has an unknown type, or has
+    /// trailing bytes, returns `Err(HpkeError::ValidationError)`.
+    pub fn from_bytes(encoded: &[u8]) -> Result<ObliviousDoHMessage, HpkeError> {
+        let (message_type, rest) = split_checked(encoded, 1)?;
+        let message_type = match message_type[0] {
+            0x01 => MessageType::Query,
+            0x02 => MessageType::Response,
+            _ => return Err(HpkeError::ValidationError),
+        };
+        let (key_id, rest) = split_u16_prefixed(rest)?;
+        let (encrypted_message, rest) = split_u16_prefixed(rest)?;
+        if encrypted_message.is_empty() || !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        Ok(ObliviousDoHMessage {
+            message_type,
+            key_id: key_id.to_vec(),
+            encrypted_message: encrypted_message.to_vec(),
+        })
+    }
+}
+
+// RFC 9230 §6.4
+// def derive_secrets(context, Q_plain, resp_nonce):
+//   secret = context.Export("odoh response", Nk)
+//   salt = Q_plain || len(resp_nonce) || resp_nonce
+//   prk = Extract(salt, secret)
+//   key = Expand(odoh_prk, "odoh key", Nk)
+//   nonce = Expand(odoh_prk, "odoh nonce", Nn)
+//   return key, nonce
+
+/// Derives the single-use context for a response, given the secret exported from the query's
+/// context, the encoded query plaintext, and the response nonce
+fn response_ctx<A: Aead, Kdf: KdfTrait>(
+    secret: &AeadKey<A>,
+    query_plain: &[u8],
+    resp_nonce: &[u8],
+) -> AeadCtx<A, Kdf, Kem> {
+    let mut salt = Vec::with_capacity(query_plain.len() + 2 + resp_nonce.len());
+    salt.extend_from_slice(query_plain);
+    write_u16_prefixed(&mut salt, resp_nonce);
+    let hkdf_ctx = SimpleHkdf::<Kdf>::new(Some(&salt), secret.0.as_slice());
+
+    // These only fail if the output is 255x the digest size, which these never are
+    let mut key = AeadKey::<A>::default();
+    let mut nonce = AeadNonce::<A>::default();
+    hkdf_ctx
+        .expand(b"odoh key", key.0.as_mut_slice())
+        .expect("aead key len is way too big");
+    hkdf_ctx
+        .expand(b"odoh nonce", nonce.0.as_mut_slice())
+        .expect("nonce len is way too big");
+
+    // The response context is sealed under once and never exported from
+    AeadCtx::new(&key, nonce, ExporterSecret::default())
+}
+
+/// What a client keeps after sending a query, to decrypt the response
+pub struct ClientResponse<A: Aead, Kdf: KdfTrait> {
+    ctx: AeadCtxS<A, Kdf, Kem>,
+    query_plain: Vec<u8>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> ClientResponse<A, Kdf> {
+    // RFC 9230 §6.4
+    // aad = 0x02 || len(resp_nonce) || resp_nonce
+    // R_plain, error = Open(key, nonce, aad, R_encrypted)
+
+    /// Decrypts the target's response
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(dns_message)` on success. If `response` isn't a response, has a response nonce
+    /// of the wrong length, or has a malformed plaintext, returns
+    /// `Err(HpkeError::ValidationError)`. If the response fails to decrypt, returns
+    /// `Err(HpkeError::OpenError)`.
+    pub fn decrypt_response(self, response: &ObliviousDoHMessage) -> Result<Vec<u8>, HpkeError> {
+        if response.message_type != MessageType::Response
+            || response.key_id.len() != crate::aead::response_nonce_len::<A>()
+        {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let mut secret = AeadKey::<A>::default();
+        self.ctx.export(RESPONSE_LABEL, secret.0.as_mut_slice())?;
+        let mut ctx: AeadCtxR<A, Kdf, Kem> =
+            response_ctx(&secret, &self.query_plain, &response.key_id).into();
+        let plaintext = ctx.open(&response.encrypted_message, &response.aad())?;
+        decode_plaintext(&plaintext)
+    }
+}
+
+// RFC 9230 §6.4
+// def encrypt_query_body(pkR, key_id, Q_plain):
+//   enc, context = SetupBaseS(pkR, "odoh query")
+//   aad = 0x01 || len(key_id) || key_id
+//   ct = context.Seal(aad, Q_plain)
+//   Q_encrypted = enc || ct
+//   return Q_encrypted
+
+/// Encrypts `dns_message`, padded with `padding_len` zero bytes, to the target with config
+/// `config`
+///
+/// Return Value
+/// ============
+/// On success, returns the query message and the state needed to read the response. If
+/// `config` isn't for `(Kdf, A)`, `dns_message` is empty, or the encrypted query would be longer
+/// than 65535 bytes, returns `Err(HpkeError::ValidationError)`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`.
+pub fn encrypt_query<A, Kdf, R>(
+    config: &ObliviousDoHConfig,
+    dns_message: &[u8],
+    padding_len: u16,
+    csprng: &mut R,
+) -> Result<(ObliviousDoHMessage, ClientResponse<A, Kdf>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    if !config.supports::<A, Kdf>() {
+        return Err(HpkeError::ValidationError);
+    }
+
+    // The encrypted query is enc || ct
+    let overhead = EncappedKey::size() + AeadTag::<A>::size();
+    let query_plain = encode_plaintext(dns_message, padding_len, overhead)?;
+    let mut query = ObliviousDoHMessage {
+        message_type: MessageType::Query,
+        key_id: config.key_id::<Kdf>()?,
+        encrypted_message: Vec::new(),
+    };
+    let (encapped_key, mut ctx) =
+        setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, &config.public_key, QUERY_LABEL, csprng)?;
+    let ct = ctx.seal(&query_plain, &query.aad())?;
+
+    query.encrypted_message = Vec::with_capacity(EncappedKey::size() + ct.len());
+    query
+        .encrypted_message
+        .extend_from_slice(&encapped_key.to_bytes());
+    query.encrypted_message.extend_from_slice(&ct);
+
+    Ok((query, ClientResponse { ctx, query_plain }))
+}
+
+/// An ODoH target's key
+pub struct Target {
+    config: ObliviousDoHConfig,
+    sk: PrivateKey,
+}
+
+impl Target {
+    /// Makes a target from its config and the private key matching `config.public_key`
+    pub fn new(config: ObliviousDoHConfig, sk: PrivateKey) -> Target {
+        Target { config, sk }
+    }
+
+    /// Returns the target's config, for publishing
+    pub fn config(&self) -> &ObliviousDoHConfig {
+        &self.config
+    }
+
+    // RFC 9230 §6.5
+    // def decrypt_query_body(skR, key_id, Q_encrypted):
+    //   enc || ct = Q_encrypted
+    //   context = SetupBaseR(enc, skR, "odoh query")
+    //   aad = 0x01 || len(key_id) || key_id
+    //   Q_plain, error = context.Open(aad, ct)
+    //   return Q_plain, error
+
+    /// Decrypts a query. The target's config decides `(Kdf, A)`.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the DNS query and the state needed to answer it. If `query` isn't a
+    /// query, is for a different key, or has a malformed plaintext, or the config isn't for
+    /// `(Kdf, A)`, returns `Err(HpkeError::ValidationError)`. If an error happened during key
+    /// decapsulation, returns `Err(HpkeError::DecapError)`. If the query fails to decrypt,
+    /// returns `Err(HpkeError::OpenError)`.
+    pub fn decrypt_query<A, Kdf>(
+        &self,
+        query: &ObliviousDoHMessage,
+    ) -> Result<(Vec<u8>, ServerResponse<A, Kdf>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        if query.message_type != MessageType::Query
+            || !self.config.supports::<A, Kdf>()
+            || query.key_id != self.config.key_id::<Kdf>()?
+        {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (enc, ct) = split_checked(&query.encrypted_message, EncappedKey::size())?;
+        let encapped_key = EncappedKey::from_bytes(enc)?;
+        let mut ctx =
+            setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &self.sk, &encapped_key, QUERY_LABEL)?;
+        let query_plain = ctx.open(ct, &query.aad())?;
+        let dns_message = decode_plaintext(&query_plain)?;
+
+        Ok((dns_message, ServerResponse { ctx, query_plain }))
+    }
+}
+
+/// What a target keeps after decrypting a query, to encrypt the response
+pub struct ServerResponse<A: Aead, Kdf: KdfTrait> {
+    ctx: AeadCtxR<A, Kdf, Kem>,
+    query_plain: Vec<u8>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> ServerResponse<A, Kdf> {
+    // RFC 9230 §6.4
+    // def encrypt_response_body(R_plain, aead_key, aead_nonce, resp_nonce):
+    //   aad = 0x02 || len(resp_nonce) || resp_nonce
+    //   R_encrypted = Seal(aead_key, aead_nonce, aad, R_plain)
+    //   return R_encrypted
+    //
+    // resp_nonce = random(max(Nn, Nk))
+
+    /// Encrypts `dns_message`, padded with `padding_len` zero bytes, as the response to the query
+    /// this came from
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(response)` on success. If `dns_message` is empty or the encrypted response
+    /// would be longer than 65535 bytes, returns `Err(HpkeError::ValidationError)`. If an error
+    /// happened during encryption, returns `Err(HpkeError::SealError)`.
+    pub fn encrypt_response<R: CryptoRng + RngCore>(
+        self,
+        dns_message: &[u8],
+        padding_len: u16,
+        csprng: &mut R,
+    ) -> Result<ObliviousDoHMessage, HpkeError> {
+        let response_plain = encode_plaintext(dns_message, padding_len, AeadTag::<A>::size())?;
+
+        let mut resp_nonce = vec![0u8; crate::aead::response_nonce_len::<A>()];
+        csprng.fill_bytes(&mut resp_nonce);
+        let mut secret = AeadKey::<A>::default();
+        self.ctx.export(RESPONSE_LABEL, secret.0.as_mut_slice())?;
+
+        let mut response = ObliviousDoHMessage {
+            message_type: MessageType::Response,
+            key_id: resp_nonce,
+            encrypted_message: Vec::new(),
+        };
+        let mut ctx: AeadCtxS<A, Kdf, Kem> =
+            response_ctx(&secret, &self.query_plain, &response.key_id).into();
+        response.encrypted_message = ctx.seal(&response_plain, &response.aad())?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::{HkdfSha256, HkdfSha384},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests a query/response round trip through published configs, the message and plaintext
+    /// encodings, and that tampered or misdirected messages are refused
+    #[test]
+    fn test_odoh_round_trip() {
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let target = Target::new(ObliviousDoHConfig::new::<A, Kdf>(pk), sk);
+
+        // The client learns the config from a list that also has a config for a future version
+        let config = target.config().to_bytes();
+        let mut future = config.clone();
+        future[..2].copy_from_slice(&0xff01u16.to_be_bytes());
+        let mut list = Vec::new();
+        write_u16_prefixed(&mut list, &[future, config].concat());
+        let configs = ObliviousDoHConfig::decode_list(&list).unwrap();
+        assert_eq!(configs, vec![target.config().clone()]);
+        assert_eq!(
+            ObliviousDoHConfig::decode_list(&ObliviousDoHConfig::encode_list(&configs)).unwrap(),
+            configs
+        );
+
+        // The key ID is a digest-length hash of the config contents
+        let key_id = configs[0].key_id::<Kdf>().unwrap();
+        assert_eq!(key_id.len(), 32);
+        assert!(configs[0].key_id::<HkdfSha384>().is_err());
+
+        // Padding is zeros, and nonzero padding is refused
+        let plaintext = encode_plaintext(b"q", 3, 0).unwrap();
+        assert_eq!(plaintext, [0, 1, b'q', 0, 3, 0, 0, 0]);
+        assert_eq!(decode_plaintext(&plaintext).unwrap(), b"q");
+        let mut bad = plaintext.clone();
+        bad[7] = 1;
+        assert!(decode_plaintext(&bad).is_err());
+        assert!(encode_plaintext(b"", 0, 0).is_err());
+
+        let (query, client) =
+            encrypt_query::<A, Kdf, _>(&configs[0], b"seed.bitcoin.example A", 32, &mut csprng)
+                .unwrap();
+        assert_eq!(query.key_id, key_id);
+        let query = ObliviousDoHMessage::from_bytes(&query.to_bytes()).unwrap();
+
+        let (dns_query, server) = target.decrypt_query::<A, Kdf>(&query).unwrap();
+        assert_eq!(dns_query, b"seed.bitcoin.example A");
+        let response = server
+            .encrypt_response(b"203.0.113.7", 0, &mut csprng)
+            .unwrap();
+        assert_eq!(response.message_type, MessageType::Response);
+        assert_eq!(response.key_id.len(), 32);
+        let response = ObliviousDoHMessage::from_bytes(&response.to_bytes()).unwrap();
+        assert_eq!(client.decrypt_response(&response).unwrap(), b"203.0.113.7");
+
+        // Suites the target doesn't offer are refused on both ends
+        assert!(encrypt_query::<A, HkdfSha384, _>(&configs[0], b"q", 0, &mut csprng).is_err());
+        assert!(target.decrypt_query::<A, HkdfSha384>(&query).is_err());
+
+        // A query for another key ID, with a tampered ciphertext, or posing as a response is
+        // refused
+        let mut bad = query.clone();
+        bad.key_id[0] ^= 1;
+        assert!(target.decrypt_query::<A, Kdf>(&bad).is_err());
+        let mut bad = query.clone();
+        *bad.encrypted_message.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            target.decrypt_query::<A, Kdf>(&bad).err(),
+            Some(HpkeError::OpenError)
+        );
+        let mut bad = query.clone();
+        bad.message_type = MessageType::Response;
+        assert!(target.decrypt_query::<A, Kdf>(&bad).is_err());
+
+        // A response is bound to its query and its nonce
+        let (_, client) = encrypt_query::<A, Kdf, _>(&configs[0], b"q", 0, &mut csprng).unwrap();
+        let (_, server) = target.decrypt_query::<A, Kdf>(&query).unwrap();
+        let mut response = server.encrypt_response(b"r", 0, &mut csprng).unwrap();
+        response.key_id[0] ^= 1;
+        assert_eq!(
+            client.decrypt_response(&response),
+            Err(HpkeError::OpenError)
+        );
+
+        // Unknown message types don't parse
+        let mut bad = query.to_bytes();
+        bad[0] = 0x03;
+        assert!(ObliviousDoHMessage::from_bytes(&bad).is_err());
+    }
+
+    /// Tests that queries and responses too long for `encrypted_message<1..2^16-1>` are refused,
+    /// and that the longest ones that fit serialize
+    #[test]
+    fn test_odoh_message_limit() {
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let target = Target::new(ObliviousDoHConfig::new::<A, Kdf>(pk), sk);
+        let config = target.config().clone();
+        let tag_len = AeadTag::<A>::size();
+
+        // A query carries enc, the plaintext's two length prefixes, and the tag
+        let max_query = u16::MAX as usize - EncappedKey::size() - 4 - tag_len;
+        assert_eq!(
+            encrypt_query::<A, Kdf, _>(&config, &vec![1; u16::MAX as usize], 0, &mut csprng).err(),
+            Some(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            encrypt_query::<A, Kdf, _>(&config, &vec![1; max_query - 9], 10, &mut csprng).err(),
+            Some(HpkeError::ValidationError)
+        );
+        let (query, _) =
+            encrypt_query::<A, Kdf, _>(&config, &vec![1; max_query - 10], 10, &mut csprng).unwrap();
+        assert_eq!(query.encrypted_message.len(), u16::MAX as usize);
+        let query = ObliviousDoHMessage::from_bytes(&query.to_bytes()).unwrap();
+
+        // A response carries the plaintext's two length prefixes and the tag
+        let max_response = u16::MAX as usize - 4 - tag_len;
+        let (_, server) = target.decrypt_query::<A, Kdf>(&query).unwrap();
+        assert_eq!(
+            server
+                .encrypt_response(&vec![1; max_response + 1], 0, &mut csprng)
+                .err(),
+            Some(HpkeError::ValidationError)
+        );
+        let (_, server) = target.decrypt_query::<A, Kdf>(&query).unwrap();
+        let response = server
+            .encrypt_response(&vec![1; max_response], 0, &mut csprng)
+            .unwrap();
+        assert_eq!(response.encrypted_message.len(), u16::MAX as usize);
+        ObliviousDoHMessage::from_bytes(&response.to_bytes()).unwrap();
+    }
+}
@@ -0,0 +1,450 @@
+//! Oblivious HTTP (RFC 9458) request and response encapsulation
+//!
+//! A gateway publishes [`KeyConfig`]s. A client uses one to encapsulate a request with
+//! [`encapsulate_request`], and gets back a [`ClientResponse`] for decapsulating the answer. The
+//! gateway decapsulates the request with [`Gateway::decapsulate_request`], and answers with the
+//! returned [`ServerResponse`].
+//!
+//! The test vectors in RFC 9458 use X25519 and AES-128-GCM, which this crate doesn't implement, so
+//! only the secp256k1 KEM with the KDFs and AEADs here can be used.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    util::{split_checked, split_u16_prefixed, write_u16_prefixed},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The media type of an encapsulated request
+pub const REQUEST_MEDIA_TYPE: &str = "message/ohttp-req";
+/// The media type of an encapsulated response
+pub const RESPONSE_MEDIA_TYPE: &str = "message/ohttp-res";
+/// The media type of a list of key configurations
+pub const KEYS_MEDIA_TYPE: &str = "application/ohttp-keys";
+
+/// The label for the info string of requests
+const REQUEST_LABEL: &[u8] = b"message/bhttp request";
+/// The label for the exporter secret of responses
+const RESPONSE_LABEL: &[u8] = b"message/bhttp response";
+
+/// A KDF and AEAD pair that a gateway accepts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SymmetricSuite {
+    /// The HPKE KDF ID
+    pub kdf_id: u16,
+    /// The HPKE AEAD ID
+    pub aead_id: u16,
+}
+
+impl SymmetricSuite {
+    /// Returns the suite for `Kdf` and `A`
+    pub fn new<A: Aead, Kdf: KdfTrait>() -> SymmetricSuite {
+        SymmetricSuite {
+            kdf_id: Kdf::KDF_ID,
+            aead_id: A::AEAD_ID,
+        }
+    }
+}
+
+/// A gateway's key configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyConfig {
+    /// Identifies the key to the gateway
+    pub key_id: u8,
+    /// The gateway's public key
+    pub public_key: PublicKey,
+    /// The KDF and AEAD pairs the gateway accepts. This MUST NOT be empty.
+    pub symmetric: Vec<SymmetricSuite>,
+}
+
+// RFC 9458 §3.1
+// Key Config {
+//   Key Identifier (8),
+//   HPKE KEM ID (16),
+//   HPKE Public Key (Npk * 8),
+//   HPKE Symmetric Algorithms Length (16) = 4..65532,
+//   HPKE Symmetric Algorithms (32) = 4..65532,
+// }
+//
+// RFC 9458 §3.2: application/ohttp-keys is a sequence of key configs, each preceded by its length
+// as a 16-bit integer.
+
+impl KeyConfig {
+    /// Returns whether the gateway accepts the suite `(Kdf, A)`
+    pub fn supports<A: Aead, Kdf: KdfTrait>(&self) -> bool {
+        self.symmetric.contains(&SymmetricSuite::new::<A, Kdf>())
+    }
+
+    /// Serializes this configuration
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `symmetric` is empty or has more than 16383 entries
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sym_len = self.symmetric.len() * 4;
+        assert!(
+            (4..=65532).contains(&sym_len),
+            "key config needs 1 to 16383 symmetric suites"
+        );
+
+        let mut out = Vec::new();
+        out.push(self.key_id);
+        out.extend_from_slice(&Kem::KEM_ID.to_be_bytes());
+        out.extend_from_slice(&self.public_key.to_bytes());
+        out.extend_from_slice(&(sym_len as u16).to_be_bytes());
+        for suite in &self.symmetric {
+            out.extend_from_slice(&suite.kdf_id.to_be_bytes());
+            out.extend_from_slice(&suite.aead_id.to_be_bytes());
+        }
+        out
+    }
+
+    /// Deserializes a single configuration
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(config)` on success. If the configuration is malformed, has trailing bytes, or
+    /// is for a KEM other than secp256k1, returns an error.
+    pub fn from_bytes(encoded: &[u8]) -> Result<KeyConfig, HpkeError> {
+        let (key_id, rest) = split_checked(encoded, 1)?;
+        let (kem_id, rest) = split_checked(rest, 2)?;
+        if u16::from_be_bytes([kem_id[0], kem_id[1]]) != Kem::KEM_ID {
+            return Err(HpkeError::ValidationError);
+        }
+        let (public_key, rest) = split_checked(rest, PublicKey::size())?;
+        let (sym_len, rest) = split_checked(rest, 2)?;
+        let sym_len = u16::from_be_bytes([sym_len[0], sym_len[1]]) as usize;
+        let suites = rest.chunks_exact(4);
+        if sym_len < 4 || rest.len() != sym_len || !suites.remainder().is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let symmetric = suites
+            .map(|c| SymmetricSuite {
+                kdf_id: u16::from_be_bytes([c[0], c[1]]),
+                aead_id: u16::from_be_bytes([c[2], c[3]]),
+            })
+            .collect();
+        Ok(KeyConfig {
+            key_id: key_id[0],
+            public_key: PublicKey::from_bytes(public_key)?,
+            symmetric,
+        })
+    }
+
+    /// Serializes a list of configurations as `application/ohttp-keys`
+    pub fn encode_list(configs: &[KeyConfig]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for config in configs {
+            write_u16_prefixed(&mut out, &config.to_bytes());
+        }
+        out
+    }
+
+    /// Deserializes an `application/ohttp-keys` list. Configurations for other KEMs are skipped,
+    /// since gateways may offer several.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(configs)` on success. If the list is malformed, returns an error.
+    pub fn decode_list(encoded: &[u8]) -> Result<Vec<KeyConfig>, HpkeError> {
+        let mut configs = Vec::new();
+        let mut rest = encoded;
+        while !rest.is_empty() {
+            let (config, r) = split_u16_prefixed(rest)?;
+            rest = r;
+
+            // Skip KEMs we don't know, but not garbage
+            if config.len() >= 3 && u16::from_be_bytes([config[1], config[2]]) != Kem::KEM_ID {
+                continue;
+            }
+            configs.push(KeyConfig::from_bytes(config)?);
+        }
+        Ok(configs)
+    }
+}
+
+/// The header of an encapsulated request. A gateway can read this to pick the key and
+/// ciphersuite before decapsulating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    /// The ID of the gateway key the request is for
+    pub key_id: u8,
+    /// The HPKE KEM ID
+    pub kem_id: u16,
+    /// The HPKE KDF ID
+    pub kdf_id: u16,
+    /// The HPKE AEAD ID
+    pub aead_id: u16,
+}
+
+// RFC 9458 §4.3
+// hdr = concat(encode(1, key_id),
+//              encode(2, kem_id),
+//              encode(2, kdf_id),
+//              encode(2, aead_id))
+// info = concat(encode_str("message/bhttp request"),
+//               encode(1, 0),
+//               hdr)
+// enc, sctxt = SetupBaseS(pkR, info)
+// ct = sctxt.Seal("", request)
+// enc_request = concat(hdr, enc, ct)
+
+impl RequestHeader {
+    /// The length of a serialized header
+    const SIZE: usize = 7;
+
+    fn to_bytes(self) -> [u8; 7] {
+        let mut out = [0u8; 7];
+        out[0] = self.key_id;
+        out[1..3].copy_from_slice(&self.kem_id.to_be_bytes());
+        out[3..5].copy_from_slice(&self.kdf_id.to_be_bytes());
+        out[5..7].copy_from_slice(&self.aead_id.to_be_bytes());
+        out
+    }
+
+    /// Reads the header of an encapsulated request
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(header)` on success. If the request is too short to have a header, returns an
+    /// error.
+    pub fn from_request(enc_request: &[u8]) -> Result<RequestHeader, HpkeError> {
+        let (hdr, _) = split_checked(enc_request, Self::SIZE)?;
+        Ok(RequestHeader {
+            key_id: hdr[0],
+            kem_id: u16::from_be_bytes([hdr[1], hdr[2]]),
+            kdf_id: u16::from_be_bytes([hdr[3], hdr[4]]),
+            aead_id: u16::from_be_bytes([hdr[5], hdr[6]]),
+        })
+    }
+
+    /// Computes the HPKE info string for a request with this header
+    fn info(self) -> Vec<u8> {
+        let mut info = Vec::with_capacity(REQUEST_LABEL.len() + 1 + Self::SIZE);
+        info.extend_from_slice(REQUEST_LABEL);
+        info.push(0);
+        info.extend_from_slice(&self.to_bytes());
+        info
+    }
+}
+
+/// What a client keeps after sending a request, to decapsulate the response
+pub struct ClientResponse<A: Aead, Kdf: KdfTrait> {
+    ctx: AeadCtxS<A, Kdf, Kem>,
+    encapped_key: EncappedKey,
+}
+
+impl<A: Aead, Kdf: KdfTrait> ClientResponse<A, Kdf> {
+    // RFC 9458 §4.4
+    // enc_response = concat(response_nonce, ct)
+
+    /// Decapsulates the gateway's response
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(response)` on success. If the response is truncated or fails to decrypt,
+    /// returns an error.
+    pub fn decapsulate_response(self, enc_response: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let (response_nonce, ct) =
+            split_checked(enc_response, crate::aead::response_nonce_len::<A>())?;
+        let mut ctx =
+            self.ctx
+                .response_context(RESPONSE_LABEL, &self.encapped_key, response_nonce)?;
+        ctx.open(ct, b"")
+    }
+}
+
+/// Encapsulates `request` to the gateway with configuration `config`
+///
+/// Return Value
+/// ============
+/// On success, returns the encapsulated request and the state needed to read the response. If
+/// the gateway doesn't accept `(Kdf, A)`, returns `Err(HpkeError::ValidationError)`. If an error
+/// happened during key encapsulation, returns `Err(HpkeError::EncapError)`.
+pub fn encapsulate_request<A, Kdf, R>(
+    config: &KeyConfig,
+    request: &[u8],
+    csprng: &mut R,
+) -> Result<(Vec<u8>, ClientResponse<A, Kdf>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    if !config.supports::<A, Kdf>() {
+        return Err(HpkeError::ValidationError);
+    }
+
+    let hdr = RequestHeader {
+        key_id: config.key_id,
+        kem_id: Kem::KEM_ID,
+        kdf_id: Kdf::KDF_ID,
+        aead_id: A::AEAD_ID,
+    };
+    let (encapped_key, mut ctx) =
+        setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, &config.public_key, &hdr.info(), csprng)?;
+    let ct = ctx.seal(request, b"")?;
+
+    let mut enc_request = Vec::with_capacity(RequestHeader::SIZE + EncappedKey::size() + ct.len());
+    enc_request.extend_from_slice(&hdr.to_bytes());
+    enc_request.extend_from_slice(&encapped_key.to_bytes());
+    enc_request.extend_from_slice(&ct);
+
+    Ok((enc_request, ClientResponse { ctx, encapped_key }))
+}
+
+/// An OHTTP gateway's key
+pub struct Gateway {
+    config: KeyConfig,
+    sk: PrivateKey,
+}
+
+impl Gateway {
+    /// Makes a gateway from its configuration and the private key matching `config.public_key`
+    pub fn new(config: KeyConfig, sk: PrivateKey) -> Gateway {
+        Gateway { config, sk }
+    }
+
+    /// Returns the gateway's key configuration, for publishing
+    pub fn config(&self) -> &KeyConfig {
+        &self.config
+    }
+
+    /// Decapsulates a request. Use `RequestHeader::from_request` to find out which `(Kdf, A)` the
+    /// client picked.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the request and the state needed to answer it. If the request is for a
+    /// different key, a different suite than `(Kdf, A)`, or a suite the gateway doesn't accept,
+    /// returns `Err(HpkeError::ValidationError)`. If an error happened during key decapsulation,
+    /// returns `Err(HpkeError::DecapError)`. If the request fails to decrypt, returns
+    /// `Err(HpkeError::OpenError)`.
+    pub fn decapsulate_request<A, Kdf>(
+        &self,
+        enc_request: &[u8],
+    ) -> Result<(Vec<u8>, ServerResponse<A, Kdf>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        let hdr = RequestHeader::from_request(enc_request)?;
+        if hdr.key_id != self.config.key_id
+            || hdr.kem_id != Kem::KEM_ID
+            || hdr.kdf_id != Kdf::KDF_ID
+            || hdr.aead_id != A::AEAD_ID
+            || !self.config.supports::<A, Kdf>()
+        {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (enc, ct) = split_checked(&enc_request[RequestHeader::SIZE..], EncappedKey::size())?;
+        let encapped_key = EncappedKey::from_bytes(enc)?;
+        let mut ctx =
+            setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &self.sk, &encapped_key, &hdr.info())?;
+        let request = ctx.open(ct, b"")?;
+
+        Ok((request, ServerResponse { ctx, encapped_key }))
+    }
+}
+
+/// What a gateway keeps after decapsulating a request, to encapsulate the response
+pub struct ServerResponse<A: Aead, Kdf: KdfTrait> {
+    ctx: AeadCtxR<A, Kdf, Kem>,
+    encapped_key: EncappedKey,
+}
+
+impl<A: Aead, Kdf: KdfTrait> ServerResponse<A, Kdf> {
+    /// Encapsulates the response to the request this came from
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(enc_response)` on success. If an error happened during encryption, returns
+    /// `Err(HpkeError::SealError)`.
+    pub fn encapsulate_response<R: CryptoRng + RngCore>(
+        self,
+        response: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let (mut enc_response, mut ctx) =
+            self.ctx
+                .reply_context(RESPONSE_LABEL, &self.encapped_key, csprng)?;
+        enc_response.extend_from_slice(&ctx.seal(response, b"")?);
+        Ok(enc_response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::{HkdfSha256, HkdfSha384},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests a request/response round trip through published key configs, and that the gateway
+    /// rejects requests for other keys and suites
+    #[test]
+    fn test_ohttp_round_trip() {
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let gateway = Gateway::new(
+            KeyConfig {
+                key_id: 7,
+                public_key: pk,
+                symmetric: vec![SymmetricSuite::new::<A, Kdf>()],
+            },
+            sk,
+        );
+
+        // The client learns the config from a list that also has a config for another KEM
+        let mut foreign = KeyConfig::encode_list(&[gateway.config().clone()]);
+        foreign[3..5].copy_from_slice(&0x0020u16.to_be_bytes());
+        let mut keys = foreign;
+        keys.extend_from_slice(&KeyConfig::encode_list(&[gateway.config().clone()]));
+        let configs = KeyConfig::decode_list(&keys).unwrap();
+        assert_eq!(configs, vec![gateway.config().clone()]);
+
+        let (enc_request, client) =
+            encapsulate_request::<A, Kdf, _>(&configs[0], b"POST /payjoin", &mut csprng).unwrap();
+        let hdr = RequestHeader::from_request(&enc_request).unwrap();
+        assert_eq!((hdr.key_id, hdr.kdf_id), (7, Kdf::KDF_ID));
+
+        let (request, server) = gateway.decapsulate_request::<A, Kdf>(&enc_request).unwrap();
+        assert_eq!(request, b"POST /payjoin");
+        let enc_response = server.encapsulate_response(b"200 OK", &mut csprng).unwrap();
+        assert_eq!(
+            client.decapsulate_response(&enc_response).unwrap(),
+            b"200 OK"
+        );
+
+        // Suites the gateway doesn't offer are refused on both ends
+        assert!(encapsulate_request::<A, HkdfSha384, _>(&configs[0], b"", &mut csprng).is_err());
+        assert!(gateway
+            .decapsulate_request::<A, HkdfSha384>(&enc_request)
+            .is_err());
+
+        // A request for another key ID, or a tampered one, is refused
+        let mut bad = enc_request.clone();
+        bad[0] ^= 1;
+        assert!(gateway.decapsulate_request::<A, Kdf>(&bad).is_err());
+        let mut bad = enc_request.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert!(gateway.decapsulate_request::<A, Kdf>(&bad).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Single-use recipient keys, for forward secrecy without the recipient being online
+//!
+//! A static recipient key can decrypt every message ever sent to it, so if it leaks, so does all
+//! past traffic. A [`OneTimeKeyStore`] instead generates batches of keypairs, whose public halves
+//! the recipient publishes (e.g., to a mailbox server). Each sender claims one, and the recipient
+//! deletes the private half as soon as it's been used. After that, neither a replay of the message
+//! nor a later compromise of the recipient can recover the session.
+//!
+//! Private keys are kept in a [`KeyStorage`]. This crate provides [`MemoryKeyStorage`] and, with
+//! the `std` feature, `FileKeyStorage`.
+
+use crate::{
+    aead::{Aead, AeadCtxR},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    op_mode::OpModeR,
+    setup::setup_receiver,
+    HpkeError, Vec,
+};
+
+use core::fmt;
+
+use rand_core::{CryptoRng, RngCore};
+
+/// Somewhere to keep one-time private keys, indexed by key ID
+pub trait KeyStorage<Kem: KemTrait> {
+    /// The error returned when the underlying storage fails
+    type Error;
+
+    /// Stores `sk` under `key_id`, replacing anything that was there
+    fn insert(&mut self, key_id: u32, sk: &Kem::PrivateKey) -> Result<(), Self::Error>;
+
+    /// Returns the private key stored under `key_id`, if any
+    fn get(&self, key_id: u32) -> Result<Option<Kem::PrivateKey>, Self::Error>;
+
+    /// Deletes the private key stored under `key_id`. Deleting a key that isn't there is not an
+    /// error.
+    fn remove(&mut self, key_id: u32) -> Result<(), Self::Error>;
+}
+
+/// Describes things that can go wrong in a `OneTimeKeyStore`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OneTimeKeyError<E> {
+    /// An HPKE operation failed, or the requested key doesn't exist
+    Hpke(HpkeError),
+    /// The key storage failed
+    Storage(E),
+}
+
+impl<E: fmt::Display> fmt::Display for OneTimeKeyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OneTimeKeyError::Hpke(e) => write!(f, "{}", e),
+            OneTimeKeyError::Storage(e) => write!(f, "Key storage failed: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug + fmt::Display> std::error::Error for OneTimeKeyError<E> {}
+
+/// Keeps one-time private keys in memory. Keys are lost when this is dropped.
+pub struct MemoryKeyStorage<Kem: KemTrait> {
+    keys: Vec<(u32, Kem::PrivateKey)>,
+}
+
+impl<Kem: KemTrait> Default for MemoryKeyStorage<Kem> {
+    fn default() -> MemoryKeyStorage<Kem> {
+        MemoryKeyStorage { keys: Vec::new() }
+    }
+}
+
+impl<Kem: KemTrait> MemoryKeyStorage<Kem> {
+    /// Makes an empty storage
+    pub fn new() -> MemoryKeyStorage<Kem> {
+        MemoryKeyStorage::default()
+    }
+}
+
+impl<Kem: KemTrait> KeyStorage<Kem> for MemoryKeyStorage<Kem> {
+    // Memory never fails
+    type Error = core::convert::Infallible;
+
+    fn insert(&mut self, key_id: u32, sk: &Kem::PrivateKey) -> Result<(), Self::Error> {
+        self.keys.retain(|(id, _)| *id != key_id);
+        self.keys.push((key_id, sk.clone()));
+        Ok(())
+    }
+
+    fn get(&self, key_id: u32) -> Result<Option<Kem::PrivateKey>, Self::Error> {
+        Ok(self
+            .keys
+            .iter()
+            .find(|(id, _)| *id == key_id)
+            .map(|(_, sk)| sk.clone()))
+    }
+
+    fn remove(&mut self, key_id: u32) -> Result<(), Self::Error> {
+        self.keys.retain(|(id, _)| *id != key_id);
+        Ok(())
+    }
+}
+
+/// Keeps one-time private keys as files in a directory, one file per key. On Unix, the directory
+/// is created with mode 0700 and key files with mode 0600, so only the recipient can read them.
+///
+/// Deleting a file does not necessarily erase its contents from the disk. Removed keys are
+/// overwritten with zeros before they're deleted, but whether that reaches the physical medium
+/// depends on the filesystem.
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+pub struct FileKeyStorage {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileKeyStorage {
+    /// Uses the given directory for key storage, creating it if it doesn't exist. A directory that
+    /// already exists keeps its permissions.
+    pub fn new<P: Into<std::path::PathBuf>>(dir: P) -> std::io::Result<FileKeyStorage> {
+        let dir = dir.into();
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        Ok(FileKeyStorage { dir })
+    }
+
+    fn key_path(&self, key_id: u32) -> std::path::PathBuf {
+        self.dir.join(format!("{:08x}.key", key_id))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Kem: KemTrait> KeyStorage<Kem> for FileKeyStorage {
+    type Error = std::io::Error;
+
+    fn insert(&mut self, key_id: u32, sk: &Kem::PrivateKey) -> Result<(), Self::Error> {
+        use crate::Serializable;
+        use std::io::Write;
+
+        // Always make a new file, so it never inherits looser permissions from an old one
+        <Self as KeyStorage<Kem>>::remove(self, key_id)?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(self.key_path(key_id))?
+            .write_all(&sk.to_bytes())
+    }
+
+    fn get(&self, key_id: u32) -> Result<Option<Kem::PrivateKey>, Self::Error> {
+        use crate::Deserializable;
+        use std::io::{Error, ErrorKind};
+
+        match std::fs::read(self.key_path(key_id)) {
+            Ok(bytes) => Kem::PrivateKey::from_bytes(&bytes)
+                .map(Some)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&mut self, key_id: u32) -> Result<(), Self::Error> {
+        use std::io::ErrorKind;
+
+        let path = self.key_path(key_id);
+        let len = match std::fs::metadata(&path) {
+            Ok(m) => m.len() as usize,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        std::fs::write(&path, vec![0u8; len])?;
+        std::fs::remove_file(&path)
+    }
+}
+
+/// A recipient's pool of single-use keys. See the module documentation.
+pub struct OneTimeKeyStore<Kem: KemTrait, S: KeyStorage<Kem>> {
+    storage: S,
+    _marker: core::marker::PhantomData<Kem>,
+}
+
+impl<Kem: KemTrait, S: KeyStorage<Kem>> OneTimeKeyStore<Kem, S> {
+    /// Makes a key store backed by `storage`. Any keys already in the storage remain usable.
+    pub fn new(storage: S) -> OneTimeKeyStore<Kem, S> {
+        OneTimeKeyStore {
+            storage,
+            _marker: core::marker::PhantomData,
+        }
+    }
+
+    /// Returns the underlying storage
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Generates `count` new keypairs under fresh random IDs and stores their private halves
+    ///
+    /// Return Value
+    /// ============
+    /// Returns the IDs and public keys to publish. If the storage fails, returns
+    /// `Err(OneTimeKeyError::Storage(e))`. Keys generated before the failure stay stored.
+    pub fn generate_batch<R: CryptoRng + RngCore>(
+        &mut self,
+        count: usize,
+        csprng: &mut R,
+    ) -> Result<Vec<(u32, Kem::PublicKey)>, OneTimeKeyError<S::Error>> {
+        let mut published = Vec::with_capacity(count);
+        while published.len() < count {
+            // Pick an ID that isn't taken
+            let key_id = csprng.next_u32();
+            if self
+                .storage
+                .get(key_id)
+                .map_err(OneTimeKeyError::Storage)?
+                .is_some()
+            {
+                continue;
+            }
+
+            let (sk, pk) = Kem::gen_keypair(csprng);
+            self.storage
+                .insert(key_id, &sk)
+                .map_err(OneTimeKeyError::Storage)?;
+            published.push((key_id, pk));
+        }
+
+        Ok(published)
+    }
+
+    /// Initiates a decryption context with the one-time key `key_id`, then deletes the key. See
+    /// `setup_receiver`.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns a decryption context. If there is no key with that ID, e.g., because it
+    /// was already used, or an error happened during key decapsulation, returns
+    /// `Err(OneTimeKeyError::Hpke(HpkeError::DecapError))`. If the storage fails, returns
+    /// `Err(OneTimeKeyError::Storage(e))`, and no context is returned, since the key might not
+    /// have been deleted.
+    pub fn setup_receiver<A, Kdf>(
+        &mut self,
+        mode: &OpModeR<Kem>,
+        key_id: u32,
+        encapped_key: &Kem::EncappedKey,
+        info: &[u8],
+    ) -> Result<AeadCtxR<A, Kdf, Kem>, OneTimeKeyError<S::Error>>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        let sk = self
+            .storage
+            .get(key_id)
+            .map_err(OneTimeKeyError::Storage)?
+            .ok_or(OneTimeKeyError::Hpke(HpkeError::DecapError))?;
+        let ctx = setup_receiver(mode, &sk, encapped_key, info).map_err(OneTimeKeyError::Hpke)?;
+
+        // The key is spent. Delete it before handing out the context.
+        self.storage
+            .remove(key_id)
+            .map_err(OneTimeKeyError::Storage)?;
+        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::SecpK256HkdfSha256, op_mode::OpModeS,
+        setup::setup_sender, test_util::aead_ctx_eq,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+    type Kem = SecpK256HkdfSha256;
+
+    /// Uses every key in a fresh batch once, and checks that using it again fails
+    fn exercise_store<S: KeyStorage<Kem>>(store: &mut OneTimeKeyStore<Kem, S>)
+    where
+        S::Error: fmt::Debug,
+    {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"read once";
+
+        let published = store.generate_batch(3, &mut csprng).unwrap();
+        assert_eq!(published.len(), 3);
+
+        for (key_id, pk) in &published {
+            let (encapped_key, mut sender_ctx) =
+                setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, pk, info, &mut csprng).unwrap();
+            let mut receiver_ctx = store
+                .setup_receiver::<A, Kdf>(&OpModeR::Base, *key_id, &encapped_key, info)
+                .unwrap();
+            assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+            // Replays fail, since the key is gone
+            let res = store.setup_receiver::<A, Kdf>(&OpModeR::Base, *key_id, &encapped_key, info);
+            assert!(matches!(
+                res,
+                Err(OneTimeKeyError::Hpke(HpkeError::DecapError))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = OneTimeKeyStore::new(MemoryKeyStorage::<Kem>::new());
+        exercise_store(&mut store);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_file_store() {
+        let suffix = crate::test_util::gen_rand_buf();
+        let dir = std::env::temp_dir().join(format!(
+            "bitcoin-hpke-otk-{:02x}{:02x}{:02x}{:02x}",
+            suffix[0], suffix[1], suffix[2], suffix[3]
+        ));
+        let mut store = OneTimeKeyStore::<Kem, _>::new(FileKeyStorage::new(&dir).unwrap());
+        exercise_store(&mut store);
+
+        // Nothing is left on disk
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Only the owner can get at the directory or the keys, even under a permissive umask
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &std::path::Path| {
+                std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+            };
+            assert_eq!(mode(&dir), 0o700);
+
+            let mut storage = store.into_storage();
+            let (sk, _) = Kem::gen_keypair(&mut StdRng::from_entropy());
+            KeyStorage::<Kem>::insert(&mut storage, 7, &sk).unwrap();
+            assert_eq!(mode(&storage.key_path(7)), 0o600);
+
+            // Replacing a key makes a fresh file rather than reusing the old one
+            std::fs::set_permissions(storage.key_path(7), std::fs::Permissions::from_mode(0o644))
+                .unwrap();
+            KeyStorage::<Kem>::insert(&mut storage, 7, &sk).unwrap();
+            assert_eq!(mode(&storage.key_path(7)), 0o600);
+            KeyStorage::<Kem>::remove(&mut storage, 7).unwrap();
+        }
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
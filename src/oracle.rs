@@ -0,0 +1,312 @@
+//! Setup with private keys that live outside this process
+//!
+//! `setup_receiver` and `OpModeS::Auth` need the raw private key in memory. When the key is held
+//! by a hardware wallet, an HSM, or a separate signing process, all HPKE actually needs from it is
+//! the DH result `sk⋅pk`. A [`DhOracle`] computes exactly that, and nothing more, so the key never
+//! has to leave the device. [`setup_receiver_with_oracle`] and [`setup_sender_with_oracle`] are
+//! the corresponding variants of `setup_receiver` and `setup_sender`.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::{
+        secp256k1::{PrivateKey, PublicKey, Secp256k1},
+        DhKeyExchange,
+    },
+    kdf::Kdf as KdfTrait,
+    kem::{
+        secpk256_hkdfsha256::{derive_shared_secret, EncappedKey},
+        Kem as KemTrait, SecpK256HkdfSha256,
+    },
+    op_mode::{OpModeR, PskBundle},
+    setup::derive_enc_ctx,
+    threshold::{kex_result, setup_receiver_with_dh, DhShare},
+    HpkeError,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+
+/// Something that holds a private key and will compute Diffie-Hellman results with it, e.g., a
+/// hardware wallet, an HSM, or a separate process
+pub trait DhOracle {
+    /// Returns the public key corresponding to the private key this oracle holds
+    fn public_key(&self) -> PublicKey;
+
+    /// Computes the full DH result `sk⋅pk`, where `sk` is the private key this oracle holds
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(dh)` on success. The error returned on failure is up to the implementation.
+    fn dh(&self, pk: &PublicKey) -> Result<DhShare, HpkeError>;
+}
+
+/// A `DhOracle` that holds its private key in memory. This is mostly useful for testing, and as a
+/// reference for what other implementations should compute.
+#[derive(Clone)]
+pub struct LocalDhOracle {
+    sk: PrivateKey,
+    pk: PublicKey,
+}
+
+impl LocalDhOracle {
+    /// Makes an oracle that computes DH results with the given private key
+    pub fn new(sk: PrivateKey) -> LocalDhOracle {
+        let pk = Kem::sk_to_pk(&sk);
+        LocalDhOracle { sk, pk }
+    }
+}
+
+impl DhOracle for LocalDhOracle {
+    fn public_key(&self) -> PublicKey {
+        self.pk.clone()
+    }
+
+    fn dh(&self, pk: &PublicKey) -> Result<DhShare, HpkeError> {
+        Ok(DhShare::new(&self.sk, pk))
+    }
+}
+
+/// Initiates a decryption context for the recipient key held by `oracle`, given an encapsulated
+/// key which was encapsulated to the oracle's public key. The oracle is asked for one DH result
+/// in `Base` and `Psk` modes, and two in `Auth` and `AuthPsk` modes.
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If the oracle fails, returns
+/// `Err(HpkeError::DecapError)`. This is the only possible error.
+pub fn setup_receiver_with_oracle<A, Kdf, O>(
+    mode: &OpModeR<Kem>,
+    oracle: &O,
+    encapped_key: &EncappedKey,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    O: DhOracle + ?Sized,
+{
+    let dh_eph = oracle
+        .dh(&encapped_key.0)
+        .map_err(|_| HpkeError::DecapError)?;
+    let dh_identity = match mode.get_pk_sender_id() {
+        Some(pk_sender_id) => Some(oracle.dh(pk_sender_id).map_err(|_| HpkeError::DecapError)?),
+        None => None,
+    };
+
+    // The DH results match the mode by construction, so this can't fail
+    setup_receiver_with_dh(
+        mode,
+        &oracle.public_key(),
+        encapped_key,
+        &dh_eph,
+        dh_identity.as_ref(),
+        info,
+    )
+}
+
+/// Initiates an encryption context to the given recipient public key, authenticated with the
+/// sender identity key held by `sender_id`. This is `setup_sender` in `Auth` mode if `psk` is
+/// `None`, and in `AuthPsk` mode otherwise. The oracle is asked for one DH result.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated public key (intended to be sent to the recipient), and an
+/// encryption context. If the oracle or the ephemeral key exchange fails, returns
+/// `Err(HpkeError::EncapError)`. This is the only possible error.
+pub fn setup_sender_with_oracle<A, Kdf, O, R>(
+    psk: Option<PskBundle>,
+    sender_id: &O,
+    pk_recip: &PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    O: DhOracle + ?Sized,
+    R: CryptoRng + RngCore,
+{
+    // The ephemeral half of AuthEncap happens locally, same as in encap
+    let (sk_eph, pk_eph) = Kem::gen_keypair(csprng);
+    let kex_res_eph = Secp256k1::dh(&sk_eph, pk_recip).map_err(|_| HpkeError::EncapError)?;
+    let encapped_key = EncappedKey(pk_eph);
+
+    // The identity half is delegated to the oracle
+    let dh_identity = sender_id.dh(pk_recip).map_err(|_| HpkeError::EncapError)?;
+    let pk_sender_id = sender_id.public_key();
+    let shared_secret = derive_shared_secret(
+        &kex_res_eph,
+        Some((&kex_result(&dh_identity), &pk_sender_id)),
+        &encapped_key,
+        pk_recip,
+    );
+
+    // The key schedule only looks at the mode ID and the PSK, which are the same from both sides,
+    // so we can use the receiver's view of the mode here
+    let mode = match psk {
+        Some(bundle) => OpModeR::AuthPsk(pk_sender_id, bundle),
+        None => OpModeR::Auth(pk_sender_id),
+    };
+    let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(&mode, shared_secret, info);
+
+    Ok((encapped_key, enc_ctx.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        op_mode::OpModeS,
+        setup::{setup_receiver, setup_sender},
+        test_util::{aead_ctx_eq, gen_rand_buf},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests that the oracle setup functions derive the same contexts as the ordinary ones, in
+    /// every mode
+    #[test]
+    fn test_oracle_setup_correctness() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"the key is in another castle";
+        let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+        let psk_bundle = PskBundle {
+            psk: &psk,
+            psk_id: &psk_id,
+        };
+
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+        let recip_oracle = LocalDhOracle::new(sk_recip.clone());
+        let sender_oracle = LocalDhOracle::new(sk_sender.clone());
+        assert_eq!(recip_oracle.public_key(), pk_recip);
+
+        // Ordinary sender, oracle receiver
+        for (sender_mode, receiver_mode) in [
+            (OpModeS::Base, OpModeR::Base),
+            (OpModeS::Psk(psk_bundle), OpModeR::Psk(psk_bundle)),
+            (
+                OpModeS::Auth((sk_sender.clone(), pk_sender.clone())),
+                OpModeR::Auth(pk_sender.clone()),
+            ),
+            (
+                OpModeS::AuthPsk((sk_sender.clone(), pk_sender.clone()), psk_bundle),
+                OpModeR::AuthPsk(pk_sender.clone(), psk_bundle),
+            ),
+        ] {
+            let (encapped_key, mut sender_ctx) =
+                setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, info, &mut csprng).unwrap();
+            let mut receiver_ctx = setup_receiver_with_oracle::<A, Kdf, _>(
+                &receiver_mode,
+                &recip_oracle,
+                &encapped_key,
+                info,
+            )
+            .unwrap();
+            assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+        }
+
+        // Oracle sender, ordinary receiver
+        for (psk, receiver_mode) in [
+            (None, OpModeR::Auth(pk_sender.clone())),
+            (
+                Some(psk_bundle),
+                OpModeR::AuthPsk(pk_sender.clone(), psk_bundle),
+            ),
+        ] {
+            let (encapped_key, mut sender_ctx) = setup_sender_with_oracle::<A, Kdf, _, _>(
+                psk,
+                &sender_oracle,
+                &pk_recip,
+                info,
+                &mut csprng,
+            )
+            .unwrap();
+            let mut receiver_ctx =
+                setup_receiver::<A, Kdf, Kem>(&receiver_mode, &sk_recip, &encapped_key, info)
+                    .unwrap();
+            assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+        }
+    }
+
+    /// A stand-in for a signer in another process. The private key lives in a server thread, and
+    /// the oracle only ever sees pubkeys and DH results go over a local socket.
+    #[cfg(feature = "std")]
+    struct SocketDhOracle {
+        pk: PublicKey,
+        stream: std::net::TcpStream,
+    }
+
+    #[cfg(feature = "std")]
+    impl DhOracle for SocketDhOracle {
+        fn public_key(&self) -> PublicKey {
+            self.pk.clone()
+        }
+
+        fn dh(&self, pk: &PublicKey) -> Result<DhShare, HpkeError> {
+            use crate::{Deserializable, Serializable};
+            use std::io::{Read, Write};
+
+            let mut stream = &self.stream;
+            stream
+                .write_all(&pk.to_bytes())
+                .map_err(|_| HpkeError::DecapError)?;
+            let mut buf = [0u8; 65];
+            stream
+                .read_exact(&mut buf)
+                .map_err(|_| HpkeError::DecapError)?;
+            DhShare::from_bytes(&buf)
+        }
+    }
+
+    /// Tests that a receiver whose key lives behind a socket can decrypt
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_socket_oracle() {
+        use crate::{Deserializable, Serializable};
+        use std::{
+            io::{Read, Write},
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        let mut csprng = StdRng::from_entropy();
+        let info = b"over the wire";
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+        // The signer answers every 65-byte pubkey with a 65-byte DH result
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let signer = LocalDhOracle::new(sk_recip);
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 65];
+            while conn.read_exact(&mut buf).is_ok() {
+                let pk = PublicKey::from_bytes(&buf).unwrap();
+                let dh = signer.dh(&pk).unwrap();
+                conn.write_all(&dh.to_bytes()).unwrap();
+            }
+        });
+
+        let oracle = SocketDhOracle {
+            pk: pk_recip.clone(),
+            stream: TcpStream::connect(addr).unwrap(),
+        };
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng).unwrap();
+        let mut receiver_ctx =
+            setup_receiver_with_oracle::<A, Kdf, _>(&OpModeR::Base, &oracle, &encapped_key, info)
+                .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Hang up so the server thread exits
+        drop(oracle);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Length-hiding padding for sealed messages
+//!
+//! An AEAD ciphertext is exactly as long as its plaintext plus a tag, so anyone watching the wire
+//! learns the message length. A [`PaddingPolicy`] rounds the plaintext up to a less revealing size
+//! before it's sealed. `AeadCtxS::seal_padded`, `AeadCtxR::open_padded`, and the corresponding
+//! single-shot functions apply and strip the padding for you.
+//!
+//! Padding is ISO/IEC 7816-4 style: the plaintext is followed by a single `0x80` byte, then as
+//! many zero bytes as the policy asks for. This is unambiguous for every plaintext, including
+//! ones that themselves end in zeros, and is removed without branching on the plaintext contents.
+
+use crate::HpkeError;
+
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+
+/// The byte that separates a plaintext from its padding
+const PADDING_MARKER: u8 = 0x80;
+
+/// How far to pad a plaintext before sealing it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Pad to the next multiple of the given bucket size
+    Bucket(usize),
+    /// Pad with Padmé, which leaks at most O(log log L) bits of the length L at a cost of at most
+    /// 12% overhead
+    Padme,
+    /// Pad to the next power of two
+    PowerOfTwo,
+    /// Pad every message to exactly the given size, as BIP77 does for its fixed-size messages
+    Exact(usize),
+}
+
+impl PaddingPolicy {
+    /// Computes the padded length of a plaintext that is `plaintext_len` bytes long. This includes
+    /// the 1-byte padding marker, so it is always at least `plaintext_len + 1`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(padded_len)` on success. If the bucket size is zero, the plaintext and marker
+    /// don't fit in an `Exact` size, or the padded length overflows a `usize`, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn padded_len(&self, plaintext_len: usize) -> Result<usize, HpkeError> {
+        let min_len = plaintext_len
+            .checked_add(1)
+            .ok_or(HpkeError::ValidationError)?;
+
+        let padded_len = match *self {
+            PaddingPolicy::Bucket(0) => None,
+            PaddingPolicy::Bucket(size) => {
+                let remainder = min_len % size;
+                if remainder == 0 {
+                    Some(min_len)
+                } else {
+                    min_len.checked_add(size - remainder)
+                }
+            }
+            PaddingPolicy::Padme => padme(min_len),
+            PaddingPolicy::PowerOfTwo => min_len.checked_next_power_of_two(),
+            PaddingPolicy::Exact(size) => Some(size).filter(|&size| size >= min_len),
+        };
+
+        padded_len.ok_or(HpkeError::ValidationError)
+    }
+}
+
+// Padmé, from Nikitin et al. "Reducing Metadata Leakage from Encrypted Files and Communication
+// with PURBs" §4:
+//   E = floor(log2(L))
+//   S = floor(log2(E)) + 1
+//   lastBits = E - S
+//   bitMask = 2^lastBits - 1
+//   return (L + bitMask) & ~bitMask
+fn padme(len: usize) -> Option<usize> {
+    // Lengths below 2 have no room to round
+    if len < 2 {
+        return Some(len);
+    }
+
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let bit_mask = (1usize << (e - s)) - 1;
+
+    len.checked_add(bit_mask).map(|l| l & !bit_mask)
+}
+
+/// Writes the padding for a plaintext of length `plaintext_len` into `buf`, which must be exactly
+/// as long as the padded message, and whose first `plaintext_len` bytes already hold the plaintext.
+///
+/// Panics
+/// ======
+/// Panics if `buf` is not longer than `plaintext_len`.
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub(crate) fn write_padding(buf: &mut [u8], plaintext_len: usize) {
+    let (marker, zeros) = buf[plaintext_len..].split_first_mut().unwrap();
+    *marker = PADDING_MARKER;
+    zeros.iter_mut().for_each(|b| *b = 0);
+}
+
+/// Finds the length of the plaintext inside a padded message. The whole buffer is scanned,
+/// regardless of where the padding starts, so the time taken depends only on the padded length.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext_len)` on success. If the last nonzero byte of `padded` is not the padding
+/// marker, or there is no nonzero byte at all, returns `Err(HpkeError::ValidationError)`.
+pub fn unpadded_len(padded: &[u8]) -> Result<usize, HpkeError> {
+    // Track the index and value of the last nonzero byte
+    let mut last_nonzero_idx = 0u64;
+    let mut last_nonzero_byte = 0u8;
+    for (i, b) in padded.iter().enumerate() {
+        let is_nonzero = !b.ct_eq(&0);
+        last_nonzero_idx.conditional_assign(&(i as u64), is_nonzero);
+        last_nonzero_byte.conditional_assign(b, is_nonzero);
+    }
+
+    if bool::from(last_nonzero_byte.ct_eq(&PADDING_MARKER)) {
+        Ok(last_nonzero_idx as usize)
+    } else {
+        Err(HpkeError::ValidationError)
+    }
+}
+
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::{Kem as KemTrait, SecpK256HkdfSha256},
+        op_mode::{OpModeR, OpModeS},
+        single_shot::{single_shot_open_padded, single_shot_seal_padded},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests each policy's padded lengths, and that padded messages round-trip through
+    /// `single_shot_seal_padded` and `single_shot_open_padded`
+    #[test]
+    fn test_padding_policies() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = SecpK256HkdfSha256;
+
+        // Lengths include the 1-byte marker
+        assert_eq!(PaddingPolicy::Bucket(32).padded_len(0), Ok(32));
+        assert_eq!(PaddingPolicy::Bucket(32).padded_len(31), Ok(32));
+        assert_eq!(PaddingPolicy::Bucket(32).padded_len(32), Ok(64));
+        assert_eq!(PaddingPolicy::PowerOfTwo.padded_len(100), Ok(128));
+        assert_eq!(PaddingPolicy::Padme.padded_len(8), Ok(10));
+        assert_eq!(PaddingPolicy::Padme.padded_len(999), Ok(1024));
+        assert_eq!(PaddingPolicy::Padme.padded_len(1024), Ok(1088));
+        assert_eq!(PaddingPolicy::Exact(64).padded_len(63), Ok(64));
+        assert_eq!(
+            PaddingPolicy::Exact(64).padded_len(64),
+            Err(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            PaddingPolicy::Bucket(0).padded_len(1),
+            Err(HpkeError::ValidationError)
+        );
+
+        // Padding must be unambiguous even when the plaintext ends in the marker or zeros
+        assert_eq!(unpadded_len(&[1, 0x80, 0x80, 0, 0]), Ok(2));
+        assert_eq!(unpadded_len(&[0x80, 0, 0]), Ok(0));
+        assert_eq!(unpadded_len(&[1, 0, 0]), Err(HpkeError::ValidationError));
+        assert_eq!(unpadded_len(&[0, 0, 0]), Err(HpkeError::ValidationError));
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let info = b"padding test";
+        let aad = b"aad";
+
+        let policies = [
+            PaddingPolicy::Bucket(256),
+            PaddingPolicy::Padme,
+            PaddingPolicy::PowerOfTwo,
+            PaddingPolicy::Exact(512),
+        ];
+        for policy in policies.iter() {
+            for msg in [&b""[..], b"hello\x80\x00\x00", &[0u8; 300]].iter() {
+                let (encapped_key, ciphertext) = single_shot_seal_padded::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    msg,
+                    aad,
+                    policy,
+                    &mut csprng,
+                )
+                .unwrap();
+
+                // The ciphertext length only reveals the padded length
+                let padded_len = policy.padded_len(msg.len()).unwrap();
+                assert_eq!(ciphertext.len(), padded_len + 16);
+
+                let decrypted = single_shot_open_padded::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                    &ciphertext,
+                    aad,
+                )
+                .unwrap();
+                assert_eq!(&decrypted, msg);
+            }
+        }
+
+        // A message that doesn't fit its exact size is refused
+        assert_eq!(
+            single_shot_seal_padded::<A, Kdf, Kem, _>(
+                &OpModeS::Base,
+                &pk_recip,
+                info,
+                &[0u8; 64],
+                aad,
+                &PaddingPolicy::Exact(64),
+                &mut csprng,
+            )
+            .map(|_| ()),
+            Err(HpkeError::ValidationError)
+        );
+
+        // A plaintext that was sealed without padding has no marker, so it fails to open
+        let (encapped_key, ciphertext) = crate::single_shot::single_shot_seal::<A, Kdf, Kem, _>(
+            &OpModeS::Base,
+            &pk_recip,
+            info,
+            b"unpadded\x00",
+            aad,
+            &mut csprng,
+        )
+        .unwrap();
+        assert_eq!(
+            single_shot_open_padded::<A, Kdf, Kem>(
+                &OpModeR::Base,
+                &sk_recip,
+                &encapped_key,
+                info,
+                &ciphertext,
+                aad,
+            ),
+            Err(HpkeError::OpenError)
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! BIP77 (Async Payjoin) directory message encapsulation
+//!
+//! Payjoin v2 senders and receivers talk through a directory that stores fixed-size encrypted
+//! messages in mailboxes. There are two messages:
+//!
+//! * Message A carries the Original PSBT from the sender to the receiver. It's sealed in `Base`
+//!   mode to the receiver's key, and includes the sender's reply key.
+//! * Message B carries the Proposal PSBT back. It's sealed in `Auth` mode from the receiver's key
+//!   to the sender's reply key.
+//!
+//! Both are `ellswift(enc) || ciphertext`, where the encapsulated key is ElligatorSwift-encoded so
+//! it looks like random bytes, and the plaintext is zero-padded so every message is exactly
+//! [`PADDED_MESSAGE_BYTES`] long. The suite is DHKEM(secp256k1, HKDF-SHA256), HKDF-SHA256, and
+//! ChaCha20Poly1305.
+//!
+//! Padding is zeros, which is not stripped when opening. BIP77 payloads are text, so callers can
+//! trim trailing zero bytes.
+
+use crate::{
+    aead::{AeadTag, ChaCha20Poly1305},
+    dhkex::secp256k1::{PrivateKey, PublicKey},
+    kdf::HkdfSha256,
+    kem::{secpk256_hkdfsha256::EncappedKey, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    util::split_checked,
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use rand_core::{CryptoRng, RngCore};
+use secp256k1::ellswift::ElligatorSwift;
+use sha2::{Digest, Sha256};
+
+type A = ChaCha20Poly1305;
+type Kdf = HkdfSha256;
+type Kem = SecpK256HkdfSha256;
+
+/// The size of every message stored in a directory mailbox
+pub const PADDED_MESSAGE_BYTES: usize = 7168;
+/// The size of an ElligatorSwift-encoded public key
+pub const ELLSWIFT_ENCODING_SIZE: usize = 64;
+/// The size of a compressed public key
+const COMPRESSED_PUBLIC_KEY_SIZE: usize = 33;
+/// The size of a ChaCha20Poly1305 tag
+const TAG_SIZE: usize = 16;
+
+/// The size of the padded plaintext of message A, including the reply key
+pub const PADDED_PLAINTEXT_A_LENGTH: usize =
+    PADDED_MESSAGE_BYTES - ELLSWIFT_ENCODING_SIZE - TAG_SIZE;
+/// The size of the padded plaintext of message B
+pub const PADDED_PLAINTEXT_B_LENGTH: usize =
+    PADDED_MESSAGE_BYTES - ELLSWIFT_ENCODING_SIZE - TAG_SIZE;
+/// The longest Original PSBT payload that fits in message A
+pub const MAX_BODY_A_LENGTH: usize = PADDED_PLAINTEXT_A_LENGTH - COMPRESSED_PUBLIC_KEY_SIZE;
+
+/// The info string for message A
+pub const INFO_A: &[u8] = b"PjV2MsgA";
+/// The info string for message B
+pub const INFO_B: &[u8] = b"PjV2MsgB";
+
+/// Derives the mailbox ID for a key, i.e., the first 8 bytes of `SHA256(compressed_pubkey)`.
+/// Message A goes in the mailbox of the receiver's key, and message B in the mailbox of the
+/// sender's reply key.
+pub fn mailbox_id(pk: &PublicKey) -> [u8; 8] {
+    let digest = Sha256::digest(pk.0.serialize());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&digest[..8]);
+    id
+}
+
+/// ElligatorSwift-encodes an encapsulated key
+fn encode_enc(encapped_key: &EncappedKey) -> [u8; ELLSWIFT_ENCODING_SIZE] {
+    ElligatorSwift::from_pubkey(encapped_key.0 .0).to_array()
+}
+
+/// Decodes an ElligatorSwift-encoded encapsulated key
+fn decode_enc(encoded: &[u8]) -> EncappedKey {
+    let mut arr = [0u8; ELLSWIFT_ENCODING_SIZE];
+    arr.copy_from_slice(encoded);
+    let pk = secp256k1::PublicKey::from_ellswift(ElligatorSwift::from_array(arr));
+    EncappedKey(PublicKey(pk))
+}
+
+/// Seals `plaintext`, zero-padded to `padded_len`, and prepends the encoded encapsulated key
+fn seal_padded<R: CryptoRng + RngCore>(
+    mode: &OpModeS<Kem>,
+    pk_recip: &PublicKey,
+    info: &[u8],
+    mut plaintext: Vec<u8>,
+    padded_len: usize,
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError> {
+    if plaintext.len() > padded_len {
+        return Err(HpkeError::ValidationError);
+    }
+    plaintext.resize(padded_len, 0);
+
+    let (encapped_key, mut ctx) = setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+    let tag = ctx.seal_in_place_detached(&mut plaintext, b"")?;
+
+    let mut message = Vec::with_capacity(PADDED_MESSAGE_BYTES);
+    message.extend_from_slice(&encode_enc(&encapped_key));
+    message.extend_from_slice(&plaintext);
+    message.extend_from_slice(&tag.to_bytes());
+    Ok(message)
+}
+
+/// Opens a message made by `seal_padded`
+fn open_padded(
+    mode: &OpModeR<Kem>,
+    sk_recip: &PrivateKey,
+    info: &[u8],
+    message: &[u8],
+) -> Result<Vec<u8>, HpkeError> {
+    if message.len() != PADDED_MESSAGE_BYTES {
+        return Err(HpkeError::IncorrectInputLength(
+            PADDED_MESSAGE_BYTES,
+            message.len(),
+        ));
+    }
+    let (enc, rest) = split_checked(message, ELLSWIFT_ENCODING_SIZE)?;
+    let (ciphertext, tag) = split_checked(rest, rest.len() - TAG_SIZE)?;
+
+    let mut ctx = setup_receiver::<A, Kdf, Kem>(mode, sk_recip, &decode_enc(enc), info)?;
+    let mut plaintext = ciphertext.to_vec();
+    ctx.open_in_place_detached(&mut plaintext, b"", &AeadTag::from_bytes(tag)?)?;
+    Ok(plaintext)
+}
+
+/// Makes message A, carrying the Original PSBT payload `body` and the sender's reply key to the
+/// receiver
+///
+/// Return Value
+/// ============
+/// Returns `Ok(message)` on success. If `body` is longer than `MAX_BODY_A_LENGTH`, returns
+/// `Err(HpkeError::ValidationError)`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`.
+pub fn seal_message_a<R: CryptoRng + RngCore>(
+    body: &[u8],
+    reply_key: &PublicKey,
+    receiver_key: &PublicKey,
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError> {
+    if body.len() > MAX_BODY_A_LENGTH {
+        return Err(HpkeError::ValidationError);
+    }
+    let mut plaintext = reply_key.0.serialize().to_vec();
+    plaintext.extend_from_slice(body);
+
+    seal_padded(
+        &OpModeS::Base,
+        receiver_key,
+        INFO_A,
+        plaintext,
+        PADDED_PLAINTEXT_A_LENGTH,
+        csprng,
+    )
+}
+
+/// Opens message A with the receiver's key
+///
+/// Return Value
+/// ============
+/// On success, returns the padded body and the sender's reply key. If the message isn't
+/// `PADDED_MESSAGE_BYTES` long, returns `Err(HpkeError::IncorrectInputLength)`. If the reply key
+/// is malformed, returns `Err(HpkeError::ValidationError)`. If the message fails to decrypt,
+/// returns `Err(HpkeError::OpenError)`.
+pub fn open_message_a(
+    message: &[u8],
+    receiver_sk: &PrivateKey,
+) -> Result<(Vec<u8>, PublicKey), HpkeError> {
+    let mut plaintext = open_padded(&OpModeR::Base, receiver_sk, INFO_A, message)?;
+    let reply_key = secp256k1::PublicKey::from_slice(&plaintext[..COMPRESSED_PUBLIC_KEY_SIZE])
+        .map_err(|_| HpkeError::ValidationError)?;
+    plaintext.drain(..COMPRESSED_PUBLIC_KEY_SIZE);
+    Ok((plaintext, PublicKey(reply_key)))
+}
+
+/// Makes message B, carrying the Proposal PSBT payload `body` from the receiver to the sender's
+/// reply key. The receiver authenticates with the key the sender sent message A to.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(message)` on success. If `body` is longer than `PADDED_PLAINTEXT_B_LENGTH`, returns
+/// `Err(HpkeError::ValidationError)`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`.
+pub fn seal_message_b<R: CryptoRng + RngCore>(
+    body: &[u8],
+    receiver_keypair: (PrivateKey, PublicKey),
+    reply_key: &PublicKey,
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError> {
+    seal_padded(
+        &OpModeS::Auth(receiver_keypair),
+        reply_key,
+        INFO_B,
+        body.to_vec(),
+        PADDED_PLAINTEXT_B_LENGTH,
+        csprng,
+    )
+}
+
+/// Opens message B with the sender's reply key, checking that it came from `receiver_key`
+///
+/// Return Value
+/// ============
+/// On success, returns the padded body. If the message isn't `PADDED_MESSAGE_BYTES` long, returns
+/// `Err(HpkeError::IncorrectInputLength)`. If the message fails to decrypt, e.g., because it
+/// wasn't sent by `receiver_key`, returns `Err(HpkeError::OpenError)`.
+pub fn open_message_b(
+    message: &[u8],
+    reply_sk: &PrivateKey,
+    receiver_key: &PublicKey,
+) -> Result<Vec<u8>, HpkeError> {
+    open_padded(
+        &OpModeR::Auth(receiver_key.clone()),
+        reply_sk,
+        INFO_B,
+        message,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kem::Kem as KemTrait;
+
+    use hex_literal::hex;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// An "RNG" that only ever outputs one byte, so that sealing is deterministic
+    struct FixedRng(u8);
+
+    impl RngCore for FixedRng {
+        fn next_u32(&mut self) -> u32 {
+            u32::from_le_bytes([self.0; 4])
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            u64::from_le_bytes([self.0; 8])
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(self.0);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for FixedRng {}
+
+    /// Runs a payjoin exchange through mailboxes and checks the message layouts
+    #[test]
+    fn test_payjoin_messages() {
+        let mut csprng = StdRng::from_entropy();
+        let (receiver_sk, receiver_pk) = Kem::gen_keypair(&mut csprng);
+        let (reply_sk, reply_pk) = Kem::gen_keypair(&mut csprng);
+
+        // The mailbox ID is the hash prefix of the compressed key
+        let sk = PrivateKey::from_bytes(&[1u8; 32]).unwrap();
+        let pk = Kem::sk_to_pk(&sk);
+        assert_eq!(&Sha256::digest(pk.0.serialize())[..8], &mailbox_id(&pk)[..]);
+        assert_ne!(mailbox_id(&receiver_pk), mailbox_id(&reply_pk));
+
+        // Message A
+        let original = b"cHNidP8BAHECAAAAAQ==?v=2";
+        let message_a = seal_message_a(original, &reply_pk, &receiver_pk, &mut csprng).unwrap();
+        assert_eq!(message_a.len(), PADDED_MESSAGE_BYTES);
+        let (body, sender_reply_key) = open_message_a(&message_a, &receiver_sk).unwrap();
+        assert_eq!(sender_reply_key, reply_pk);
+        assert_eq!(body.len(), MAX_BODY_A_LENGTH);
+        assert_eq!(&body[..original.len()], original);
+        assert!(body[original.len()..].iter().all(|&b| b == 0));
+
+        // Message B is only accepted from the receiver
+        let proposal = b"cHNidP8BAJoCAAAAAg==";
+        let message_b = seal_message_b(
+            proposal,
+            (receiver_sk.clone(), receiver_pk.clone()),
+            &sender_reply_key,
+            &mut csprng,
+        )
+        .unwrap();
+        assert_eq!(message_b.len(), PADDED_MESSAGE_BYTES);
+        let body = open_message_b(&message_b, &reply_sk, &receiver_pk).unwrap();
+        assert_eq!(&body[..proposal.len()], proposal);
+        assert!(open_message_b(&message_b, &reply_sk, &reply_pk).is_err());
+
+        // Oversized bodies and truncated messages are rejected
+        let too_big = vec![0u8; MAX_BODY_A_LENGTH + 1];
+        assert!(seal_message_a(&too_big, &reply_pk, &receiver_pk, &mut csprng).is_err());
+        assert!(matches!(
+            open_message_a(&message_a[1..], &receiver_sk),
+            Err(HpkeError::IncorrectInputLength(_, _))
+        ));
+    }
+
+    /// Pins messages A and B, and mailbox IDs, made from fixed keys and a fixed ephemeral key. This
+    /// is a regression test, not a known-answer test: no vectors from BIP77 or the reference
+    /// payjoin implementation are available to this tree. The expected bytes are this
+    /// implementation's own output, which a Python script decrypted under `INFO_A` and `INFO_B`
+    /// to the expected padded plaintexts. That shows the messages are well-formed, not that they
+    /// match other BIP77 implementations.
+    #[test]
+    fn test_payjoin_regression() {
+        let receiver_sk = PrivateKey::from_bytes(&[0x11; 32]).unwrap();
+        let receiver_pk = Kem::sk_to_pk(&receiver_sk);
+        let reply_sk = PrivateKey::from_bytes(&[0x22; 32]).unwrap();
+        let reply_pk = Kem::sk_to_pk(&reply_sk);
+        assert_eq!(
+            receiver_pk.to_bytes()[..],
+            hex!("044f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa385b6b1b8ead809ca67454d9683fcf2ba03456d6fe2c4abe2b07f0fbdbb2f1c1")
+        );
+        assert_eq!(
+            reply_pk.to_bytes()[..],
+            hex!("04466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276728176c3c6431f8eeda4538dc37c865e2784f3a9e77d044f33e407797e1278a")
+        );
+
+        assert_eq!(mailbox_id(&receiver_pk), hex!("5b6b92b37b765963"));
+        assert_eq!(mailbox_id(&reply_pk), hex!("4c98afd617dc61c1"));
+
+        // Message A: ellswift(enc) || ciphertext || tag, where the plaintext is the compressed
+        // reply key, then the body, then zeros
+        let original = b"cHNidP8BAHECAAAAAQ==";
+        let message_a =
+            seal_message_a(original, &reply_pk, &receiver_pk, &mut FixedRng(0x33)).unwrap();
+        assert_eq!(
+            message_a[..ELLSWIFT_ENCODING_SIZE],
+            hex!("f13f4224816d60f8bac5d1f9c0977d67b19044d544e18fcbf09dbeed26c7b8fd8e66a88b96189848344d25d462df7d7c7803c2bf848cfa24159a4622bc299e4c")
+        );
+        assert_eq!(
+            message_a[PADDED_MESSAGE_BYTES - TAG_SIZE..],
+            hex!("0cec57ad706bba804da4ad01b12b760a")
+        );
+        assert_eq!(
+            Sha256::digest(&message_a)[..],
+            hex!("7b7ab78af98bc83c484426f0ba6829af57c2a6bca8f60da1d8cf9f0f912ff063")
+        );
+        let (body, sender_reply_key) = open_message_a(&message_a, &receiver_sk).unwrap();
+        assert_eq!(sender_reply_key, reply_pk);
+        assert_eq!(&body[..original.len()], original);
+
+        // Message B is sealed in Auth mode from the receiver's key to the reply key
+        let proposal = b"cHNidP8BAJoCAAAAAg==";
+        let message_b = seal_message_b(
+            proposal,
+            (receiver_sk, receiver_pk.clone()),
+            &reply_pk,
+            &mut FixedRng(0x44),
+        )
+        .unwrap();
+        assert_eq!(
+            message_b[..ELLSWIFT_ENCODING_SIZE],
+            hex!("878472eb3c26c688e8162d7479b96fb9945f920f57f4cc1953a4a74b37ccea0798cc311815689cddc1067c4e24b3b49928961de27cb2ce31cf6adbbd6bd98994")
+        );
+        assert_eq!(
+            message_b[PADDED_MESSAGE_BYTES - TAG_SIZE..],
+            hex!("2f11a85d5537d5090f1b1a646dce728a")
+        );
+        assert_eq!(
+            Sha256::digest(&message_b)[..],
+            hex!("b4350eb2b5d046447aa89b3eee6df8c080df71feef244e77f54a3c73f9bb64c3")
+        );
+        let body = open_message_b(&message_b, &reply_sk, &receiver_pk).unwrap();
+        assert_eq!(&body[..proposal.len()], proposal);
+    }
+}
@@ -0,0 +1,346 @@
+//! Signed prekey bundles for recipients who are offline
+//!
+//! This is modelled on the signed prekeys of Signal's X3DH. A recipient has a long-term BIP-340
+//! identity key, which senders know and trust. The recipient periodically generates a
+//! medium-term HPKE keypair (the prekey) and publishes a [`KeyBundle`]: the prekey's public half,
+//! signed by the identity key along with an expiry and the ciphersuite it's meant for. A sender
+//! fetches the bundle, checks it with [`KeyBundle::verify`], and encrypts to the prekey. The sender
+//! sends the prekey ID along with the encapsulated key, so the recipient's [`PrekeyManager`] knows
+//! which private key to use.
+//!
+//! This way the recipient's encryption keys can rotate without their identity changing.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    dhkex::secp256k1::PublicKey,
+    kdf::Kdf as KdfTrait,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    keyring::Keyring,
+    op_mode::{OpModeR, OpModeS},
+    schnorr::{Signature, SigningKey, VerifyingKey},
+    setup::setup_sender,
+    util::{enforce_equal_len, enforce_outbuf_len, tagged_hash, write_u16_be, write_u64_be},
+    Deserializable, HpkeError, Serializable,
+};
+
+use generic_array::typenum;
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// A prekey signed by its owner's identity key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyBundle {
+    /// The identity key of the recipient who owns the prekey
+    pub identity_key: VerifyingKey,
+    /// The recipient's identifier for this prekey. Senders send it alongside the encapsulated key.
+    pub prekey_id: u32,
+    /// The prekey itself
+    pub prekey: PublicKey,
+    /// The time after which senders must not use this prekey. This is in whatever unit the
+    /// application uses, e.g., seconds since the UNIX epoch.
+    pub expiry: u64,
+    /// The KEM, KDF, and AEAD the prekey is meant to be used with
+    pub suite: (u16, u16, u16),
+    /// The identity key's signature over everything else in the bundle
+    pub signature: Signature,
+}
+
+// The wire format is
+//   struct {
+//     opaque identity_key[32];
+//     uint32 prekey_id;
+//     opaque prekey[Npk];
+//     uint64 expiry;
+//     uint16 kem_id;
+//     uint16 kdf_id;
+//     uint16 aead_id;
+//     opaque signature[64];
+//   }
+// and the signature is over hash_HPKE/KeyBundle of everything before the signature.
+
+/// The number of bytes before the signature in a serialized bundle
+const UNSIGNED_BUNDLE_SIZE: usize = 32 + 4 + 65 + 8 + 6;
+
+/// Encodes every field of a bundle except the signature, in wire order
+fn encode_unsigned(
+    identity_key: &VerifyingKey,
+    prekey_id: u32,
+    prekey: &PublicKey,
+    expiry: u64,
+    suite: (u16, u16, u16),
+) -> [u8; UNSIGNED_BUNDLE_SIZE] {
+    let (kem_id, kdf_id, aead_id) = suite;
+    let mut buf = [0u8; UNSIGNED_BUNDLE_SIZE];
+    buf[0..32].copy_from_slice(&identity_key.to_bytes());
+    buf[32..36].copy_from_slice(&prekey_id.to_be_bytes());
+    buf[36..101].copy_from_slice(&prekey.to_bytes());
+    write_u64_be(&mut buf[101..109], expiry);
+    write_u16_be(&mut buf[109..111], kem_id);
+    write_u16_be(&mut buf[111..113], kdf_id);
+    write_u16_be(&mut buf[113..115], aead_id);
+    buf
+}
+
+/// Computes the message that the identity key signs
+fn signed_msg(unsigned: &[u8; UNSIGNED_BUNDLE_SIZE]) -> [u8; 32] {
+    tagged_hash(b"HPKE/KeyBundle", &[unsigned])
+}
+
+impl KeyBundle {
+    fn encode_unsigned(&self) -> [u8; UNSIGNED_BUNDLE_SIZE] {
+        encode_unsigned(
+            &self.identity_key,
+            self.prekey_id,
+            &self.prekey,
+            self.expiry,
+            self.suite,
+        )
+    }
+
+    /// Checks that this bundle is signed by `identity_key`, has not expired at time `now`, and is
+    /// meant for the ciphersuite `(Kem, Kdf, A)`. Senders MUST call this before encrypting to
+    /// `self.prekey`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if all the checks pass. Otherwise returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify<A, Kdf>(&self, identity_key: &VerifyingKey, now: u64) -> Result<(), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        if &self.identity_key != identity_key
+            || now >= self.expiry
+            || self.suite != (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID)
+        {
+            return Err(HpkeError::ValidationError);
+        }
+        identity_key.verify(&signed_msg(&self.encode_unsigned()), &self.signature)
+    }
+}
+
+impl Serializable for KeyBundle {
+    // 32 + 4 + 65 + 8 + 6 + 64
+    type OutputSize = typenum::U179;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        buf[..UNSIGNED_BUNDLE_SIZE].copy_from_slice(&self.encode_unsigned());
+        self.signature.write_exact(&mut buf[UNSIGNED_BUNDLE_SIZE..]);
+    }
+}
+
+impl Deserializable for KeyBundle {
+    // This doesn't check the signature. Use KeyBundle::verify for that.
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        let be_u16 = |i: usize| u16::from_be_bytes([encoded[i], encoded[i + 1]]);
+        let mut prekey_id = [0u8; 4];
+        prekey_id.copy_from_slice(&encoded[32..36]);
+        let mut expiry = [0u8; 8];
+        expiry.copy_from_slice(&encoded[101..109]);
+
+        Ok(KeyBundle {
+            identity_key: VerifyingKey::from_bytes(&encoded[0..32])?,
+            prekey_id: u32::from_be_bytes(prekey_id),
+            prekey: PublicKey::from_bytes(&encoded[36..101])?,
+            expiry: u64::from_be_bytes(expiry),
+            suite: (be_u16(109), be_u16(111), be_u16(113)),
+            signature: Signature::from_bytes(&encoded[UNSIGNED_BUNDLE_SIZE..])?,
+        })
+    }
+}
+
+/// Verifies `bundle` and, if it's valid, initiates an encryption context to its prekey. The
+/// sender MUST send `bundle.prekey_id` to the recipient along with the encapsulated key.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated public key and an encryption context. If the bundle fails
+/// `KeyBundle::verify`, returns `Err(HpkeError::ValidationError)`. If an error happened during key
+/// encapsulation, returns `Err(HpkeError::EncapError)`.
+pub fn setup_sender_to_bundle<A, Kdf, R>(
+    mode: &OpModeS<Kem>,
+    bundle: &KeyBundle,
+    identity_key: &VerifyingKey,
+    info: &[u8],
+    now: u64,
+    csprng: &mut R,
+) -> Result<(EncappedKey, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    R: CryptoRng + RngCore,
+{
+    bundle.verify::<A, Kdf>(identity_key, now)?;
+    setup_sender::<A, Kdf, Kem, R>(mode, &bundle.prekey, info, csprng)
+}
+
+/// The recipient's side of signed prekeys. This holds the identity key and every prekey that
+/// hasn't been pruned, and finds the right prekey for an incoming encapsulated key by its ID.
+pub struct PrekeyManager {
+    identity: SigningKey,
+    prekeys: Keyring<Kem>,
+    grace_period: u64,
+}
+
+impl PrekeyManager {
+    /// Makes a manager with no prekeys. Messages sent shortly before a prekey expires can arrive
+    /// after it expires, so each prekey is kept for `grace_period` after its expiry.
+    pub fn new(identity: SigningKey, grace_period: u64) -> PrekeyManager {
+        PrekeyManager {
+            identity,
+            prekeys: Keyring::new(),
+            grace_period,
+        }
+    }
+
+    /// Returns the identity key that senders should trust
+    pub fn identity_key(&self) -> VerifyingKey {
+        self.identity.verifying_key()
+    }
+
+    /// Generates a new prekey for the ciphersuite `(Kem, Kdf, A)` that expires at `expiry`, and
+    /// returns its signed bundle for publishing
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(bundle)` on success. If a prekey with the same ID is still held, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn generate<A, Kdf, R>(
+        &mut self,
+        prekey_id: u32,
+        expiry: u64,
+        csprng: &mut R,
+    ) -> Result<KeyBundle, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        R: CryptoRng + RngCore,
+    {
+        let (sk, pk) = Kem::gen_keypair(csprng);
+        self.prekeys.insert(
+            &prekey_id.to_be_bytes(),
+            sk,
+            None,
+            Some(expiry.saturating_add(self.grace_period)),
+        )?;
+
+        let identity_key = self.identity.verifying_key();
+        let suite = (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID);
+        let unsigned = encode_unsigned(&identity_key, prekey_id, &pk, expiry, suite);
+        let mut aux_rand = [0u8; 32];
+        csprng.fill_bytes(&mut aux_rand);
+        let signature = self.identity.sign(&signed_msg(&unsigned), &aux_rand);
+
+        Ok(KeyBundle {
+            identity_key,
+            prekey_id,
+            prekey: pk,
+            expiry,
+            suite,
+            signature,
+        })
+    }
+
+    /// Initiates a decryption context for a message sent to the prekey with ID `prekey_id`
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns a decryption context. If no prekey with that ID is held, or its grace
+    /// period is over at time `now`, or an error happened during key decapsulation, returns
+    /// `Err(HpkeError::DecapError)`.
+    pub fn setup_receiver<A, Kdf>(
+        &self,
+        mode: &OpModeR<Kem>,
+        prekey_id: u32,
+        encapped_key: &EncappedKey,
+        info: &[u8],
+        now: u64,
+    ) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        self.prekeys
+            .setup_receiver(mode, &prekey_id.to_be_bytes(), encapped_key, info, now)
+    }
+
+    /// Deletes every prekey whose grace period is over at time `now`
+    pub fn prune(&mut self, now: u64) {
+        self.prekeys.remove_expired(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, test_util::aead_ctx_eq};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Tests the whole prekey flow: publish, verify, encrypt, decrypt, expire
+    #[test]
+    fn test_prekey_bundles() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"leave a message after the tone";
+
+        let mut manager = PrekeyManager::new(SigningKey::gen(&mut csprng), 50);
+        let identity_key = manager.identity_key();
+        let bundle1 = manager.generate::<A, Kdf, _>(1, 100, &mut csprng).unwrap();
+        let bundle2 = manager.generate::<A, Kdf, _>(2, 200, &mut csprng).unwrap();
+        assert!(manager.generate::<A, Kdf, _>(2, 300, &mut csprng).is_err());
+
+        // The bundle survives serialization and the sender can use it
+        let bundle1 = KeyBundle::from_bytes(&bundle1.to_bytes()).unwrap();
+        let (encapped_key, mut sender_ctx) = setup_sender_to_bundle::<A, Kdf, _>(
+            &OpModeS::Base,
+            &bundle1,
+            &identity_key,
+            info,
+            90,
+            &mut csprng,
+        )
+        .unwrap();
+
+        // The recipient comes online after the prekey expired, but within the grace period
+        let mut receiver_ctx = manager
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, bundle1.prekey_id, &encapped_key, info, 120)
+            .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // The wrong prekey ID doesn't work
+        let mut receiver_ctx = manager
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, bundle2.prekey_id, &encapped_key, info, 120)
+            .unwrap();
+        assert!(!aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Senders reject expired bundles, bundles from other identities, bundles for other
+        // ciphersuites, and tampered bundles
+        let other_identity = SigningKey::gen(&mut csprng).verifying_key();
+        assert!(bundle1.verify::<A, Kdf>(&identity_key, 100).is_err());
+        assert!(bundle2.verify::<A, Kdf>(&other_identity, 0).is_err());
+        assert!(bundle2
+            .verify::<A, crate::kdf::HkdfSha384>(&identity_key, 0)
+            .is_err());
+        let mut tampered = bundle2.clone();
+        tampered.expiry += 1000;
+        assert!(tampered.verify::<A, Kdf>(&identity_key, 0).is_err());
+        bundle2.verify::<A, Kdf>(&identity_key, 0).unwrap();
+
+        // After the grace period, the prekey is gone
+        manager.prune(150);
+        assert!(manager
+            .setup_receiver::<A, Kdf>(&OpModeR::Base, bundle1.prekey_id, &encapped_key, info, 150)
+            .is_err());
+    }
+}
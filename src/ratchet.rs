@@ -0,0 +1,634 @@
+//! Double-ratchet messaging sessions
+//!
+//! A [`RatchetSession`] starts with an HPKE setup to the responder's public key and then runs the
+//! Signal double ratchet on top of it. Every time the direction of conversation flips, the new
+//! sender picks a fresh secp256k1 ratchet key and mixes a DH with the other side's latest ratchet
+//! key into the root key (the DH ratchet). Within a run of messages in one direction, each message
+//! key is derived one-way from the one before it (the symmetric ratchet). Compromising a session's
+//! state exposes neither earlier messages nor, once the other side has replied, later ones.
+//!
+//! Messages may arrive out of order. Keys for messages that were skipped over are kept until those
+//! messages show up, up to [`MAX_SKIP`] at a time.
+//!
+//! The responder can't send until it has received the initiator's first message.
+
+use crate::{
+    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce},
+    dhkex::{
+        secp256k1::{PrivateKey, PublicKey, Secp256k1},
+        DhKeyExchange,
+    },
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender, ExporterSecret},
+    util::{enforce_equal_len, enforce_outbuf_len, full_suite_id, split_checked, write_u16_be},
+    Deserializable, HpkeError, Serializable, Vec,
+};
+
+use core::marker::PhantomData;
+
+use generic_array::typenum;
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// The most message keys a session will skip over, or hold on to, at once
+pub const MAX_SKIP: u32 = 1000;
+
+// root_key = Context.Export("ratchet root", 32)
+//
+// def KDF_RK(rk, dh_out):
+//   prk = LabeledExtract(rk, "root", dh_out)
+//   return (LabeledExpand(prk, "root_key", "", 32), LabeledExpand(prk, "chain_key", "", 32))
+//
+// def KDF_CK(ck):
+//   prk = LabeledExtract("", "chain", ck)
+//   return (LabeledExpand(prk, "chain_key", "", 32), LabeledExpand(prk, "msg_key", "", 32))
+//
+// def MessageCtx(mk):
+//   prk = LabeledExtract("", "msg", mk)
+//   return (LabeledExpand(prk, "key", "", Nk), LabeledExpand(prk, "base_nonce", "", Nn))
+//
+// Everything uses the full suite ID of the session's ciphersuite.
+
+/// Derives a new root key and chain key from the current root key and a DH result
+fn kdf_rk<A: Aead, Kdf: KdfTrait>(root_key: &[u8; 32], dh_out: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let suite_id = full_suite_id::<A, Kdf, Kem>();
+    let (_, prk) = labeled_extract::<Kdf>(root_key, &suite_id, b"root", dh_out);
+
+    // These only fail if the output is 255x the digest size, which it isn't
+    let mut new_root_key = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    prk.labeled_expand(&suite_id, b"root_key", b"", &mut new_root_key)
+        .expect("root key is way too big");
+    prk.labeled_expand(&suite_id, b"chain_key", b"", &mut chain_key)
+        .expect("chain key is way too big");
+
+    (new_root_key, chain_key)
+}
+
+/// Steps a chain key forward. Returns the next chain key and the current message key.
+fn kdf_ck<A: Aead, Kdf: KdfTrait>(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let suite_id = full_suite_id::<A, Kdf, Kem>();
+    let (_, prk) = labeled_extract::<Kdf>(&[], &suite_id, b"chain", chain_key);
+
+    // These only fail if the output is 255x the digest size, which it isn't
+    let mut next_chain_key = [0u8; 32];
+    let mut msg_key = [0u8; 32];
+    prk.labeled_expand(&suite_id, b"chain_key", b"", &mut next_chain_key)
+        .expect("chain key is way too big");
+    prk.labeled_expand(&suite_id, b"msg_key", b"", &mut msg_key)
+        .expect("message key is way too big");
+
+    (next_chain_key, msg_key)
+}
+
+/// Makes the single-message encryption context for a message key
+fn msg_ctx<A: Aead, Kdf: KdfTrait>(msg_key: &[u8; 32]) -> AeadCtx<A, Kdf, Kem> {
+    let suite_id = full_suite_id::<A, Kdf, Kem>();
+    let (_, prk) = labeled_extract::<Kdf>(&[], &suite_id, b"msg", msg_key);
+
+    // These only fail if the output is 255x the digest size, which it isn't
+    let mut key = AeadKey::<A>::default();
+    let mut base_nonce = AeadNonce::<A>::default();
+    prk.labeled_expand(&suite_id, b"key", b"", key.0.as_mut_slice())
+        .expect("aead key len is way too big");
+    prk.labeled_expand(&suite_id, b"base_nonce", b"", base_nonce.0.as_mut_slice())
+        .expect("nonce len is way too big");
+
+    // Message keys are used once, so there's nothing to export
+    AeadCtx::new(&key, base_nonce, ExporterSecret::default())
+}
+
+/// Concatenates the header and the caller's associated data. The header is fixed-length, so this
+/// is unambiguous.
+fn header_aad(header: &MessageHeader, aad: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MessageHeader::size() + aad.len());
+    out.extend_from_slice(&header.to_bytes());
+    out.extend_from_slice(aad);
+    out
+}
+
+/// The cleartext header sent along with every ratchet message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageHeader {
+    /// The sender's current ratchet public key
+    pub ratchet_key: PublicKey,
+    /// The number of messages the sender sent under its previous ratchet key
+    pub prev_chain_len: u32,
+    /// The index of this message under the current ratchet key
+    pub msg_num: u32,
+}
+
+impl Serializable for MessageHeader {
+    // opaque ratchet_key[Npk] || uint32 prev_chain_len || uint32 msg_num
+    type OutputSize = typenum::U73;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        self.ratchet_key.write_exact(&mut buf[0..65]);
+        buf[65..69].copy_from_slice(&self.prev_chain_len.to_be_bytes());
+        buf[69..73].copy_from_slice(&self.msg_num.to_be_bytes());
+    }
+}
+
+impl Deserializable for MessageHeader {
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        Ok(MessageHeader {
+            ratchet_key: PublicKey::from_bytes(&encoded[0..65])?,
+            prev_chain_len: read_u32_be(&encoded[65..69]),
+            msg_num: read_u32_be(&encoded[69..73]),
+        })
+    }
+}
+
+/// Reads a big-endian `u32` from a 4-byte slice
+fn read_u32_be(buf: &[u8]) -> u32 {
+    let mut arr = [0u8; 4];
+    arr.copy_from_slice(buf);
+    u32::from_be_bytes(arr)
+}
+
+/// One end of a double-ratchet session. All secrets are zeroized on drop.
+pub struct RatchetSession<A: Aead, Kdf: KdfTrait> {
+    /// Our current ratchet keypair
+    sk_self: PrivateKey,
+    pk_self: PublicKey,
+    /// The other side's latest ratchet public key, if we've seen one
+    pk_remote: Option<PublicKey>,
+    root_key: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    /// Messages sent and received in the current chains, and sent in the previous sending chain
+    send_num: u32,
+    recv_num: u32,
+    prev_send_num: u32,
+    /// Message keys for messages we skipped over, oldest first
+    skipped: Vec<(PublicKey, u32, [u8; 32])>,
+    _marker: PhantomData<(A, Kdf)>,
+}
+
+impl<A: Aead, Kdf: KdfTrait> Clone for RatchetSession<A, Kdf> {
+    fn clone(&self) -> RatchetSession<A, Kdf> {
+        RatchetSession {
+            sk_self: self.sk_self.clone(),
+            pk_self: self.pk_self.clone(),
+            pk_remote: self.pk_remote.clone(),
+            root_key: self.root_key,
+            send_chain: self.send_chain,
+            recv_chain: self.recv_chain,
+            send_num: self.send_num,
+            recv_num: self.recv_num,
+            prev_send_num: self.prev_send_num,
+            skipped: self.skipped.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait> Drop for RatchetSession<A, Kdf> {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.send_chain.zeroize();
+        self.recv_chain.zeroize();
+        for (_, _, msg_key) in self.skipped.iter_mut() {
+            msg_key.zeroize();
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait> RatchetSession<A, Kdf> {
+    /// Makes a session with the given root key and ratchet keypair, and no chains
+    fn from_root(root_key: [u8; 32], sk_self: PrivateKey) -> RatchetSession<A, Kdf> {
+        RatchetSession {
+            pk_self: Secp256k1::sk_to_pk(&sk_self),
+            sk_self,
+            pk_remote: None,
+            root_key,
+            send_chain: None,
+            recv_chain: None,
+            send_num: 0,
+            recv_num: 0,
+            prev_send_num: 0,
+            skipped: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Starts a session with the holder of `pk_recip`. The HPKE setup is done in `mode`, so the
+    /// initiator can authenticate itself with a PSK or identity key. The returned encapsulated key
+    /// MUST reach the responder along with the first message.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the encapsulated key and the session. If an error happened during key
+    /// encapsulation, returns `Err(HpkeError::EncapError)`.
+    pub fn initiate<R: CryptoRng + RngCore>(
+        mode: &OpModeS<Kem>,
+        pk_recip: &PublicKey,
+        info: &[u8],
+        csprng: &mut R,
+    ) -> Result<(EncappedKey, RatchetSession<A, Kdf>), HpkeError> {
+        let (encapped_key, ctx): (_, AeadCtxS<A, Kdf, Kem>) =
+            setup_sender(mode, pk_recip, info, csprng)?;
+        let mut root_key = [0u8; 32];
+        ctx.export(b"ratchet root", &mut root_key)?;
+
+        // The responder's first ratchet key is its HPKE key. Ratchet against it right away so we
+        // have a sending chain.
+        let (sk_self, _) = Kem::gen_keypair(csprng);
+        let mut session = RatchetSession::from_root(root_key, sk_self);
+        session.pk_remote = Some(pk_recip.clone());
+        let dh_out =
+            Secp256k1::dh(&session.sk_self, pk_recip).map_err(|_| HpkeError::EncapError)?;
+        let (root_key, send_chain) = kdf_rk::<A, Kdf>(&session.root_key, &dh_out.0);
+        session.root_key = root_key;
+        session.send_chain = Some(send_chain);
+
+        Ok((encapped_key, session))
+    }
+
+    /// Accepts a session started with `initiate`. `sk_recip` becomes the responder's first ratchet
+    /// key, and is replaced as soon as the responder replies.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the session. If an error happened during key decapsulation, returns
+    /// `Err(HpkeError::DecapError)`.
+    pub fn accept(
+        mode: &OpModeR<Kem>,
+        sk_recip: &PrivateKey,
+        encapped_key: &EncappedKey,
+        info: &[u8],
+    ) -> Result<RatchetSession<A, Kdf>, HpkeError> {
+        let ctx: AeadCtxR<A, Kdf, Kem> = setup_receiver(mode, sk_recip, encapped_key, info)?;
+        let mut root_key = [0u8; 32];
+        ctx.export(b"ratchet root", &mut root_key)?;
+
+        Ok(RatchetSession::from_root(root_key, sk_recip.clone()))
+    }
+
+    /// Encrypts a message. `aad` is authenticated along with the returned header.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns the header and ciphertext to send. If this is the responder and it
+    /// hasn't received anything yet, returns `Err(HpkeError::SealError)`. If 2^32 messages have been
+    /// sent in a row without a reply, returns `Err(HpkeError::MessageLimitReached)`.
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<(MessageHeader, Vec<u8>), HpkeError> {
+        let chain_key = self.send_chain.as_ref().ok_or(HpkeError::SealError)?;
+        let next_num = self
+            .send_num
+            .checked_add(1)
+            .ok_or(HpkeError::MessageLimitReached)?;
+        let (next_chain_key, mut msg_key) = kdf_ck::<A, Kdf>(chain_key);
+
+        let header = MessageHeader {
+            ratchet_key: self.pk_self.clone(),
+            prev_chain_len: self.prev_send_num,
+            msg_num: self.send_num,
+        };
+        let mut ctx: AeadCtxS<A, Kdf, Kem> = msg_ctx::<A, Kdf>(&msg_key).into();
+        msg_key.zeroize();
+        let ciphertext = ctx.seal(plaintext, &header_aad(&header, aad))?;
+
+        self.send_chain = Some(next_chain_key);
+        self.send_num = next_num;
+        Ok((header, ciphertext))
+    }
+
+    /// Decrypts a message. If the header carries a new ratchet key, this does a DH ratchet step,
+    /// which needs fresh randomness. If decryption fails, the session is left unchanged.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If the message can't be decrypted, e.g., because it was
+    /// tampered with, was already received, or would mean skipping more than `MAX_SKIP` messages,
+    /// returns `Err(HpkeError::OpenError)`.
+    pub fn decrypt<R: CryptoRng + RngCore>(
+        &mut self,
+        header: &MessageHeader,
+        ciphertext: &[u8],
+        aad: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, HpkeError> {
+        // Work on a copy so that a bad message can't advance our state
+        let mut next = self.clone();
+        let mut msg_key = next.recv_msg_key(header, csprng)?;
+        let mut ctx: AeadCtxR<A, Kdf, Kem> = msg_ctx::<A, Kdf>(&msg_key).into();
+        msg_key.zeroize();
+        let plaintext = ctx.open(ciphertext, &header_aad(header, aad))?;
+
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Finds or derives the message key for `header`, advancing the ratchets as needed
+    fn recv_msg_key<R: CryptoRng + RngCore>(
+        &mut self,
+        header: &MessageHeader,
+        csprng: &mut R,
+    ) -> Result<[u8; 32], HpkeError> {
+        // Is this a message we skipped over earlier?
+        if let Some(idx) = self
+            .skipped
+            .iter()
+            .position(|(pk, n, _)| *pk == header.ratchet_key && *n == header.msg_num)
+        {
+            let (_, _, msg_key) = self.skipped.remove(idx);
+            return Ok(msg_key);
+        }
+
+        // A new ratchet key means the other side has heard from us. Finish off the old receiving
+        // chain and do a DH ratchet step.
+        if self.pk_remote.as_ref() != Some(&header.ratchet_key) {
+            self.skip_until(header.prev_chain_len)?;
+            self.dh_ratchet(&header.ratchet_key, csprng)?;
+        }
+
+        self.skip_until(header.msg_num)?;
+        let chain_key = self.recv_chain.as_ref().ok_or(HpkeError::OpenError)?;
+        let (next_chain_key, msg_key) = kdf_ck::<A, Kdf>(chain_key);
+        self.recv_chain = Some(next_chain_key);
+        self.recv_num = header.msg_num.checked_add(1).ok_or(HpkeError::OpenError)?;
+        Ok(msg_key)
+    }
+
+    /// Stores the message keys of the current receiving chain up to, but not including, `until`
+    fn skip_until(&mut self, until: u32) -> Result<(), HpkeError> {
+        let (pk_remote, mut chain_key) = match (&self.pk_remote, self.recv_chain) {
+            (Some(pk), Some(ck)) => (pk.clone(), ck),
+            // No receiving chain yet, so nothing to skip
+            _ => return Ok(()),
+        };
+        // A message from the past that isn't in `skipped` was either received already or is from
+        // too far back
+        if until < self.recv_num {
+            return Err(HpkeError::OpenError);
+        }
+        if until - self.recv_num > MAX_SKIP {
+            return Err(HpkeError::OpenError);
+        }
+
+        while self.recv_num < until {
+            let (next_chain_key, msg_key) = kdf_ck::<A, Kdf>(&chain_key);
+            self.skipped
+                .push((pk_remote.clone(), self.recv_num, msg_key));
+            chain_key = next_chain_key;
+            self.recv_num += 1;
+        }
+        self.recv_chain = Some(chain_key);
+        chain_key.zeroize();
+
+        // Forget the oldest skipped keys if we're holding too many
+        let num_skipped = self.skipped.len();
+        if num_skipped > MAX_SKIP as usize {
+            for (_, _, mut msg_key) in self.skipped.drain(..num_skipped - MAX_SKIP as usize) {
+                msg_key.zeroize();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Does a DH ratchet step to the other side's new ratchet key
+    fn dh_ratchet<R: CryptoRng + RngCore>(
+        &mut self,
+        pk_remote: &PublicKey,
+        csprng: &mut R,
+    ) -> Result<(), HpkeError> {
+        self.prev_send_num = self.send_num;
+        self.send_num = 0;
+        self.recv_num = 0;
+        self.pk_remote = Some(pk_remote.clone());
+
+        // Receiving chain from our old ratchet key, then a sending chain from a new one
+        let dh_out = Secp256k1::dh(&self.sk_self, pk_remote).map_err(|_| HpkeError::OpenError)?;
+        let (root_key, recv_chain) = kdf_rk::<A, Kdf>(&self.root_key, &dh_out.0);
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+
+        let (sk_self, pk_self) = Kem::gen_keypair(csprng);
+        self.sk_self = sk_self;
+        self.pk_self = pk_self;
+        let dh_out = Secp256k1::dh(&self.sk_self, pk_remote).map_err(|_| HpkeError::OpenError)?;
+        let (root_key, send_chain) = kdf_rk::<A, Kdf>(&self.root_key, &dh_out.0);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+
+        Ok(())
+    }
+
+    // The serialized state is
+    //   struct {
+    //     uint16 kem_id;
+    //     uint16 kdf_id;
+    //     uint16 aead_id;
+    //     opaque sk_self[Nsk];
+    //     uint8 flags;  // bit 0: pk_remote, bit 1: send_chain, bit 2: recv_chain
+    //     opaque pk_remote[Npk];  // zeros if absent
+    //     opaque root_key[32];
+    //     opaque send_chain[32];  // zeros if absent
+    //     opaque recv_chain[32];  // zeros if absent
+    //     uint32 send_num;
+    //     uint32 recv_num;
+    //     uint32 prev_send_num;
+    //     uint32 num_skipped;
+    //     struct {
+    //       opaque ratchet_key[Npk];
+    //       uint32 msg_num;
+    //       opaque msg_key[32];
+    //     } skipped[num_skipped];
+    //   }
+
+    /// Serializes the session state, so it can be saved and resumed with `from_bytes`. The output
+    /// contains every secret in the session. Protect it accordingly, and don't keep old copies
+    /// around, or forward secrecy is lost.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ids = [0u8; 6];
+        write_u16_be(&mut ids[0..2], Kem::KEM_ID);
+        write_u16_be(&mut ids[2..4], Kdf::KDF_ID);
+        write_u16_be(&mut ids[4..6], A::AEAD_ID);
+        let flags = (self.pk_remote.is_some() as u8)
+            | (self.send_chain.is_some() as u8) << 1
+            | (self.recv_chain.is_some() as u8) << 2;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&ids);
+        out.extend_from_slice(&self.sk_self.to_bytes());
+        out.push(flags);
+        match &self.pk_remote {
+            Some(pk) => out.extend_from_slice(&pk.to_bytes()),
+            None => out.extend_from_slice(&[0u8; 65]),
+        }
+        out.extend_from_slice(&self.root_key);
+        out.extend_from_slice(&self.send_chain.unwrap_or([0u8; 32]));
+        out.extend_from_slice(&self.recv_chain.unwrap_or([0u8; 32]));
+        out.extend_from_slice(&self.send_num.to_be_bytes());
+        out.extend_from_slice(&self.recv_num.to_be_bytes());
+        out.extend_from_slice(&self.prev_send_num.to_be_bytes());
+        out.extend_from_slice(&(self.skipped.len() as u32).to_be_bytes());
+        for (pk, msg_num, msg_key) in &self.skipped {
+            out.extend_from_slice(&pk.to_bytes());
+            out.extend_from_slice(&msg_num.to_be_bytes());
+            out.extend_from_slice(msg_key);
+        }
+        out
+    }
+
+    /// Restores a session serialized with `to_bytes`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(session)` on success. If `encoded` is malformed, or was made by a session with a
+    /// different ciphersuite, returns an error.
+    pub fn from_bytes(encoded: &[u8]) -> Result<RatchetSession<A, Kdf>, HpkeError> {
+        let read_key = |buf: &[u8]| {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(buf);
+            key
+        };
+
+        let (ids, rest) = split_checked(encoded, 6)?;
+        if ids[0..2] != Kem::KEM_ID.to_be_bytes()
+            || ids[2..4] != Kdf::KDF_ID.to_be_bytes()
+            || ids[4..6] != A::AEAD_ID.to_be_bytes()
+        {
+            return Err(HpkeError::ValidationError);
+        }
+        let (sk_self, rest) = split_checked(rest, PrivateKey::size())?;
+        let (flags, rest) = split_checked(rest, 1)?;
+        let (pk_remote, rest) = split_checked(rest, PublicKey::size())?;
+        let (root_key, rest) = split_checked(rest, 32)?;
+        let (send_chain, rest) = split_checked(rest, 32)?;
+        let (recv_chain, rest) = split_checked(rest, 32)?;
+        let (nums, mut rest) = split_checked(rest, 16)?;
+
+        let flags = flags[0];
+        if flags > 0b111 {
+            return Err(HpkeError::ValidationError);
+        }
+        let mut session =
+            RatchetSession::from_root(read_key(root_key), PrivateKey::from_bytes(sk_self)?);
+        if flags & 1 != 0 {
+            session.pk_remote = Some(PublicKey::from_bytes(pk_remote)?);
+        }
+        if flags & 2 != 0 {
+            session.send_chain = Some(read_key(send_chain));
+        }
+        if flags & 4 != 0 {
+            session.recv_chain = Some(read_key(recv_chain));
+        }
+        session.send_num = read_u32_be(&nums[0..4]);
+        session.recv_num = read_u32_be(&nums[4..8]);
+        session.prev_send_num = read_u32_be(&nums[8..12]);
+
+        let num_skipped = read_u32_be(&nums[12..16]);
+        if num_skipped > MAX_SKIP {
+            return Err(HpkeError::ValidationError);
+        }
+        for _ in 0..num_skipped {
+            let (pk, r) = split_checked(rest, PublicKey::size())?;
+            let (msg_num, r) = split_checked(r, 4)?;
+            let (msg_key, r) = split_checked(r, 32)?;
+            session.skipped.push((
+                PublicKey::from_bytes(pk)?,
+                read_u32_be(msg_num),
+                read_key(msg_key),
+            ));
+            rest = r;
+        }
+        if !rest.is_empty() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Runs a conversation with replies, out-of-order delivery, a replay, tampering, and a save and
+    /// restore of the session state
+    #[test]
+    fn test_ratchet_conversation() {
+        let mut csprng = StdRng::from_entropy();
+        let info = b"wallet chat";
+        let (sk_bob, pk_bob) = Kem::gen_keypair(&mut csprng);
+
+        let (encapped_key, mut alice) =
+            RatchetSession::<A, Kdf>::initiate(&OpModeS::Base, &pk_bob, info, &mut csprng).unwrap();
+        let mut bob =
+            RatchetSession::<A, Kdf>::accept(&OpModeR::Base, &sk_bob, &encapped_key, info).unwrap();
+
+        // Bob can't speak first
+        assert!(bob.encrypt(b"hello?", b"").is_err());
+
+        // Alice sends three messages. Bob gets them out of order.
+        let texts = [&b"one"[..], b"two", b"three"];
+        let msgs: Vec<_> = texts
+            .iter()
+            .map(|m| alice.encrypt(m, b"").unwrap())
+            .collect();
+        for &i in &[2, 0] {
+            let (header, ct) = &msgs[i];
+            let pt = bob.decrypt(header, ct, b"", &mut csprng).unwrap();
+            assert_eq!(pt, texts[i]);
+        }
+
+        // Tampering and replays fail without disturbing the session
+        let (header, ct) = &msgs[1];
+        let mut bad_ct = ct.clone();
+        bad_ct[0] ^= 1;
+        assert!(bob.decrypt(header, &bad_ct, b"", &mut csprng).is_err());
+        assert!(bob.decrypt(header, ct, b"wrong aad", &mut csprng).is_err());
+        assert!(bob
+            .decrypt(&msgs[0].0, &msgs[0].1, b"", &mut csprng)
+            .is_err());
+
+        // Bob saves and restores his state, then gets the skipped message
+        let mut bob = RatchetSession::<A, Kdf>::from_bytes(&bob.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(header, ct, b"", &mut csprng).unwrap(), b"two");
+
+        // Replies ratchet both sides forward, each time with a fresh key
+        let mut last_key = msgs[0].0.ratchet_key.clone();
+        for round in 0..3 {
+            let (header, ct) = bob.encrypt(b"pong", &[round]).unwrap();
+            assert_ne!(header.ratchet_key, last_key);
+            let bytes = header.to_bytes();
+            let header = MessageHeader::from_bytes(&bytes).unwrap();
+            let pt = alice.decrypt(&header, &ct, &[round], &mut csprng).unwrap();
+            assert_eq!(pt, b"pong");
+
+            let (header, ct) = alice.encrypt(b"ping", &[round]).unwrap();
+            assert_ne!(header.ratchet_key, last_key);
+            last_key = header.ratchet_key.clone();
+            let pt = bob.decrypt(&header, &ct, &[round], &mut csprng).unwrap();
+            assert_eq!(pt, b"ping");
+        }
+
+        // Skipping too far ahead is refused
+        let (mut header, ct) = alice.encrypt(b"far future", b"").unwrap();
+        header.msg_num = MAX_SKIP + 1;
+        assert!(bob.decrypt(&header, &ct, b"", &mut csprng).is_err());
+    }
+}
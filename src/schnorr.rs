@@ -0,0 +1,134 @@
+//! BIP-340 Schnorr signing keys and signatures
+//!
+//! These are the keys a sender uses to sign over HPKE values, e.g., in
+//! [`crate::auth_sig::setup_sender_signed`]. They are separate from the HPKE `PrivateKey` and
+//! `PublicKey` types because a BIP-340 public key is x-only.
+
+use crate::{
+    dhkex::secp256k1::PrivateKey,
+    kem::{Kem as KemTrait, SecpK256HkdfSha256},
+    util::{enforce_equal_len, enforce_outbuf_len},
+    Deserializable, HpkeError, Serializable,
+};
+
+use generic_array::typenum;
+use rand_core::{CryptoRng, RngCore};
+use secp256k1::{schnorr, Keypair, Message, SecretKey, XOnlyPublicKey, SECP256K1};
+
+/// A BIP-340 signing key
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey(pub(crate) Keypair);
+
+/// A BIP-340 verifying key, i.e., an x-only public key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifyingKey(pub(crate) XOnlyPublicKey);
+
+/// A BIP-340 signature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature(pub(crate) schnorr::Signature);
+
+impl SigningKey {
+    /// Generates a random signing key using the given RNG
+    pub fn gen<R: CryptoRng + RngCore>(csprng: &mut R) -> SigningKey {
+        let (sk, _) = SecpK256HkdfSha256::gen_keypair(csprng);
+        SigningKey::from_private_key(&sk)
+    }
+
+    /// Uses the given HPKE private key as a signing key. Reusing one key for both HPKE and
+    /// signatures is NOT RECOMMENDED, but this is handy when the key is a Bitcoin identity key.
+    pub fn from_private_key(sk: &PrivateKey) -> SigningKey {
+        SigningKey(Keypair::from_secret_key(SECP256K1, &sk.0))
+    }
+
+    /// Returns the verifying key for this signing key
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.x_only_public_key().0)
+    }
+
+    /// Signs the 32-byte message `msg`. `aux_rand` SHOULD be fresh randomness.
+    pub fn sign(&self, msg: &[u8; 32], aux_rand: &[u8; 32]) -> Signature {
+        let msg = Message::from_digest(*msg);
+        Signature(SECP256K1.sign_schnorr_with_aux_rand(&msg, &self.0, aux_rand))
+    }
+}
+
+impl VerifyingKey {
+    /// Verifies a signature on the 32-byte message `msg`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if the signature is valid. Otherwise returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify(&self, msg: &[u8; 32], sig: &Signature) -> Result<(), HpkeError> {
+        let msg = Message::from_digest(*msg);
+        SECP256K1
+            .verify_schnorr(&sig.0, &msg, &self.0)
+            .map_err(|_| HpkeError::ValidationError)
+    }
+}
+
+impl Serializable for SigningKey {
+    // BIP-340 secret keys are 32 bytes
+    type OutputSize = typenum::U32;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        buf.copy_from_slice(&self.0.secret_bytes());
+    }
+}
+
+impl Deserializable for SigningKey {
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        let sk = SecretKey::from_slice(encoded).map_err(|_| HpkeError::ValidationError)?;
+        Ok(SigningKey(Keypair::from_secret_key(SECP256K1, &sk)))
+    }
+}
+
+impl Serializable for VerifyingKey {
+    // BIP-340 public keys are x-only, so 32 bytes
+    type OutputSize = typenum::U32;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        buf.copy_from_slice(&self.0.serialize());
+    }
+}
+
+impl Deserializable for VerifyingKey {
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        XOnlyPublicKey::from_slice(encoded)
+            .map(VerifyingKey)
+            .map_err(|_| HpkeError::ValidationError)
+    }
+}
+
+impl Serializable for Signature {
+    // BIP-340 signatures are 64 bytes
+    type OutputSize = typenum::U64;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        buf.copy_from_slice(self.0.as_ref());
+    }
+}
+
+impl Deserializable for Signature {
+    // Range checks on the signature happen during verification
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        enforce_equal_len(Self::size(), encoded.len())?;
+
+        schnorr::Signature::from_slice(encoded)
+            .map(Signature)
+            .map_err(|_| HpkeError::ValidationError)
+    }
+}
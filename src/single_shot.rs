@@ -81,6 +81,45 @@ where
     Ok((encapped_key, ciphertext))
 }
 
+/// Does a `setup_sender` and `AeadCtxS::seal_padded` in one shot. That is, it does a key
+/// encapsulation to the specified recipient, pads the provided plaintext according to `policy`, and
+/// encrypts it. See `setup::setup_sender` and `AeadCtxS::seal_padded` for more detail.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, ciphertext))` on success. If an error happened during key
+/// encapsulation, returns `Err(HpkeError::EncapError)`. If `policy` can't pad a plaintext of this
+/// length, returns `Err(HpkeError::ValidationError)`. If an error happened during encryption,
+/// returns `Err(HpkeError::SealError)`.
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub fn single_shot_seal_padded<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem>,
+    pk_recip: &Kem::PublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    policy: &crate::padding::PaddingPolicy,
+    csprng: &mut R,
+) -> Result<(Kem::EncappedKey, crate::Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Check the policy before doing any public key operations
+    policy.padded_len(plaintext.len())?;
+
+    // Encap a key
+    let (encapped_key, mut aead_ctx) =
+        setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+    // Pad and encrypt
+    let ciphertext = aead_ctx.seal_padded(plaintext, aad, policy)?;
+
+    Ok((encapped_key, ciphertext))
+}
+
 // RFC 9180 §6.1
 // def OpenAuthPSK(enc, skR, info, aad, ct, psk, psk_id, pkS):
 //   ctx = SetupAuthPSKR(enc, skR, info, psk, psk_id, pkS)
@@ -145,6 +184,36 @@ where
     aead_ctx.open(ciphertext, aad)
 }
 
+/// Does a `setup_receiver` and `AeadCtxR::open_padded` in one shot. That is, it does a key
+/// decapsulation for the specified recipient, decrypts the provided ciphertext, and strips its
+/// padding. See `setup::setup_reciever` and `AeadCtxR::open_padded` for more detail.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If an error happened during key decapsulation, returns
+/// `Err(HpkeError::DecapError)`. If an error happened during decryption, or the plaintext is not
+/// correctly padded, returns `Err(HpkeError::OpenError)`.
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub fn single_shot_open_padded<A, Kdf, Kem>(
+    mode: &OpModeR<Kem>,
+    sk_recip: &Kem::PrivateKey,
+    encapped_key: &Kem::EncappedKey,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<crate::Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // Decap the key
+    let mut aead_ctx = setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info)?;
+    // Decrypt and unpad
+    aead_ctx.open_padded(ciphertext, aad)
+}
+
 #[cfg(any(feature = "alloc", feature = "std"))]
 #[cfg(test)]
 mod test {
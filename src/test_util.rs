@@ -73,7 +73,7 @@ pub(crate) enum OpModeKind {
 }
 
 /// Makes an agreeing pair of `OpMode`s of the specified variant
-pub(crate) fn new_op_mode_pair<'a, Kdf: KdfTrait, Kem: KemTrait>(
+pub(crate) fn new_op_mode_pair<'a, Kem: KemTrait>(
     kind: OpModeKind,
     psk: &'a [u8],
     psk_id: &'a [u8],
@@ -132,17 +132,18 @@ pub(crate) fn aead_ctx_eq<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(
     // Do 1000 iterations of encryption-decryption. The underlying sequence number increments
     // each time.
     for i in 0..1000 {
-        let plaintext = &mut msg_buf.clone()[..msg_len];
+        let mut msg_copy = msg_buf;
+        let plaintext = &mut msg_copy[..msg_len];
         // Encrypt the plaintext
         let tag = sender
-            .seal_in_place_detached(&mut plaintext[..], &aad)
+            .seal_in_place_detached(&mut plaintext[..], aad)
             .unwrap_or_else(|_| panic!("seal() #{} failed", i));
         // Rename for clarity
         let ciphertext = plaintext;
 
         // Now to decrypt on the other side
         if receiver
-            .open_in_place_detached(&mut ciphertext[..], &aad, &tag)
+            .open_in_place_detached(&mut ciphertext[..], aad, &tag)
             .is_err()
         {
             // An error occurred in decryption. These encryption contexts are not identical.
@@ -0,0 +1,467 @@
+//! Decapsulation for recipient keys that are split among several parties
+//!
+//! When the recipient public key is a FROST group key or a MuSig2 aggregate key, nobody holds the
+//! full private key, so nobody can call `setup_receiver`. Instead, every participant computes a
+//! [`DhShare`] with their share of the key, optionally alongside a DLEQ proof that the share was
+//! computed honestly. A combiner then interpolates (FROST) or sums (MuSig2) the shares into the
+//! full DH result, and passes it to [`setup_receiver_with_dh`], which runs the usual DHKEM
+//! `ExtractAndExpand` and key schedule.
+//!
+//! Whoever combines the shares learns the shared secret of that one message. They do not learn
+//! anything about the group private key.
+
+use crate::{
+    aead::{Aead, AeadCtxR},
+    dhkex::secp256k1::{KexResult, PrivateKey, PublicKey},
+    dleq::{self, DleqProof},
+    kdf::Kdf as KdfTrait,
+    kem::{secpk256_hkdfsha256::derive_shared_secret, Kem as KemTrait, SecpK256HkdfSha256},
+    op_mode::OpModeR,
+    setup::derive_enc_ctx,
+    util::enforce_outbuf_len,
+    Deserializable, HpkeError, Serializable,
+};
+
+use secp256k1::{constants::CURVE_ORDER, Scalar, SecretKey, SECP256K1};
+
+type Kem = SecpK256HkdfSha256;
+type EncappedKey = <Kem as KemTrait>::EncappedKey;
+
+/// A (possibly partial) Diffie-Hellman result `sk⋅P`, where `sk` is one participant's share of
+/// the recipient private key and `P` is the encapsulated key or the sender's identity key. Once
+/// enough shares are combined, this is the full DH result `sk_recip⋅P`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhShare(pub(crate) PublicKey);
+
+impl Serializable for DhShare {
+    // A share is a curve point, so it's serialized the same way as a pubkey
+    type OutputSize = <PublicKey as Serializable>::OutputSize;
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        // Check the length is correct and panic if not
+        enforce_outbuf_len::<Self>(buf);
+
+        self.0.write_exact(buf);
+    }
+}
+
+impl Deserializable for DhShare {
+    fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+        PublicKey::from_bytes(encoded).map(DhShare)
+    }
+}
+
+impl DhShare {
+    /// Computes `sk_share⋅pk`. Use this with the sender's identity pubkey in `Auth` and `AuthPsk`
+    /// modes. For the encapsulated key, see `DhShare::from_encapped_key`.
+    pub fn new(sk_share: &PrivateKey, pk: &PublicKey) -> DhShare {
+        // Multiplying a valid point by a nonzero scalar mod the group order never gives the point
+        // at infinity, since the group has prime order
+        let point =
+            pk.0.mul_tweak(SECP256K1, &Scalar::from(sk_share.0))
+                .expect("nonzero multiple of a point in a prime-order group");
+        DhShare(PublicKey(point))
+    }
+
+    /// Computes `sk_share⋅enc`, where `enc` is the ephemeral pubkey in `encapped_key`
+    pub fn from_encapped_key(sk_share: &PrivateKey, encapped_key: &EncappedKey) -> DhShare {
+        DhShare::new(sk_share, &encapped_key.0)
+    }
+
+    /// Computes `sk_share⋅pk` along with a BIP-374 DLEQ proof that it used the same scalar as the
+    /// participant's public key share `sk_share⋅G`. `aux_rand` SHOULD be fresh randomness.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((share, proof))` on success. Proof generation only fails with negligible
+    /// probability, in which case it returns `Err(HpkeError::ValidationError)`.
+    pub fn new_with_proof(
+        sk_share: &PrivateKey,
+        pk: &PublicKey,
+        aux_rand: &[u8; 32],
+    ) -> Result<(DhShare, DleqProof), HpkeError> {
+        let (point, proof) = dleq::prove(sk_share, pk, aux_rand, None)?;
+        Ok((DhShare(point), proof))
+    }
+
+    /// Verifies that this share is `sk_share⋅pk`, where `pk_share = sk_share⋅G` is the public key
+    /// share of the participant who produced it
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if the proof is valid. Otherwise returns `Err(HpkeError::ValidationError)`.
+    pub fn verify(
+        &self,
+        pk_share: &PublicKey,
+        pk: &PublicKey,
+        proof: &DleqProof,
+    ) -> Result<(), HpkeError> {
+        dleq::verify(pk_share, pk, &self.0, proof, None)
+    }
+}
+
+/// Turns a participant identifier into a scalar. Identifiers must be nonzero.
+fn identifier_scalar(id: u32) -> Result<SecretKey, HpkeError> {
+    let mut buf = [0u8; 32];
+    buf[28..].copy_from_slice(&id.to_be_bytes());
+    SecretKey::from_slice(&buf).map_err(|_| HpkeError::ValidationError)
+}
+
+/// Inverts a nonzero scalar mod the group order using Fermat's little theorem, i.e., by computing
+/// `x^(n-2)`. The identifiers are public, so this doesn't need to be constant time.
+fn invert_scalar(x: &SecretKey) -> SecretKey {
+    // n - 2. The last byte of n is 0x41, so there's no borrow.
+    let mut exponent = CURVE_ORDER;
+    exponent[31] -= 2;
+
+    // Square-and-multiply, most significant bit first. The top bit of n - 2 is set, so start with
+    // x itself and skip that bit.
+    let mut acc = *x;
+    for bit in (0..255).rev() {
+        acc = acc
+            .mul_tweak(&Scalar::from(acc))
+            .expect("product of nonzero scalars is nonzero");
+        if (exponent[31 - bit / 8] >> (bit % 8)) & 1 == 1 {
+            acc = acc
+                .mul_tweak(&Scalar::from(*x))
+                .expect("product of nonzero scalars is nonzero");
+        }
+    }
+    acc
+}
+
+// RFC 9591 §4.2
+// def derive_interpolating_value(L, x_i):
+//   numerator = 1
+//   denominator = 1
+//   for x_j in L:
+//     if x_j == x_i: continue
+//     numerator *= x_j
+//     denominator *= x_j - x_i
+//   value = numerator / denominator
+//   return value
+
+/// Computes the Lagrange coefficient of `x_i` at zero over the set of identifiers `ids`
+fn lagrange_coefficient(ids: &[u32], x_i: u32) -> Result<Scalar, HpkeError> {
+    let x_i_scalar = identifier_scalar(x_i)?;
+    let neg_x_i = Scalar::from(x_i_scalar.negate());
+
+    let mut numerator: Option<SecretKey> = None;
+    let mut denominator: Option<SecretKey> = None;
+    for &x_j in ids.iter().filter(|&&x_j| x_j != x_i) {
+        let x_j_scalar = identifier_scalar(x_j)?;
+        let diff = x_j_scalar
+            .add_tweak(&neg_x_i)
+            .map_err(|_| HpkeError::ValidationError)?;
+
+        numerator = Some(
+            match numerator {
+                Some(n) => n.mul_tweak(&Scalar::from(x_j_scalar)),
+                None => Ok(x_j_scalar),
+            }
+            .map_err(|_| HpkeError::ValidationError)?,
+        );
+        denominator = Some(
+            match denominator {
+                Some(d) => d.mul_tweak(&Scalar::from(diff)),
+                None => Ok(diff),
+            }
+            .map_err(|_| HpkeError::ValidationError)?,
+        );
+    }
+
+    match (numerator, denominator) {
+        (Some(n), Some(d)) => n
+            .mul_tweak(&Scalar::from(invert_scalar(&d)))
+            .map(Scalar::from)
+            .map_err(|_| HpkeError::ValidationError),
+        // A single share is its own interpolation
+        _ => Ok(Scalar::ONE),
+    }
+}
+
+/// Combines shares from a threshold (e.g., FROST) key sharing into the full DH result. Each share
+/// is given with the nonzero identifier of the participant that produced it. At least `t` shares
+/// from distinct participants are required, where `t` is the sharing's threshold. Shares from
+/// fewer participants will silently produce the wrong result, which shows up as a decryption
+/// failure.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(dh)` on success. If `shares` is empty, or an identifier is zero or repeated,
+/// returns `Err(HpkeError::ValidationError)`.
+pub fn combine_interpolated(shares: &[(u32, DhShare)]) -> Result<DhShare, HpkeError> {
+    // Fixed-size buffer, since we're no_std. Nobody runs a federation this big.
+    const MAX_SHARES: usize = 256;
+    if shares.is_empty() || shares.len() > MAX_SHARES {
+        return Err(HpkeError::ValidationError);
+    }
+    let mut ids = [0u32; MAX_SHARES];
+    for (slot, (id, _)) in ids.iter_mut().zip(shares.iter()) {
+        *slot = *id;
+    }
+    let ids = &ids[..shares.len()];
+
+    // Reject repeated identifiers up front. Otherwise they'd show up as a zero denominator.
+    for (i, id) in ids.iter().enumerate() {
+        if ids[..i].contains(id) {
+            return Err(HpkeError::ValidationError);
+        }
+    }
+
+    // Sum lambda_i * share_i
+    let mut acc: Option<secp256k1::PublicKey> = None;
+    for (id, share) in shares {
+        let lambda = lagrange_coefficient(ids, *id)?;
+        let term = share
+            .0
+             .0
+            .mul_tweak(SECP256K1, &lambda)
+            .map_err(|_| HpkeError::ValidationError)?;
+        acc = Some(match acc {
+            Some(a) => a.combine(&term).map_err(|_| HpkeError::ValidationError)?,
+            None => term,
+        });
+    }
+
+    acc.map(|p| DhShare(PublicKey(p)))
+        .ok_or(HpkeError::ValidationError)
+}
+
+/// Combines shares from an additive (e.g., MuSig2) key sharing into the full DH result. This is
+/// just the sum of the shares. If the aggregate key applies a coefficient to each participant's
+/// key, as MuSig2 does, each participant must multiply their secret key by their coefficient
+/// before computing their share.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(dh)` on success. If `shares` is empty or the shares sum to the point at infinity,
+/// returns `Err(HpkeError::ValidationError)`.
+pub fn combine_summed(shares: &[DhShare]) -> Result<DhShare, HpkeError> {
+    let mut acc: Option<secp256k1::PublicKey> = None;
+    for share in shares {
+        acc = Some(match acc {
+            Some(a) => a
+                .combine(&share.0 .0)
+                .map_err(|_| HpkeError::ValidationError)?,
+            None => share.0 .0,
+        });
+    }
+
+    acc.map(|p| DhShare(PublicKey(p)))
+        .ok_or(HpkeError::ValidationError)
+}
+
+/// Turns a full DH result point into the DHKEM's DH output, i.e., its x-coordinate
+pub(crate) fn kex_result(dh: &DhShare) -> KexResult {
+    let mut buf = [0u8; 64];
+    buf.copy_from_slice(&dh.0 .0.serialize_uncompressed()[1..]);
+    KexResult(buf)
+}
+
+/// Initiates a decryption context from already-computed DH results rather than a private key.
+/// `dh_eph` is `sk_recip⋅enc`, where `enc` is the ephemeral pubkey in `encapped_key`. In `Auth`
+/// and `AuthPsk` modes, `dh_identity` is `sk_recip⋅pk_sender_id`. Otherwise it MUST be `None`.
+/// Both are typically the output of `combine_interpolated` or `combine_summed`.
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If `dh_identity` is given when the mode doesn't
+/// authenticate the sender, or vice versa, returns `Err(HpkeError::ValidationError)`. If either DH
+/// result is wrong, this still succeeds, but the resulting context will fail to open anything.
+pub fn setup_receiver_with_dh<A, Kdf>(
+    mode: &OpModeR<Kem>,
+    pk_recip: &PublicKey,
+    encapped_key: &EncappedKey,
+    dh_eph: &DhShare,
+    dh_identity: Option<&DhShare>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+{
+    let kex_res_eph = kex_result(dh_eph);
+    let shared_secret = match (mode.get_pk_sender_id(), dh_identity) {
+        (Some(pk_sender_id), Some(dh_identity)) => {
+            let kex_res_identity = kex_result(dh_identity);
+            derive_shared_secret(
+                &kex_res_eph,
+                Some((&kex_res_identity, pk_sender_id)),
+                encapped_key,
+                pk_recip,
+            )
+        }
+        (None, None) => derive_shared_secret(&kex_res_eph, None, encapped_key, pk_recip),
+        _ => return Err(HpkeError::ValidationError),
+    };
+
+    let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
+    Ok(enc_ctx.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        op_mode::OpModeS,
+        setup::setup_sender,
+        test_util::{aead_ctx_eq, gen_rand_buf},
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    /// Evaluates the polynomial with the given coefficients at `x`
+    fn eval_poly(coeffs: &[SecretKey], x: u32) -> PrivateKey {
+        let x = Scalar::from(identifier_scalar(x).unwrap());
+        // Horner's method
+        let mut acc = *coeffs.last().unwrap();
+        for c in coeffs.iter().rev().skip(1) {
+            acc = acc
+                .mul_tweak(&x)
+                .unwrap()
+                .add_tweak(&Scalar::from(*c))
+                .unwrap();
+        }
+        PrivateKey(acc)
+    }
+
+    /// Tests that 2-of-3 Shamir shares of a recipient key, combined by interpolation, derive the
+    /// same context as the sender, in both Base and Auth modes
+    #[test]
+    fn test_threshold_interpolated() {
+        let mut csprng = StdRng::from_entropy();
+
+        // f(x) = s + c*x. The group key is s*G and participant i holds f(i).
+        let (s, _) = Kem::gen_keypair(&mut csprng);
+        let (c, _) = Kem::gen_keypair(&mut csprng);
+        let pk_group = Kem::sk_to_pk(&s);
+        let shares = [1, 2, 3].map(|i| eval_poly(&[s.0, c.0], i));
+
+        let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+        let info = b"federation inbox";
+
+        // Try Base mode with participants 1 and 3
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_group, info, &mut csprng).unwrap();
+        let dh_eph = combine_interpolated(&[
+            (1, DhShare::from_encapped_key(&shares[0], &encapped_key)),
+            (3, DhShare::from_encapped_key(&shares[2], &encapped_key)),
+        ])
+        .unwrap();
+        let mut receiver_ctx = setup_receiver_with_dh::<A, Kdf>(
+            &OpModeR::Base,
+            &pk_group,
+            &encapped_key,
+            &dh_eph,
+            None,
+            info,
+        )
+        .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Try Auth mode with participants 2 and 3
+        let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+            &OpModeS::Auth((sk_sender, pk_sender.clone())),
+            &pk_group,
+            info,
+            &mut csprng,
+        )
+        .unwrap();
+        let dh_eph = combine_interpolated(&[
+            (2, DhShare::from_encapped_key(&shares[1], &encapped_key)),
+            (3, DhShare::from_encapped_key(&shares[2], &encapped_key)),
+        ])
+        .unwrap();
+        let dh_identity = combine_interpolated(&[
+            (2, DhShare::new(&shares[1], &pk_sender)),
+            (3, DhShare::new(&shares[2], &pk_sender)),
+        ])
+        .unwrap();
+        let receiver_mode = OpModeR::Auth(pk_sender);
+        let mut receiver_ctx = setup_receiver_with_dh::<A, Kdf>(
+            &receiver_mode,
+            &pk_group,
+            &encapped_key,
+            &dh_eph,
+            Some(&dh_identity),
+            info,
+        )
+        .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Forgetting the identity share is an error
+        assert!(setup_receiver_with_dh::<A, Kdf>(
+            &receiver_mode,
+            &pk_group,
+            &encapped_key,
+            &dh_eph,
+            None,
+            info,
+        )
+        .is_err());
+
+        // A single share isn't enough
+        let dh_eph =
+            combine_interpolated(&[(1, DhShare::from_encapped_key(&shares[0], &encapped_key))])
+                .unwrap();
+        let mut receiver_ctx = setup_receiver_with_dh::<A, Kdf>(
+            &OpModeR::Base,
+            &pk_group,
+            &encapped_key,
+            &dh_eph,
+            None,
+            info,
+        )
+        .unwrap();
+        assert!(!aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        // Repeated identifiers are rejected
+        let share = DhShare::from_encapped_key(&shares[0], &encapped_key);
+        assert!(combine_interpolated(&[(1, share.clone()), (1, share)]).is_err());
+    }
+
+    /// Tests that additive shares with DLEQ proofs, combined by summing, derive the same context
+    /// as the sender
+    #[test]
+    fn test_threshold_summed_with_proofs() {
+        let mut csprng = StdRng::from_entropy();
+
+        // The aggregate key is the sum of the participants' keys
+        let (sk1, pk1) = Kem::gen_keypair(&mut csprng);
+        let (sk2, pk2) = Kem::gen_keypair(&mut csprng);
+        let pk_agg = PublicKey(pk1.0.combine(&pk2.0).unwrap());
+
+        let info = b"musig inbox";
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_agg, info, &mut csprng).unwrap();
+
+        // Each participant proves their share is honest, and the combiner checks the proofs
+        let (share1, proof1) =
+            DhShare::new_with_proof(&sk1, &encapped_key.0, &gen_rand_buf()).unwrap();
+        let (share2, proof2) =
+            DhShare::new_with_proof(&sk2, &encapped_key.0, &gen_rand_buf()).unwrap();
+        share1.verify(&pk1, &encapped_key.0, &proof1).unwrap();
+        share2.verify(&pk2, &encapped_key.0, &proof2).unwrap();
+
+        // A share that claims to be from the wrong participant doesn't verify
+        assert!(share1.verify(&pk2, &encapped_key.0, &proof1).is_err());
+
+        let dh_eph = combine_summed(&[share1, share2]).unwrap();
+        let mut receiver_ctx = setup_receiver_with_dh::<A, Kdf>(
+            &OpModeR::Base,
+            &pk_agg,
+            &encapped_key,
+            &dh_eph,
+            None,
+            info,
+        )
+        .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+    }
+}
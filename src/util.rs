@@ -1,5 +1,8 @@
 use crate::{aead::Aead, kdf::Kdf as KdfTrait, kem::Kem as KemTrait, HpkeError, Serializable};
 
+#[cfg(feature = "secp")]
+use sha2::{Digest, Sha256};
+
 /// Represents a ciphersuite context. That's "KEMXX", where `XX` is the KEM ID
 pub(crate) type KemSuiteId = [u8; 5];
 
@@ -69,6 +72,23 @@ pub(crate) fn kem_suite_id<Kem: KemTrait>() -> KemSuiteId {
     suite_id
 }
 
+// BIP-340
+// hash_name(x) = SHA256(SHA256(tag) || SHA256(tag) || x)
+
+/// Computes the BIP-340 tagged hash of the concatenation of `msgs` under the given tag
+#[cfg(feature = "secp")]
+pub(crate) fn tagged_hash(tag: &[u8], msgs: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag);
+
+    let mut h = Sha256::new();
+    h.update(tag_hash);
+    h.update(tag_hash);
+    for msg in msgs {
+        h.update(msg);
+    }
+    h.finalize().into()
+}
+
 /// Returns a const expression that evaluates to the number of arguments it received
 macro_rules! count {
     () => (0usize);
@@ -105,6 +125,56 @@ pub(crate) fn write_to_buf<'a>(buf: &'a mut [u8], to_write: &[u8]) -> &'a mut [u
     &mut buf[to_write.len()..]
 }
 
+/// Splits off the first `len` bytes of `buf`, or returns `Err(HpkeError::ValidationError)` if
+/// there aren't enough
+#[cfg(any(feature = "alloc", feature = "std"))]
+pub(crate) fn split_checked(buf: &[u8], len: usize) -> Result<(&[u8], &[u8]), HpkeError> {
+    if buf.len() < len {
+        Err(HpkeError::ValidationError)
+    } else {
+        Ok(buf.split_at(len))
+    }
+}
+
+/// Appends `data` to `out` with a big-endian 16-bit length prefix
+///
+/// Panics
+/// ======
+/// Panics if `data` is longer than 65535 bytes
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub(crate) fn write_u16_prefixed(out: &mut crate::Vec<u8>, data: &[u8]) {
+    assert!(
+        data.len() <= u16::MAX as usize,
+        "data is longer than 65535 bytes"
+    );
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Splits a value with a big-endian 16-bit length prefix off the front of `buf`. Returns the value
+/// and what follows it.
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub(crate) fn split_u16_prefixed(buf: &[u8]) -> Result<(&[u8], &[u8]), HpkeError> {
+    let (len, rest) = split_checked(buf, 2)?;
+    split_checked(rest, u16::from_be_bytes([len[0], len[1]]) as usize)
+}
+
+/// Splits a `uint16 version` followed by 16-bit length-prefixed contents off the front of `buf`.
+/// This is how ECH and ODoH frame each config in a list. Returns the version, the contents, the
+/// whole framed item, and what follows it.
+#[cfg(all(feature = "secp", any(feature = "alloc", feature = "std")))]
+pub(crate) fn split_versioned(buf: &[u8]) -> Result<(u16, &[u8], &[u8], &[u8]), HpkeError> {
+    let (version, rest) = split_checked(buf, 2)?;
+    let (contents, rest) = split_u16_prefixed(rest)?;
+    let item = &buf[..buf.len() - rest.len()];
+    Ok((
+        u16::from_be_bytes([version[0], version[1]]),
+        contents,
+        item,
+        rest,
+    ))
+}
+
 /// Takes two lengths and returns an `Err(Error::IncorrectInputLength)` iff they don't match
 pub(crate) fn enforce_equal_len(expected_len: usize, given_len: usize) -> Result<(), HpkeError> {
     if given_len != expected_len {